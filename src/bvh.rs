@@ -0,0 +1,400 @@
+use crate::object::MIN_HIT_DISTANCE;
+use crate::vector::{Ray, Vec3f};
+
+// Number of buckets the centroids are binned into when evaluating the SAH.
+const SAH_BUCKETS: usize = 12;
+// Cost of visiting an interior node, relative to one primitive intersection.
+const TRAVERSAL_COST: f64 = 0.125;
+// Nodes with at most this many primitives may become leaves.
+const MAX_LEAF_SIZE: usize = 4;
+
+// An axis-aligned bounding box, stored as its two extreme corners.
+#[derive(Clone, Debug)]
+pub struct Aabb {
+    pub min: Vec3f,
+    pub max: Vec3f,
+}
+
+impl Aabb {
+    pub fn new(min: Vec3f, max: Vec3f) -> Self {
+        Aabb { min, max }
+    }
+
+    // The box containing nothing. Unioning anything with it yields the other box.
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vec3f::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: Vec3f::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+        }
+    }
+
+    // The box containing everything, for shapes such as planes that have no
+    // bounds. The BVH keeps them out of the hierarchy.
+    pub fn infinite() -> Self {
+        Aabb {
+            min: Vec3f::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            max: Vec3f::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Aabb {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        }
+    }
+
+    pub fn grow(&self, point: &Vec3f) -> Self {
+        Aabb {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3f {
+        (&self.min + &self.max) * 0.5
+    }
+
+    pub fn is_finite(&self) -> bool {
+        [&self.min, &self.max]
+            .iter()
+            .all(|v| v.x.is_finite() && v.y.is_finite() && v.z.is_finite())
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = &self.max - &self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    // Index of the axis along which the box is the longest.
+    pub fn largest_axis(&self) -> usize {
+        let d = &self.max - &self.min;
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    // Slab test: intersect the ray with the three pairs of planes bounding the
+    // box and check that the resulting parameter intervals overlap.
+    pub fn hit(&self, ray: &Ray, inv_dir: &Vec3f, t_min: f64, t_max: f64) -> bool {
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        for axis in 0..3 {
+            let near = (self.min[axis] - ray.origin[axis]) * inv_dir[axis];
+            let far = (self.max[axis] - ray.origin[axis]) * inv_dir[axis];
+            let (near, far) = if near > far { (far, near) } else { (near, far) };
+            // f64::max/min ignore NaNs, which show up when the ray origin lies
+            // exactly on a slab and is parallel to it.
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t0 > t1 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+enum BvhNode {
+    // Covers `count` primitives starting at `start` in `Bvh::indices`.
+    Leaf {
+        bounds: Aabb,
+        start: usize,
+        count: usize,
+    },
+    // The first child is stored directly after its parent; the second one is at
+    // `second_child`. `axis` is the axis the children were split along.
+    Interior {
+        bounds: Aabb,
+        second_child: usize,
+        axis: usize,
+    },
+}
+
+struct BuildItem {
+    index: usize,
+    bounds: Aabb,
+    centroid: Vec3f,
+}
+
+// A bounding volume hierarchy over a list of primitives (the objects of a
+// `World`, or the triangles of a mesh), built with the surface area heuristic
+// and stored as a flattened, depth-first list of nodes. Primitives are only
+// referred to by their index in the list the hierarchy was built from.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    // Primitive indices, ordered so every leaf references a contiguous range.
+    indices: Vec<usize>,
+    // Primitives without a finite bounding box are tested against every ray.
+    unbounded: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new(primitive_bounds: Vec<Aabb>) -> Self {
+        let mut bvh = Bvh {
+            nodes: vec![],
+            indices: vec![],
+            unbounded: vec![],
+        };
+
+        let mut items = vec![];
+        for (index, bounds) in primitive_bounds.into_iter().enumerate() {
+            if bounds.is_finite() {
+                let centroid = bounds.centroid();
+                items.push(BuildItem {
+                    index,
+                    bounds,
+                    centroid,
+                });
+            } else {
+                bvh.unbounded.push(index);
+            }
+        }
+
+        if !items.is_empty() {
+            bvh.build(&mut items);
+        }
+        bvh
+    }
+
+    // Recursively builds the subtree over `items` and returns its node index.
+    fn build(&mut self, items: &mut [BuildItem]) -> usize {
+        let node_index = self.nodes.len();
+        let bounds = items
+            .iter()
+            .fold(Aabb::empty(), |acc, item| acc.union(&item.bounds));
+        let n = items.len();
+
+        let centroid_bounds = items
+            .iter()
+            .fold(Aabb::empty(), |acc, item| acc.grow(&item.centroid));
+        let axis = centroid_bounds.largest_axis();
+        let axis_min = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - axis_min;
+
+        // With a single primitive, or with every centroid at the same spot,
+        // there is nothing left to split.
+        if n == 1 || extent <= 0.0 {
+            return self.push_leaf(bounds, items);
+        }
+
+        let bucket_of = |centroid: &Vec3f| {
+            (((centroid[axis] - axis_min) / extent * SAH_BUCKETS as f64) as usize)
+                .min(SAH_BUCKETS - 1)
+        };
+
+        let mut buckets: Vec<(usize, Aabb)> = vec![(0, Aabb::empty()); SAH_BUCKETS];
+        for item in items.iter() {
+            let bucket = &mut buckets[bucket_of(&item.centroid)];
+            bucket.0 += 1;
+            bucket.1 = bucket.1.union(&item.bounds);
+        }
+
+        // Cost of splitting between bucket `split - 1` and bucket `split`.
+        let area = bounds.surface_area().max(f64::EPSILON);
+        let mut best_cost = f64::INFINITY;
+        let mut best_split = 0;
+        for split in 1..SAH_BUCKETS {
+            let side = |range: &[(usize, Aabb)]| {
+                range
+                    .iter()
+                    .fold((0, Aabb::empty()), |(count, acc), (c, b)| {
+                        (count + c, acc.union(b))
+                    })
+            };
+            let (left_count, left_bounds) = side(&buckets[..split]);
+            let (right_count, right_bounds) = side(&buckets[split..]);
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost = TRAVERSAL_COST
+                + (left_count as f64 * left_bounds.surface_area()
+                    + right_count as f64 * right_bounds.surface_area())
+                    / area;
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        let mid = if best_cost.is_finite() {
+            if n <= MAX_LEAF_SIZE && n as f64 <= best_cost {
+                return self.push_leaf(bounds, items);
+            }
+            let mut mid = 0;
+            for i in 0..n {
+                if bucket_of(&items[i].centroid) < best_split {
+                    items.swap(i, mid);
+                    mid += 1;
+                }
+            }
+            mid
+        } else {
+            // Every centroid landed in the same bucket; fall back to a median split.
+            items.select_nth_unstable_by(n / 2, |a, b| {
+                a.centroid[axis].partial_cmp(&b.centroid[axis]).unwrap()
+            });
+            n / 2
+        };
+
+        // Reserve the slot for this node, then fill it in once the position of
+        // the second child is known.
+        self.nodes.push(BvhNode::Leaf {
+            bounds: Aabb::empty(),
+            start: 0,
+            count: 0,
+        });
+        let (left, right) = items.split_at_mut(mid);
+        self.build(left);
+        let second_child = self.build(right);
+        self.nodes[node_index] = BvhNode::Interior {
+            bounds,
+            second_child,
+            axis,
+        };
+        node_index
+    }
+
+    fn push_leaf(&mut self, bounds: Aabb, items: &[BuildItem]) -> usize {
+        let start = self.indices.len();
+        self.indices.extend(items.iter().map(|item| item.index));
+        self.nodes.push(BvhNode::Leaf {
+            bounds,
+            start,
+            count: items.len(),
+        });
+        self.nodes.len() - 1
+    }
+
+    // Returns the index, distance and hit data of the closest primitive along
+    // the ray. `hit` intersects the ray with the primitive at the given index.
+    pub fn intersect<H>(
+        &self,
+        ray: &Ray,
+        mut hit: impl FnMut(usize) -> Option<(f64, H)>,
+    ) -> Option<(usize, f64, H)> {
+        let mut closest = None;
+        let mut test = |index: usize, closest: &mut Option<(usize, f64, H)>| {
+            if let Some((t, data)) = hit(index) {
+                let is_closer = closest.as_ref().is_none_or(|(_, t2, _)| t < *t2);
+                if t >= MIN_HIT_DISTANCE && is_closer {
+                    *closest = Some((index, t, data));
+                }
+            }
+        };
+
+        for &index in self.unbounded.iter() {
+            test(index, &mut closest);
+        }
+
+        if self.nodes.is_empty() {
+            return closest;
+        }
+
+        let inv_dir = Vec3f::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z);
+        let dir_is_neg = [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0];
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(node_index) = stack.pop() {
+            let t_max = closest.as_ref().map_or(f64::INFINITY, |(_, t, _)| *t);
+            match &self.nodes[node_index] {
+                BvhNode::Leaf {
+                    bounds,
+                    start,
+                    count,
+                } => {
+                    if bounds.hit(ray, &inv_dir, MIN_HIT_DISTANCE, t_max) {
+                        for &index in self.indices[*start..*start + *count].iter() {
+                            test(index, &mut closest);
+                        }
+                    }
+                }
+                BvhNode::Interior {
+                    bounds,
+                    second_child,
+                    axis,
+                } => {
+                    if bounds.hit(ray, &inv_dir, MIN_HIT_DISTANCE, t_max) {
+                        // Visit the child nearer to the ray origin first, so the
+                        // farther one can be culled by the closer hit.
+                        if dir_is_neg[*axis] {
+                            stack.push(node_index + 1);
+                            stack.push(*second_child);
+                        } else {
+                            stack.push(*second_child);
+                            stack.push(node_index + 1);
+                        }
+                    }
+                }
+            }
+        }
+        closest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Shape;
+    use crate::primitive::{Cuboid, Plane};
+    use crate::sampler::Rng;
+
+    fn random_point(rng: &mut Rng, scale: f64) -> Vec3f {
+        Vec3f::new(
+            scale * (2.0 * rng.next_f64() - 1.0),
+            scale * (2.0 * rng.next_f64() - 1.0),
+            scale * (2.0 * rng.next_f64() - 1.0),
+        )
+    }
+
+    // The BVH must find the same closest hit as testing every primitive.
+    #[test]
+    fn intersect_matches_brute_force() {
+        let mut rng = Rng::new(1);
+        let boxes = 200;
+        let mut shapes: Vec<Box<dyn Shape>> = vec![];
+        for _ in 0..boxes {
+            let min = random_point(&mut rng, 10.0);
+            let size = Vec3f::new(rng.next_f64(), rng.next_f64(), rng.next_f64());
+            shapes.push(Box::new(Cuboid::new(min.clone(), &min + &(size * 2.0))));
+        }
+        // Planes have no bounds, and are tested on every ray.
+        for _ in 0..3 {
+            shapes.push(Box::new(Plane {
+                point: random_point(&mut rng, 12.0),
+                normal: random_point(&mut rng, 1.0).normalize(),
+            }));
+        }
+        let bvh = Bvh::new(shapes.iter().map(|s| s.bounding_box()).collect());
+
+        let mut box_hits = 0;
+        for _ in 0..2000 {
+            // Aimed at the boxes, from inside and outside of them.
+            let origin = random_point(&mut rng, 15.0);
+            let dir = &random_point(&mut rng, 10.0) - &origin;
+            let ray = Ray { origin, dir };
+            let expected = shapes
+                .iter()
+                .enumerate()
+                .filter_map(|(index, shape)| shape.intersect(&ray).map(|hit| (index, hit.t)))
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            let found = bvh
+                .intersect(&ray, |index| {
+                    shapes[index].intersect(&ray).map(|hit| (hit.t, ()))
+                })
+                .map(|(index, t, ())| (index, t));
+            assert_eq!(found, expected);
+            box_hits += found.is_some_and(|(index, _)| index < boxes) as usize;
+        }
+        // Planes are hit by most rays, but plenty should hit a box first, or
+        // the test doesn't test the tree much.
+        assert!(box_hits > 400, "only {} rays hit a box", box_hits);
+    }
+}
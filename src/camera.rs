@@ -1,11 +1,13 @@
 use crate::color::Color;
+use crate::framebuffer::Framebuffer;
+use crate::integrator::Integrator;
 use crate::object::*;
-use crate::ppm::PPM;
+use crate::sampler::Sampler;
+use crate::sampling::{concentric_disk, uniform_triangle};
 use crate::vector::Ray;
 use crate::vector::Vec3f;
 use egui_winit::winit::window::Window;
 use indicatif::ProgressBar;
-use rand;
 use softbuffer::Buffer;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -13,6 +15,72 @@ use std::thread::ScopedJoinHandle;
 
 const DEFAULT_NUM_THREADS: usize = 6;
 
+// A thin lens in front of the camera. Rays start from random points on the
+// lens and converge on the plane `focus_distance` away from the camera, so
+// that only objects near that plane are sharp. An aperture of 0 is a pinhole
+// camera, where everything is in focus.
+#[derive(Clone, Debug, Default)]
+pub struct Lens {
+    // Diameter of the lens.
+    pub aperture: f64,
+    pub focus_distance: f64,
+    // The number of diaphragm blades, which shape out-of-focus highlights
+    // ("bokeh") like a polygon with as many sides. Fewer than 3 blades make a
+    // round aperture.
+    pub blades: usize,
+    // Rotation of the polygonal aperture, in degrees.
+    pub blade_rotation: f64,
+}
+
+impl Lens {
+    // A random point on the unit-sized aperture, as offsets along the
+    // horizontal and vertical axes of the camera.
+    fn sample(&self, sampler: &mut dyn Sampler) -> (f64, f64) {
+        if self.blades < 3 {
+            return concentric_disk(sampler.next_2d());
+        }
+
+        // The polygon is made of `blades` triangles of the same area around
+        // its center, so pick one of them and a uniform point inside it.
+        let sector = 2.0 * std::f64::consts::PI / self.blades as f64;
+        let triangle = (sampler.next_1d() * self.blades as f64).floor();
+        let angle = self.blade_rotation.to_radians() + triangle * sector;
+        let (a, b) = uniform_triangle(sampler.next_2d());
+        (
+            a * angle.cos() + b * (angle + sector).cos(),
+            a * angle.sin() + b * (angle + sector).sin(),
+        )
+    }
+}
+
+pub const AOV_NAMES: &str = "normal and depth";
+
+// An extra image rendered alongside the beauty pass, for compositing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aov {
+    // The unit normal of the first surface seen, facing the camera.
+    Normal,
+    // The distance from the camera to the first surface seen.
+    Depth,
+}
+
+impl Aov {
+    pub fn from_name(name: &str) -> Option<Aov> {
+        match name {
+            "normal" => Some(Aov::Normal),
+            "depth" => Some(Aov::Depth),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct Camera {
     pub width: usize,
@@ -25,6 +93,13 @@ pub struct Camera {
     pub v_up: Vec3f,
     pub vfov: f64, // in degrees
     pub samples_per_pixel: usize,
+    pub lens: Lens,
+    // Unit vectors along the horizontal and vertical axes of the image, and
+    // pointing backwards out of the camera.
+    pub u: Vec3f,
+    pub v: Vec3f,
+    pub w: Vec3f,
+    // The viewport lies on the plane of focus.
     pub lower_left_corner: Vec3f,
     pub horizontal: Vec3f,
     pub vertical: Vec3f,
@@ -38,6 +113,7 @@ pub struct Camera {
 
 // Implements a camera view.
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         width: usize,
         height: usize,
@@ -46,6 +122,7 @@ impl Camera {
         v_up: Vec3f,
         vfov: f64,
         samples_per_pixel: usize,
+        lens: Lens,
     ) -> Self {
         let mut camera = Self {
             width,
@@ -55,115 +132,239 @@ impl Camera {
             v_up,
             vfov,
             samples_per_pixel,
+            lens,
             ..Default::default()
         };
 
-        let aspect_ratio = width as f64 / height as f64;
+        camera.w = (&camera.origin - &camera.lookat).normalize();
+        camera.u = camera.v_up.cross(&camera.w).normalize();
+        camera.v = camera.w.cross(&camera.u);
+        camera.set_focus_distance(camera.lens.focus_distance);
+        camera
+    }
 
-        let w = (&camera.origin - &camera.lookat).normalize();
-        let u = camera.v_up.cross(&w).normalize();
-        let v = w.cross(&u);
+    // Moves the plane of focus, which is also where the viewport sits.
+    pub fn set_focus_distance(&mut self, focus_distance: f64) {
+        self.lens.focus_distance = focus_distance;
+        let aspect_ratio = self.width as f64 / self.height as f64;
 
-        let theta_fov = camera.vfov.to_radians();
+        let theta_fov = self.vfov.to_radians();
         let camera_half_height = (theta_fov / 2.0).tan();
 
-        let viewport_height = 2.0 * camera_half_height;
+        let viewport_height = 2.0 * camera_half_height * focus_distance;
         let viewport_width = aspect_ratio * viewport_height;
-        camera.horizontal = u * viewport_width;
-        camera.vertical = v * viewport_height;
+        self.horizontal = &self.u * viewport_width;
+        self.vertical = &self.v * viewport_height;
         // The norms of these vectors are the same as the whole camera (u*viewport_width) and (v * viewport_height)
-        camera.lower_left_corner =
-            &camera.origin - &(&camera.horizontal * 0.5) - (&camera.vertical * 0.5) - w;
-        camera
+        self.lower_left_corner = &self.origin
+            - &(&self.horizontal * 0.5)
+            - (&self.vertical * 0.5)
+            - &self.w * focus_distance;
     }
 
-    #[allow(dead_code)]
-    pub fn write_ppm(
-        &mut self,
-        world: Arc<World>,
-        num_threads: Option<usize>,
-        img: Arc<Mutex<PPM>>,
-    ) {
-        self.write(
-            world,
-            num_threads,
-            Arc::new(move |row: usize, col, color| {
-                img.lock().unwrap().set_pixel(color, row, col);
-            }),
-        );
+    // Focuses on the surface in the middle of the image, looking through any
+    // fog or smoke. The focus distance is left alone if the center ray
+    // doesn't hit anything.
+    pub fn autofocus(&mut self, world: &World) {
+        let ray = Ray {
+            origin: self.origin.clone(),
+            dir: &self.w * -1.0,
+        };
+        if let Some((hit, _)) = world.intersect_surface(&ray) {
+            self.set_focus_distance(hit.t);
+        }
+    }
+
+    // A ray through a point of the image, given in fractional pixel
+    // coordinates, starting from a random point on the lens.
+    pub fn get_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Ray {
+        let target = self.viewport_point(x, y);
+        let (lens_x, lens_y) = self.lens.sample(sampler);
+        let lens_radius = self.lens.aperture / 2.0;
+        let origin =
+            &self.origin + &(&self.u * (lens_x * lens_radius)) + &self.v * (lens_y * lens_radius);
+        Ray::from_pts(origin, target)
+    }
+
+    fn viewport_point(&self, x: f64, y: f64) -> Vec3f {
+        &self.lower_left_corner
+            + &(&self.horizontal * (x / self.width as f64))
+            + &self.vertical * (y / self.height as f64)
+    }
+
+    // Fills `framebuffer`, which must be the size of the camera, with `aov`
+    // as seen by a ray through the center of each pixel and of the lens.
+    // Pixels where that ray hits no surface are left black.
+    pub fn write_aov(&self, world: &World, aov: Aov, framebuffer: &mut Framebuffer) {
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let ray = Ray::from_pts(
+                    self.origin.clone(),
+                    self.viewport_point(col as f64 + 0.5, row as f64 + 0.5),
+                );
+                let Some((hit, _)) = world.intersect_surface(&ray) else {
+                    continue;
+                };
+                let value = match aov {
+                    Aov::Normal => hit.normal,
+                    Aov::Depth => {
+                        let distance = hit.t * ray.dir.norm();
+                        Vec3f::new(distance, distance, distance)
+                    }
+                };
+                framebuffer.set_pixel(row, col, value);
+            }
+        }
     }
 
     pub fn write_buffer(
         &mut self,
         world: Arc<World>,
+        integrator: Arc<dyn Integrator + Send + Sync>,
+        sampler: Arc<dyn Sampler + Send + Sync>,
         num_threads: Option<usize>,
         buffer: Arc<Mutex<Buffer<Arc<Window>, Arc<Window>>>>,
     ) {
-        let buffer_clone = buffer.clone();
-        let width = self.width;
-        self.write(
-            world,
-            num_threads,
-            Arc::new(move |row: usize, col, color: Color| {
-                buffer_clone.lock().unwrap()[row * width + col] =
+        let mut framebuffer = Framebuffer::new(self.width, self.height);
+        self.write(world, integrator, sampler, num_threads, &mut framebuffer);
+
+        let mut buffer = buffer.lock().unwrap();
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let color = Color::from_linear(&framebuffer.pixel(row, col));
+                buffer[row * self.width + col] =
                     color.blue as u32 | ((color.green as u32) << 8) | ((color.red as u32) << 16);
-            }),
-        );
+            }
+        }
     }
 
-    fn write(
+    // Renders `samples_per_pixel` samples for every pixel with `integrator`
+    // and accumulates them into `framebuffer`, which must be the size of the
+    // camera. Each thread draws the numbers of its samples from a copy of
+    // `sampler`.
+    pub fn write(
         &self,
         world: Arc<World>,
+        integrator: Arc<dyn Integrator + Send + Sync>,
+        sampler: Arc<dyn Sampler + Send + Sync>,
         num_threads: Option<usize>,
-        write_pixel_fn: Arc<impl Fn(usize, usize, Color) + Send + Sync>,
+        framebuffer: &mut Framebuffer,
     ) {
         let bar = Arc::new(ProgressBar::new(self.height as u64 * self.width as u64));
 
         let total_pixels = self.height * self.width;
+        let num_threads = num_threads.unwrap_or(DEFAULT_NUM_THREADS);
 
         thread::scope(|s| {
-            let mut handles: Vec<ScopedJoinHandle<()>> = vec![];
-            for i in 0..num_threads.unwrap_or(DEFAULT_NUM_THREADS) {
-                let origin = self.origin.clone();
-                let horizontal = self.horizontal.clone();
-                let vertical = self.vertical.clone();
-                let lower_left_corner = self.lower_left_corner.clone();
+            let mut handles: Vec<ScopedJoinHandle<Vec<(usize, Vec3f)>>> = vec![];
+            for i in 0..num_threads {
                 let world_ptr = world.clone();
+                let integrator_ptr = integrator.clone();
+                let sampler_ptr = sampler.clone();
                 let bar_ptr = bar.clone();
-                let write_pixel_fn_clone = write_pixel_fn.clone();
                 let width = self.width;
-                let height = self.height;
                 let samples_per_pixel = self.samples_per_pixel;
                 handles.push(s.spawn(move || {
+                    let mut sampler = sampler_ptr.clone_sampler();
+                    let mut pixels = vec![];
                     let mut j = 0;
-                    while j * DEFAULT_NUM_THREADS + i < total_pixels {
-                        let pixel_val = j * DEFAULT_NUM_THREADS + i;
+                    while j * num_threads + i < total_pixels {
+                        let pixel_val = j * num_threads + i;
                         let row = pixel_val / width;
                         let col = pixel_val % width;
                         let mut acc = Vec3f::new(0.0, 0.0, 0.0);
                         // sample multiple times for anti-aliasing
-                        for _ in 0..samples_per_pixel {
-                            let pass_through_camera_point = lower_left_corner.clone()
-                                + (&horizontal
-                                    * ((col as f64 + rand::random::<f64>()) / width as f64))
-                                + &vertical
-                                    * ((row as f64 + rand::random::<f64>()) / height as f64);
-                            let ray = Ray::from_pts(origin.clone(), pass_through_camera_point);
-                            let color = world_ptr.color_at(&ray);
+                        for index in 0..samples_per_pixel {
+                            sampler.start_pixel_sample((col, row), index);
+                            let (dx, dy) = sampler.next_2d();
+                            let ray =
+                                self.get_ray(col as f64 + dx, row as f64 + dy, sampler.as_mut());
+                            let color = integrator_ptr.radiance(&world_ptr, &ray, sampler.as_mut());
                             acc = acc + color;
                         }
-                        acc = acc * (1.0 / (samples_per_pixel as f64));
-                        // let gamma_corr = acc.sqrt();
-                        write_pixel_fn_clone(row, col, Color::from_vec(acc));
+                        pixels.push((pixel_val, acc));
                         bar_ptr.inc(1);
                         j += 1;
                     }
+                    pixels
                 }));
             }
 
             for handle in handles {
-                handle.join().unwrap();
+                for (pixel_val, acc) in handle.join().unwrap() {
+                    framebuffer.add_samples(
+                        pixel_val / self.width,
+                        pixel_val % self.width,
+                        acc,
+                        self.samples_per_pixel,
+                    );
+                }
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::furnace::constant;
+
+    #[test]
+    fn aovs_of_a_sphere() {
+        let camera = Camera::new(
+            9,
+            9,
+            Vec3f::new(0.0, 0.0, 0.0),
+            Vec3f::new(0.0, 0.0, -1.0),
+            Vec3f::new(0.0, 1.0, 0.0),
+            40.0,
+            1,
+            Lens {
+                focus_distance: 1.0,
+                ..Default::default()
+            },
+        );
+        let center = Vec3f::new(0.0, 0.0, -5.0);
+        let world = World::new(vec![Object {
+            material: Arc::new(DiffuseMaterial {
+                color: constant(0.5),
+            }),
+            shape: Arc::new(Sphere {
+                center: center.clone(),
+                radius: 1.0,
+            }),
+        }]);
+        let mut depth = Framebuffer::new(9, 9);
+        let mut normal = Framebuffer::new(9, 9);
+        camera.write_aov(&world, Aov::Depth, &mut depth);
+        camera.write_aov(&world, Aov::Normal, &mut normal);
+
+        assert!((depth.pixel(4, 4).x - 4.0).abs() < 1e-9);
+        assert!((&normal.pixel(4, 4) - &Vec3f::new(0.0, 0.0, 1.0)).norm() < 1e-9);
+        // The sphere only covers the middle of the 40 degree field of view.
+        assert_eq!(depth.pixel(0, 0).x, 0.0);
+        assert_eq!(normal.pixel(8, 8).norm(), 0.0);
+
+        let mut hits = 0;
+        for row in 0..9 {
+            for col in 0..9 {
+                let distance = depth.pixel(row, col).x;
+                if distance == 0.0 {
+                    continue;
+                }
+                hits += 1;
+                // The point `distance` along the pixel's ray is on the
+                // sphere, and the normal there points back at the camera.
+                let dir = camera
+                    .viewport_point(col as f64 + 0.5, row as f64 + 0.5)
+                    .normalize();
+                let point = &dir * distance;
+                assert!(((&point - &center).norm() - 1.0).abs() < 1e-9);
+                let n = normal.pixel(row, col);
+                assert!((&n - &(&point - &center)).norm() < 1e-9);
+                assert!(n.dot_ref(&dir) < 0.0);
+            }
+        }
+        assert!(hits > 9 && hits < 81);
+    }
+}
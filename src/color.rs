@@ -1,8 +1,11 @@
+use crate::sampler::Rng;
 use crate::vector::Vec3f;
 use std::fmt;
 use std::ops;
 
 // Implements a RGB color. Values range from 0 to 255.
+// This is the 8-bit, display-ready form of a color; rendering itself happens
+// on linear `Vec3f`s, see `Framebuffer`.
 #[derive(PartialEq, Clone, Default, Debug)]
 pub struct Color {
     pub red: u8,
@@ -15,12 +18,21 @@ impl Color {
         Color { red, green, blue }
     }
 
+    // Quantizes a vector with components in [0, 1], without any transfer
+    // function. Values outside that range are clamped.
     pub fn from_vec(v: Vec3f) -> Self {
-        Self::new(
-            (v.x.abs() * 255.0) as u8,
-            (v.y.abs() * 255.0) as u8,
-            (v.z.abs() * 255.0) as u8,
-        )
+        let quantize = |x: f64| (x.clamp(0.0, 1.0) * 255.0).round() as u8;
+        Self::new(quantize(v.x), quantize(v.y), quantize(v.z))
+    }
+
+    // Encodes a linear radiance value for display: clamps it to [0, 1] and
+    // applies the sRGB transfer function before quantizing.
+    pub fn from_linear(v: &Vec3f) -> Self {
+        Self::from_vec(Vec3f::new(
+            linear_to_srgb(v.x),
+            linear_to_srgb(v.y),
+            linear_to_srgb(v.z),
+        ))
     }
 
     pub fn from_vec_255(v: Vec3f) -> Self {
@@ -39,23 +51,24 @@ impl Color {
         Self::new(0, 0, 0)
     }
 
-    pub fn random() -> Self {
+    pub fn random(rng: &mut Rng) -> Self {
         Self::new(
-            rand::random::<u8>(),
-            rand::random::<u8>(),
-            rand::random::<u8>(),
+            rng.next_u64() as u8,
+            rng.next_u64() as u8,
+            rng.next_u64() as u8,
         )
     }
 }
 
+// Sums and differences saturate at 0 and 255 rather than wrapping around.
 impl<'a, 'b> ops::Add<&'b Color> for &'a Color {
     type Output = Color;
 
     fn add(self, other: &'b Color) -> Color {
         Color {
-            red: self.red + other.red,
-            green: self.green + other.green,
-            blue: self.blue + other.blue,
+            red: self.red.saturating_add(other.red),
+            green: self.green.saturating_add(other.green),
+            blue: self.blue.saturating_add(other.blue),
         }
     }
 }
@@ -65,9 +78,9 @@ impl ops::Add for Color {
 
     fn add(self, other: Self) -> Self {
         Self {
-            red: self.red + other.red,
-            green: self.green + other.green,
-            blue: self.blue + other.blue,
+            red: self.red.saturating_add(other.red),
+            green: self.green.saturating_add(other.green),
+            blue: self.blue.saturating_add(other.blue),
         }
     }
 }
@@ -77,9 +90,9 @@ impl<'a, 'b> ops::Sub<&'b Color> for &'a Color {
 
     fn sub(self, other: &'b Color) -> Color {
         Color {
-            red: self.red - other.red,
-            green: self.green - other.green,
-            blue: self.blue - other.blue,
+            red: self.red.saturating_sub(other.red),
+            green: self.green.saturating_sub(other.green),
+            blue: self.blue.saturating_sub(other.blue),
         }
     }
 }
@@ -89,9 +102,9 @@ impl ops::Sub for Color {
 
     fn sub(self, other: Self) -> Self {
         Self {
-            red: self.red - other.red,
-            green: self.green - other.green,
-            blue: self.blue - other.blue,
+            red: self.red.saturating_sub(other.red),
+            green: self.green.saturating_sub(other.green),
+            blue: self.blue.saturating_sub(other.blue),
         }
     }
 }
@@ -125,3 +138,74 @@ impl fmt::Display for Color {
         write!(f, "{}\t{}\t{}", self.red, self.green, self.blue)
     }
 }
+
+// The sRGB transfer function, mapping linear intensities in [0, 1] to the
+// perceptually uniform values that displays and 8-bit images expect.
+pub fn linear_to_srgb(x: f64) -> f64 {
+    let x = x.clamp(0.0, 1.0);
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Inverse of `linear_to_srgb`.
+pub fn srgb_to_linear(x: f64) -> f64 {
+    let x = x.clamp(0.0, 1.0);
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Black bodies are this bright, relative to each other, at this temperature:
+// about that of a candle flame, so that fire comes out in a sensible range.
+const BLACKBODY_REFERENCE: f64 = 1500.0;
+
+// One lobe of the analytic fit of the CIE 1931 color matching functions by
+// Wyman, Sloan and Shirley: a Gaussian with different widths on either side.
+fn cie_lobe(wavelength: f64, mean: f64, below: f64, above: f64) -> f64 {
+    let width = if wavelength < mean { below } else { above };
+    let x = (wavelength - mean) / width;
+    (-0.5 * x * x).exp()
+}
+
+// The CIE XYZ color of the light emitted by a black body at `temperature`
+// kelvins, up to a constant factor, from Planck's law.
+fn blackbody_xyz(temperature: f64) -> [f64; 3] {
+    // The second radiation constant, h * c / k, in nanometer kelvins.
+    const C2: f64 = 1.4388e7;
+    let mut xyz = [0.0; 3];
+    for wavelength in (380..=780).step_by(5).map(|w| w as f64) {
+        let radiance = wavelength.powi(-5) / (C2 / (wavelength * temperature)).exp_m1();
+        let l = wavelength;
+        xyz[0] += radiance
+            * (1.056 * cie_lobe(l, 599.8, 37.9, 31.0) + 0.362 * cie_lobe(l, 442.0, 16.0, 26.7)
+                - 0.065 * cie_lobe(l, 501.1, 20.4, 26.2));
+        xyz[1] += radiance
+            * (0.821 * cie_lobe(l, 568.8, 46.9, 40.5) + 0.286 * cie_lobe(l, 530.9, 16.3, 31.1));
+        xyz[2] += radiance
+            * (1.217 * cie_lobe(l, 437.0, 11.8, 36.0) + 0.681 * cie_lobe(l, 459.0, 26.0, 13.8));
+    }
+    xyz
+}
+
+// The linear sRGB radiance of a black body at `temperature` kelvins, scaled
+// so that one at BLACKBODY_REFERENCE has a luminance of 1. It grows very fast
+// with the temperature: 60 times brighter at 2000 K, a million times
+// dimmer at 800 K. Colors outside of the sRGB gamut, the deep reds of
+// cool bodies, are clipped.
+pub fn blackbody(temperature: f64) -> Vec3f {
+    if temperature <= 0.0 {
+        return Vec3f::new(0.0, 0.0, 0.0);
+    }
+    let [x, y, z] = blackbody_xyz(temperature);
+    let scale = 1.0 / blackbody_xyz(BLACKBODY_REFERENCE)[1];
+    Vec3f::new(
+        (3.2406 * x - 1.5372 * y - 0.4986 * z).max(0.0),
+        (-0.9689 * x + 1.8758 * y + 0.0415 * z).max(0.0),
+        (0.0557 * x - 0.2040 * y + 1.0570 * z).max(0.0),
+    ) * scale
+}
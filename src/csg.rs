@@ -0,0 +1,141 @@
+use crate::bvh::Aabb;
+use crate::object::{Hit, Interval, Shape, MIN_HIT_DISTANCE};
+use crate::vector::Ray;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CsgOperation {
+    // Inside either shape.
+    Union,
+    // Inside both shapes.
+    Intersection,
+    // Inside the left shape but not the right one.
+    Difference,
+}
+
+impl CsgOperation {
+    fn contains(self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            CsgOperation::Union => in_left || in_right,
+            CsgOperation::Intersection => in_left && in_right,
+            CsgOperation::Difference => in_left && !in_right,
+        }
+    }
+}
+
+// Constructive solid geometry: a solid made by combining two others. Both
+// must enclose a volume, i.e. report their intervals, and may themselves be
+// CSG nodes, so any number of shapes can be combined into a tree.
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub left: Arc<dyn Shape + Send + Sync>,
+    pub right: Arc<dyn Shape + Send + Sync>,
+}
+
+impl Shape for Csg {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.intervals(ray)
+            .into_iter()
+            .flat_map(|interval| [interval.enter, interval.exit])
+            .find(|hit| hit.t >= MIN_HIT_DISTANCE)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let left = self.left.bounding_box();
+        let right = self.right.bounding_box();
+        match self.operation {
+            CsgOperation::Union => left.union(&right),
+            CsgOperation::Intersection => {
+                Aabb::new(left.min.max(&right.min), left.max.min(&right.max))
+            }
+            CsgOperation::Difference => left,
+        }
+    }
+
+    // Walks along the boundaries of both shapes in order, keeping track of
+    // which ones the ray is inside of, and keeps the boundaries where that
+    // changes whether it's inside the combined shape.
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let mut boundaries: Vec<(Hit, bool, bool)> = vec![];
+        for (is_left, shape) in [(true, &self.left), (false, &self.right)] {
+            for interval in shape.intervals(ray) {
+                boundaries.push((interval.enter, is_left, true));
+                boundaries.push((interval.exit, is_left, false));
+            }
+        }
+        boundaries.sort_by(|a, b| a.0.t.total_cmp(&b.0.t));
+
+        let (mut in_left, mut in_right) = (false, false);
+        let mut enter: Option<Hit> = None;
+        let mut intervals = vec![];
+        for (mut hit, is_left, entering) in boundaries {
+            let was_inside = self.operation.contains(in_left, in_right);
+            if is_left {
+                in_left = entering;
+            } else {
+                in_right = entering;
+            }
+            let inside = self.operation.contains(in_left, in_right);
+            if inside == was_inside {
+                continue;
+            }
+            // The normal still faces the ray, but which side of the combined
+            // shape it's on depends on the operation: leaving the right shape
+            // of a difference enters the result.
+            hit.front_face = inside;
+            if inside {
+                enter = Some(hit);
+            } else if let Some(enter) = enter.take() {
+                intervals.push(Interval { enter, exit: hit });
+            }
+        }
+        intervals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Sphere;
+    use crate::vector::Vec3f;
+
+    // Two unit spheres, centered at x = 0 and x = 1, along a ray down the x
+    // axis from x = -3: inside the left one for t in [2, 4] and the right one
+    // for t in [3, 5].
+    fn intervals(operation: CsgOperation) -> Vec<(f64, f64)> {
+        let sphere = |x| {
+            Arc::new(Sphere {
+                center: Vec3f::new(x, 0.0, 0.0),
+                radius: 1.0,
+            })
+        };
+        let csg = Csg {
+            operation,
+            left: sphere(0.0),
+            right: sphere(1.0),
+        };
+        let ray = Ray {
+            origin: Vec3f::new(-3.0, 0.0, 0.0),
+            dir: Vec3f::new(1.0, 0.0, 0.0),
+        };
+        csg.intervals(&ray)
+            .iter()
+            .map(|interval| (interval.enter.t, interval.exit.t))
+            .collect()
+    }
+
+    #[test]
+    fn union() {
+        assert_eq!(intervals(CsgOperation::Union), [(2.0, 5.0)]);
+    }
+
+    #[test]
+    fn intersection() {
+        assert_eq!(intervals(CsgOperation::Intersection), [(3.0, 4.0)]);
+    }
+
+    #[test]
+    fn difference() {
+        assert_eq!(intervals(CsgOperation::Difference), [(2.0, 3.0)]);
+    }
+}
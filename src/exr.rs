@@ -0,0 +1,537 @@
+use crate::framebuffer::Framebuffer;
+use crate::zlib;
+use std::io::{self, Write};
+
+// A writer for single-part scanline OpenEXR files, following "The OpenEXR
+// File Layout": a header made of named attributes, a table with the offset
+// of every chunk of scanlines, and then the chunks themselves.
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+// File format version 2, without any of the tiled/multi-part/deep flags.
+const VERSION: [u8; 4] = [2, 0, 0, 0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrPixelType {
+    Half,
+    Float,
+}
+
+impl ExrPixelType {
+    fn id(self) -> i32 {
+        match self {
+            ExrPixelType::Half => 1,
+            ExrPixelType::Float => 2,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ExrPixelType::Half => 2,
+            ExrPixelType::Float => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrCompression {
+    None,
+    // Deflate, applied to blocks of 16 scanlines.
+    Zip,
+}
+
+impl ExrCompression {
+    fn id(self) -> u8 {
+        match self {
+            ExrCompression::None => 0,
+            ExrCompression::Zip => 3,
+        }
+    }
+
+    fn scanlines_per_chunk(self) -> usize {
+        match self {
+            ExrCompression::None => 1,
+            ExrCompression::Zip => 16,
+        }
+    }
+}
+
+// A set of RGB channels. The beauty render is usually the layer with an empty
+// name, whose channels are plainly called R, G and B; other layers, such as
+// AOVs, get channels named `<name>.R`, `<name>.G` and `<name>.B`.
+pub struct ExrLayer<'a> {
+    pub name: &'a str,
+    pub framebuffer: &'a Framebuffer,
+}
+
+struct Channel<'a> {
+    name: String,
+    framebuffer: &'a Framebuffer,
+    // 0, 1 or 2 for the red, green or blue component of the framebuffer.
+    component: usize,
+}
+
+// Converts to IEEE 754 binary16, rounding to the nearest even value.
+// Values too large for a half become infinities.
+pub fn f32_to_half(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        // Infinity stays infinity, NaN stays (a quiet) NaN.
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let half_exponent = exponent - 127 + 15;
+    if half_exponent >= 31 {
+        return sign | 0x7c00;
+    }
+
+    // Keep the top bits of `mantissa` and round based on the dropped ones.
+    let round = |mantissa: u32, shift: u32| {
+        let kept = mantissa >> shift;
+        let dropped = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        if dropped > halfway || (dropped == halfway && kept & 1 == 1) {
+            kept + 1
+        } else {
+            kept
+        }
+    };
+
+    if half_exponent <= 0 {
+        // Too small for a normal half: make a subnormal out of the mantissa
+        // with its implicit leading one, or flush to zero.
+        if half_exponent < -10 {
+            return sign;
+        }
+        let shift = (14 - half_exponent) as u32;
+        return sign | round(mantissa | 0x80_0000, shift) as u16;
+    }
+
+    // A carry out of the mantissa correctly bumps the exponent.
+    sign | (((half_exponent as u32) << 10) + round(mantissa, 13)) as u16
+}
+
+fn write_attribute(header: &mut Vec<u8>, name: &str, attribute_type: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(attribute_type.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn box2i(width: usize, height: usize) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect()
+}
+
+// Reorders the bytes of a chunk and replaces them with their differences,
+// which is what OpenEXR does to floating point data before deflating it.
+fn zip_predict(data: &[u8]) -> Vec<u8> {
+    // The first half receives the even bytes and the second half the odd
+    // ones, so the (similar) high bytes of neighbouring values end up together.
+    let mut reordered: Vec<u8> = data.iter().step_by(2).copied().collect();
+    reordered.extend(data.iter().skip(1).step_by(2));
+
+    let Some(&first) = reordered.first() else {
+        return reordered;
+    };
+    let mut previous = first;
+    for byte in reordered.iter_mut().skip(1) {
+        let current = *byte;
+        *byte = current.wrapping_sub(previous).wrapping_add(128);
+        previous = current;
+    }
+    reordered
+}
+
+pub fn write_exr(
+    writer: &mut impl Write,
+    layers: &[ExrLayer],
+    pixel_type: ExrPixelType,
+    compression: ExrCompression,
+) -> Result<(), io::Error> {
+    let (width, height) = match layers.first() {
+        Some(layer) => (layer.framebuffer.width, layer.framebuffer.height),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "an EXR file needs at least one layer",
+            ))
+        }
+    };
+    if layers
+        .iter()
+        .any(|layer| layer.framebuffer.width != width || layer.framebuffer.height != height)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "all layers of an EXR file must have the same size",
+        ));
+    }
+
+    // Channels have to be stored in alphabetical order.
+    let mut channels = vec![];
+    for layer in layers.iter() {
+        for (component, suffix) in ["R", "G", "B"].iter().enumerate() {
+            let name = if layer.name.is_empty() {
+                suffix.to_string()
+            } else {
+                format!("{}.{}", layer.name, suffix)
+            };
+            channels.push(Channel {
+                name,
+                framebuffer: layer.framebuffer,
+                component,
+            });
+        }
+    }
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut header = vec![];
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION);
+
+    let mut channel_list = vec![];
+    for channel in channels.iter() {
+        channel_list.extend_from_slice(channel.name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&pixel_type.id().to_le_bytes());
+        // pLinear and three reserved bytes, then x and y sampling.
+        channel_list.extend_from_slice(&[0, 0, 0, 0]);
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channel_list.push(0);
+    write_attribute(&mut header, "channels", "chlist", &channel_list);
+    write_attribute(
+        &mut header,
+        "compression",
+        "compression",
+        &[compression.id()],
+    );
+    write_attribute(&mut header, "dataWindow", "box2i", &box2i(width, height));
+    write_attribute(&mut header, "displayWindow", "box2i", &box2i(width, height));
+    // Increasing y: chunks are stored from the top of the image down.
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    write_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    let mut window_center = 0f32.to_le_bytes().to_vec();
+    window_center.extend_from_slice(&0f32.to_le_bytes());
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &window_center);
+    write_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    let lines_per_chunk = compression.scanlines_per_chunk();
+    let mut chunks = vec![];
+    for first_line in (0..height).step_by(lines_per_chunk) {
+        let last_line = (first_line + lines_per_chunk).min(height);
+        let mut data = Vec::with_capacity(
+            (last_line - first_line) * width * channels.len() * pixel_type.size(),
+        );
+        // Within a chunk, each scanline holds all values of its first
+        // channel, then all values of the second one, and so on.
+        for row in first_line..last_line {
+            for channel in channels.iter() {
+                for col in 0..width {
+                    let value = channel.framebuffer.pixel(row, col)[channel.component] as f32;
+                    match pixel_type {
+                        ExrPixelType::Half => {
+                            data.extend_from_slice(&f32_to_half(value).to_le_bytes())
+                        }
+                        ExrPixelType::Float => data.extend_from_slice(&value.to_le_bytes()),
+                    }
+                }
+            }
+        }
+
+        // Readers take a chunk that is as large as its uncompressed data to be
+        // uncompressed, so only keep the compressed data if it's smaller.
+        if compression == ExrCompression::Zip {
+            let compressed = zlib::compress(&zip_predict(&data));
+            if compressed.len() < data.len() {
+                data = compressed;
+            }
+        }
+
+        let mut chunk = (first_line as i32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        chunk.extend_from_slice(&data);
+        chunks.push(chunk);
+    }
+
+    writer.write_all(&header)?;
+    let mut offset = (header.len() + 8 * chunks.len()) as u64;
+    for chunk in chunks.iter() {
+        writer.write_all(&offset.to_le_bytes())?;
+        offset += chunk.len() as u64;
+    }
+    for chunk in chunks.iter() {
+        writer.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector::Vec3f;
+    use std::convert::TryInto;
+
+    #[test]
+    fn half_known_values() {
+        let cases: [(f32, u16); 16] = [
+            (0.0, 0x0000),
+            (-0.0, 0x8000),
+            (1.0, 0x3c00),
+            (-2.0, 0xc000),
+            (0.5, 0x3800),
+            (65504.0, 0x7bff),
+            // Halfway between 65504 and the next step, 65536, rounds up to it.
+            (65520.0, 0x7c00),
+            (1e10, 0x7c00),
+            (f32::INFINITY, 0x7c00),
+            (f32::NEG_INFINITY, 0xfc00),
+            // The smallest normal and the smallest and largest subnormals.
+            (2f32.powi(-14), 0x0400),
+            (2f32.powi(-24), 0x0001),
+            (2f32.powi(-14) - 2f32.powi(-24), 0x03ff),
+            // Half of the smallest subnormal is a tie, rounding to even zero.
+            (2f32.powi(-25), 0x0000),
+            (1.5 * 2f32.powi(-24), 0x0002),
+            (2f32.powi(-30), 0x0000),
+        ];
+        for &(x, half) in cases.iter() {
+            assert_eq!(f32_to_half(x), half, "{:e}", x);
+        }
+
+        // Halves have 10 mantissa bits, so around 1 they are 2^-10 apart.
+        let step = 2f32.powi(-10);
+        // Ties go to the even mantissa: down from 1 + step/2, up from 1 + 3 step/2.
+        assert_eq!(f32_to_half(1.0 + step / 2.0), 0x3c00);
+        assert_eq!(f32_to_half(1.0 + 1.5 * step), 0x3c02);
+        // Anything past the tie rounds up, anything short of it down.
+        assert_eq!(f32_to_half(1.0 + step * 0.5001), 0x3c01);
+        assert_eq!(f32_to_half(1.0 + step * 0.4999), 0x3c00);
+        // A carry out of the mantissa moves on to the next exponent.
+        assert_eq!(f32_to_half(2.0 - step / 4.0), 0x4000);
+
+        let nan = f32_to_half(f32::NAN);
+        assert_eq!(nan & 0x7c00, 0x7c00);
+        assert_ne!(nan & 0x3ff, 0);
+    }
+
+    #[test]
+    fn zip_predict_reorders_and_differences_bytes() {
+        assert_eq!(zip_predict(&[]), Vec::<u8>::new());
+        assert_eq!(zip_predict(&[1, 2, 3, 4]), [1, 130, 127, 130]);
+        assert_eq!(zip_predict(&[10, 0, 10]), [10, 128, 118]);
+    }
+
+    fn read_i32(data: &[u8], at: usize) -> i32 {
+        i32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    fn read_string(data: &[u8], at: &mut usize) -> String {
+        let end = *at + data[*at..].iter().position(|&b| b == 0).unwrap();
+        let string = String::from_utf8(data[*at..end].to_vec()).unwrap();
+        *at = end + 1;
+        string
+    }
+
+    // Undoes zip_predict.
+    fn zip_unpredict(data: &[u8]) -> Vec<u8> {
+        let mut reordered = data.to_vec();
+        for i in 1..reordered.len() {
+            reordered[i] = reordered[i]
+                .wrapping_add(reordered[i - 1])
+                .wrapping_sub(128);
+        }
+        let (even, odd) = reordered.split_at(data.len().div_ceil(2));
+        let mut bytes = vec![];
+        for i in 0..even.len() {
+            bytes.push(even[i]);
+            if i < odd.len() {
+                bytes.push(odd[i]);
+            }
+        }
+        bytes
+    }
+
+    fn check_file(pixel_type: ExrPixelType, compression: ExrCompression) {
+        let (width, height) = (5, 20);
+        let mut beauty = Framebuffer::new(width, height);
+        let mut depth = Framebuffer::new(width, height);
+        for row in 0..height {
+            for col in 0..width {
+                let x = (row * width + col) as f64;
+                beauty.set_pixel(row, col, Vec3f::new(x, 0.5, -x / 8.0));
+                depth.set_pixel(row, col, Vec3f::new(x, x, x) * 0.25);
+            }
+        }
+        let layers = [
+            ExrLayer {
+                name: "",
+                framebuffer: &beauty,
+            },
+            ExrLayer {
+                name: "depth",
+                framebuffer: &depth,
+            },
+        ];
+        let mut data = vec![];
+        write_exr(&mut data, &layers, pixel_type, compression).unwrap();
+
+        assert_eq!(data[0..4], MAGIC);
+        assert_eq!(data[4..8], VERSION);
+
+        let mut at = 8;
+        let mut attributes = vec![];
+        while data[at] != 0 {
+            let name = read_string(&data, &mut at);
+            let attribute_type = read_string(&data, &mut at);
+            let size = read_i32(&data, at) as usize;
+            attributes.push((name, attribute_type, data[at + 4..at + 4 + size].to_vec()));
+            at += 4 + size;
+        }
+        at += 1;
+        let names: Vec<(&str, &str)> = attributes
+            .iter()
+            .map(|(name, attribute_type, _)| (name.as_str(), attribute_type.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("channels", "chlist"),
+                ("compression", "compression"),
+                ("dataWindow", "box2i"),
+                ("displayWindow", "box2i"),
+                ("lineOrder", "lineOrder"),
+                ("pixelAspectRatio", "float"),
+                ("screenWindowCenter", "v2f"),
+                ("screenWindowWidth", "float"),
+            ]
+        );
+
+        let channel_list = &attributes[0].2;
+        let mut channels = vec![];
+        let mut i = 0;
+        while channel_list[i] != 0 {
+            let name = read_string(channel_list, &mut i);
+            assert_eq!(read_i32(channel_list, i), pixel_type.id());
+            assert_eq!(read_i32(channel_list, i + 8), 1);
+            assert_eq!(read_i32(channel_list, i + 12), 1);
+            channels.push(name);
+            i += 16;
+        }
+        assert_eq!(channels, ["B", "G", "R", "depth.B", "depth.G", "depth.R"]);
+        assert_eq!(attributes[1].2, [compression.id()]);
+        let window: Vec<i32> = (0..4).map(|i| read_i32(&attributes[2].2, 4 * i)).collect();
+        assert_eq!(window, [0, 0, width as i32 - 1, height as i32 - 1]);
+
+        // Every entry of the offset table points to the next chunk in turn.
+        let lines_per_chunk = compression.scanlines_per_chunk();
+        let num_chunks = height.div_ceil(lines_per_chunk);
+        let mut chunk_start = at + 8 * num_chunks;
+        for chunk in 0..num_chunks {
+            let offset =
+                u64::from_le_bytes(data[at + 8 * chunk..at + 8 * chunk + 8].try_into().unwrap());
+            assert_eq!(offset as usize, chunk_start);
+
+            let first_line = chunk * lines_per_chunk;
+            let lines = lines_per_chunk.min(height - first_line);
+            assert_eq!(read_i32(&data, chunk_start), first_line as i32);
+            let size = read_i32(&data, chunk_start + 4) as usize;
+            let mut values = data[chunk_start + 8..chunk_start + 8 + size].to_vec();
+            let uncompressed_size = lines * width * channels.len() * pixel_type.size();
+            if size < uncompressed_size {
+                assert_eq!(compression, ExrCompression::Zip);
+                values = zip_unpredict(&zlib::decompress(&values).unwrap());
+            }
+            assert_eq!(values.len(), uncompressed_size);
+
+            let mut values = values.chunks(pixel_type.size());
+            for row in first_line..first_line + lines {
+                for (channel, &(framebuffer, component)) in [
+                    (&beauty, 2),
+                    (&beauty, 1),
+                    (&beauty, 0),
+                    (&depth, 2),
+                    (&depth, 1),
+                    (&depth, 0),
+                ]
+                .iter()
+                .enumerate()
+                {
+                    for col in 0..width {
+                        let expected = framebuffer.pixel(row, col)[component] as f32;
+                        let expected = match pixel_type {
+                            ExrPixelType::Half => f32_to_half(expected).to_le_bytes().to_vec(),
+                            ExrPixelType::Float => expected.to_le_bytes().to_vec(),
+                        };
+                        assert_eq!(
+                            values.next().unwrap(),
+                            expected,
+                            "row {} col {} channel {}",
+                            row,
+                            col,
+                            channels[channel]
+                        );
+                    }
+                }
+            }
+            chunk_start += 8 + size;
+        }
+        assert_eq!(chunk_start, data.len());
+    }
+
+    #[test]
+    fn uncompressed_layers() {
+        check_file(ExrPixelType::Half, ExrCompression::None);
+        check_file(ExrPixelType::Float, ExrCompression::None);
+    }
+
+    #[test]
+    fn zip_compressed_layers() {
+        check_file(ExrPixelType::Half, ExrCompression::Zip);
+        check_file(ExrPixelType::Float, ExrCompression::Zip);
+    }
+
+    #[test]
+    fn layers_must_match_in_size() {
+        let (small, large) = (Framebuffer::new(2, 2), Framebuffer::new(3, 2));
+        let layers = [
+            ExrLayer {
+                name: "",
+                framebuffer: &small,
+            },
+            ExrLayer {
+                name: "normal",
+                framebuffer: &large,
+            },
+        ];
+        let error = write_exr(
+            &mut vec![],
+            &layers,
+            ExrPixelType::Half,
+            ExrCompression::None,
+        );
+        assert!(error.is_err());
+        let error = write_exr(&mut vec![], &[], ExrPixelType::Half, ExrCompression::None);
+        assert!(error.is_err());
+    }
+}
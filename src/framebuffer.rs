@@ -0,0 +1,47 @@
+use crate::vector::Vec3f;
+
+// A floating point image holding linear RGB radiance. Samples are accumulated
+// per pixel, so more passes can be added to a render to refine it, and values
+// are never clamped: quantizing to 8 bits is left to the output stage.
+#[derive(Debug, Default)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    sums: Vec<Vec3f>,
+    sample_counts: Vec<usize>,
+}
+
+// all fields are 0-indexed
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer {
+            width,
+            height,
+            sums: vec![Vec3f::new(0.0, 0.0, 0.0); width * height],
+            sample_counts: vec![0; width * height],
+        }
+    }
+
+    // Adds `count` samples whose radiance adds up to `sum` to a pixel.
+    pub fn add_samples(&mut self, row: usize, col: usize, sum: Vec3f, count: usize) {
+        let index = row * self.width + col;
+        self.sums[index] = &self.sums[index] + &sum;
+        self.sample_counts[index] += count;
+    }
+
+    // Replaces the samples of a pixel with a single sample of the given value.
+    pub fn set_pixel(&mut self, row: usize, col: usize, value: Vec3f) {
+        let index = row * self.width + col;
+        self.sums[index] = value;
+        self.sample_counts[index] = 1;
+    }
+
+    // The mean radiance of the samples in a pixel, or black if there are none.
+    pub fn pixel(&self, row: usize, col: usize) -> Vec3f {
+        let index = row * self.width + col;
+        match self.sample_counts[index] {
+            0 => Vec3f::new(0.0, 0.0, 0.0),
+            count => &self.sums[index] * (1.0 / count as f64),
+        }
+    }
+}
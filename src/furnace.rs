@@ -0,0 +1,346 @@
+use crate::object::{Hit, Material};
+use crate::sampler::{self, Sampler};
+use crate::sampling::{
+    concentric_disk, cosine_hemisphere, cosine_hemisphere_pdf, disk_pdf, triangle_pdf,
+    uniform_disk, uniform_hemisphere, uniform_hemisphere_pdf, uniform_sphere, uniform_sphere_pdf,
+    uniform_triangle,
+};
+use crate::texture::{ConstantTexture, Texture};
+use crate::vector::{Ray, Vec3f};
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// Statistical checks of the sampling functions and of materials, for the
+// tests of the samplers and of every material.
+//
+// The samplers, and the sampling functions with the numbers of each, are
+// checked against integrals with known values. Materials are put in a white
+// furnace: lit evenly by white light from every direction, a surface can only
+// look as bright as the light or darker, so the average weight `scatter`
+// returns, its albedo, must not exceed 1, and materials that lose no light
+// must reach exactly 1. Materials with a BSDF are also checked for
+// consistency: the weight of every scattered ray must be `eval / pdf`, and
+// the albedo must match the integral of `eval` over the sphere, estimated
+// independently with uniformly picked directions.
+
+const SAMPLES: usize = 100_000;
+
+// How many standard errors an estimate may be off before a check fails. The
+// checks are random, so with a tolerance tight enough to catch small biases,
+// correct code would fail with some seeds.
+const TOLERANCE: f64 = 5.0;
+
+// Room for rounding errors in checks of exact values.
+const EPSILON: f64 = 1e-6;
+
+// The mean and standard error of a Monte Carlo estimate, per color channel.
+struct Estimate {
+    sum: [f64; 3],
+    sum_sq: [f64; 3],
+    count: usize,
+}
+
+impl Estimate {
+    // Averages `SAMPLES` samples, each taken with the numbers of a sample of
+    // the same pixel from `sampler`.
+    fn new<F: FnMut(&mut dyn Sampler) -> [f64; 3]>(
+        sampler: &mut dyn Sampler,
+        mut sample: F,
+    ) -> Self {
+        let mut estimate = Estimate {
+            sum: [0.0; 3],
+            sum_sq: [0.0; 3],
+            count: SAMPLES,
+        };
+        for index in 0..SAMPLES {
+            sampler.start_pixel_sample((0, 0), index);
+            for (c, value) in sample(sampler).iter().enumerate() {
+                estimate.sum[c] += value;
+                estimate.sum_sq[c] += value * value;
+            }
+        }
+        estimate
+    }
+
+    fn mean(&self) -> [f64; 3] {
+        self.sum.map(|sum| sum / self.count as f64)
+    }
+
+    // For samplers other than the independent one, samples are spread out
+    // more evenly than independent ones, and the actual error is smaller.
+    fn error(&self) -> [f64; 3] {
+        let n = self.count as f64;
+        let mean = self.mean();
+        [0, 1, 2].map(|c| ((self.sum_sq[c] / n - mean[c] * mean[c]).max(0.0) / n).sqrt())
+    }
+}
+
+fn channels(color: &Vec3f) -> [f64; 3] {
+    [color.x, color.y, color.z]
+}
+
+fn format(values: &[f64; 3]) -> String {
+    format!("({:.4}, {:.4}, {:.4})", values[0], values[1], values[2])
+}
+
+// Collects the failed checks, to report them all at once.
+struct Report {
+    failures: Vec<String>,
+    // Every estimate gets a sampler with its own seed, so that estimates
+    // compared with each other are independent, and every run gives the
+    // same results.
+    seed: u64,
+}
+
+impl Report {
+    fn new() -> Self {
+        Report {
+            failures: vec![],
+            seed: 0,
+        }
+    }
+
+    fn sampler(&mut self, name: &str) -> Box<dyn Sampler> {
+        self.seed += 1;
+        sampler::from_name(name, SAMPLES, self.seed)
+            .expect("unknown sampler")
+            .clone_sampler()
+    }
+
+    fn check(&mut self, passed: bool, name: &str, detail: String) {
+        if !passed {
+            self.failures.push(format!("{}: {}", name, detail));
+        }
+    }
+
+    // Checks that an estimate agrees with an exact value.
+    fn expect(&mut self, name: &str, estimate: &Estimate, expected: f64) {
+        let (mean, error) = (estimate.mean(), estimate.error());
+        let passed = (0..3).all(|c| (mean[c] - expected).abs() <= TOLERANCE * error[c] + EPSILON);
+        self.check(
+            passed,
+            name,
+            format!("{} expected {:.4}", format(&mean), expected),
+        );
+    }
+
+    fn assert_passed(&self) {
+        assert!(
+            self.failures.is_empty(),
+            "{} checks failed:\n{}",
+            self.failures.len(),
+            self.failures.join("\n")
+        );
+    }
+}
+
+// Checks a sampler, and the sampling functions fed with its numbers. Panics
+// if any check fails.
+pub fn check_sampler(sampler_name: &str) {
+    let mut report = Report::new();
+    let report = &mut report;
+    let name = |check: &str| format!("{} sampler, {}", sampler_name, check);
+
+    // Every dimension must be uniformly distributed, and independent of the
+    // others, far beyond the few dimensions of a camera ray.
+    let mut invalid = 0;
+    let dimensions = Estimate::new(report.sampler(sampler_name).as_mut(), |sampler| {
+        let mut values = vec![];
+        for _ in 0..40 {
+            values.push(sampler.next_1d());
+            let (u, v) = sampler.next_2d();
+            values.extend([u, v]);
+        }
+        invalid += values.iter().filter(|u| !(0.0..1.0).contains(*u)).count();
+        let mean = values.iter().sum::<f64>() / values.len() as f64;
+        // The correlation of consecutive dimensions.
+        let correlation = values
+            .windows(2)
+            .map(|pair| 3.0 * (2.0 * pair[0] - 1.0) * (2.0 * pair[1] - 1.0))
+            .sum::<f64>()
+            / (values.len() - 1) as f64;
+        [mean, correlation, correlation]
+    });
+    let (mean, error) = (dimensions.mean(), dimensions.error());
+    report.check(
+        (mean[0] - 0.5).abs() <= TOLERANCE * error[0] + EPSILON,
+        &name("mean of every dimension"),
+        format!("{:.4} expected 0.5", mean[0]),
+    );
+    report.check(
+        mean[1].abs() <= TOLERANCE * error[1] + EPSILON,
+        &name("correlation of consecutive dimensions"),
+        format!("{:.4} expected 0", mean[1]),
+    );
+
+    // Every sample has to land where the function promises, or the estimates
+    // below don't mean much.
+    let mut direction = |w: Vec3f, hemisphere: bool| {
+        if (w.norm() - 1.0).abs() > EPSILON || (hemisphere && w.z < 0.0) {
+            invalid += 1;
+        }
+        w
+    };
+    let estimate = Estimate::new(report.sampler(sampler_name).as_mut(), |sampler| {
+        let w = direction(uniform_hemisphere(sampler.next_2d()), true);
+        [w.z / uniform_hemisphere_pdf(); 3]
+    });
+    report.expect(&name("uniform_hemisphere, integral of cos"), &estimate, PI);
+    let estimate = Estimate::new(report.sampler(sampler_name).as_mut(), |sampler| {
+        let w = direction(cosine_hemisphere(sampler.next_2d()), true);
+        [w.z * w.z / cosine_hemisphere_pdf(w.z); 3]
+    });
+    report.expect(
+        &name("cosine_hemisphere, integral of cos^2"),
+        &estimate,
+        2.0 * PI / 3.0,
+    );
+    let estimate = Estimate::new(report.sampler(sampler_name).as_mut(), |sampler| {
+        let w = direction(uniform_sphere(sampler.next_2d()), false);
+        [w.z * w.z / uniform_sphere_pdf(); 3]
+    });
+    report.expect(
+        &name("uniform_sphere, integral of z^2"),
+        &estimate,
+        4.0 * PI / 3.0,
+    );
+
+    let mut point = |(x, y): (f64, f64)| {
+        if x * x + y * y > 1.0 + EPSILON {
+            invalid += 1;
+        }
+        x * x + y * y
+    };
+    let estimate = Estimate::new(report.sampler(sampler_name).as_mut(), |sampler| {
+        [point(uniform_disk(sampler.next_2d())) / disk_pdf(); 3]
+    });
+    report.expect(&name("uniform_disk, integral of r^2"), &estimate, PI / 2.0);
+    let estimate = Estimate::new(report.sampler(sampler_name).as_mut(), |sampler| {
+        [point(concentric_disk(sampler.next_2d())) / disk_pdf(); 3]
+    });
+    report.expect(
+        &name("concentric_disk, integral of r^2"),
+        &estimate,
+        PI / 2.0,
+    );
+
+    // On the triangle (0, 0), (1, 0), (0, 1), where the barycentric
+    // coordinates are the x and y coordinates.
+    let estimate = Estimate::new(report.sampler(sampler_name).as_mut(), |sampler| {
+        let (b1, b2) = uniform_triangle(sampler.next_2d());
+        if b1 < 0.0 || b2 < 0.0 || b1 + b2 > 1.0 {
+            invalid += 1;
+        }
+        [b1 / triangle_pdf(0.5); 3]
+    });
+    report.expect(
+        &name("uniform_triangle, integral of x"),
+        &estimate,
+        1.0 / 6.0,
+    );
+
+    report.check(
+        invalid == 0,
+        &name("samples in their domains"),
+        format!("{} out of bounds", invalid),
+    );
+    report.assert_passed();
+}
+
+pub fn constant(value: f64) -> Arc<dyn Texture + Send + Sync> {
+    Arc::new(ConstantTexture {
+        color: Vec3f::new(value, value, value),
+    })
+}
+
+// What a material must do in the furnace.
+pub struct Furnace {
+    // The albedo the material must have, if it's known exactly.
+    pub albedo: Option<f64>,
+    // Whether `eval` covers all the light the material scatters, so that it
+    // can be integrated to find the albedo. Not for materials with
+    // perfectly specular reflections, or like `MetalMaterial` none at all.
+    pub has_bsdf: bool,
+    // Whether to also check rays coming from inside the surface.
+    pub two_sided: bool,
+}
+
+impl Furnace {
+    // Puts `material` in the furnace with light arriving at a few angles.
+    // Panics if any check fails.
+    pub fn check(&self, material: &dyn Material) {
+        let mut report = Report::new();
+        for angle in [0.0, 30.0, 60.0, 85.0] {
+            self.check_angle(&mut report, material, angle, false);
+            if self.two_sided {
+                self.check_angle(&mut report, material, angle, true);
+            }
+        }
+        report.assert_passed();
+    }
+
+    // Light arrives at `angle` degrees from the normal of a surface facing
+    // +z, from above or from below.
+    fn check_angle(&self, report: &mut Report, material: &dyn Material, angle: f64, inside: bool) {
+        let name = format!(
+            "at {} degrees{}",
+            angle,
+            if inside { " from inside" } else { "" }
+        );
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let side = if inside { -1.0 } else { 1.0 };
+        // Hits the surface at the origin.
+        let ray = Ray {
+            origin: Vec3f::new(-sin, 0.0, side * cos),
+            dir: Vec3f::new(sin, 0.0, -side * cos),
+        };
+        let hit = Hit::new(&ray, 1.0, Vec3f::new(0.0, 0.0, 1.0), (0.5, 0.5));
+
+        let mut inconsistent = 0;
+        let albedo = Estimate::new(report.sampler("independent").as_mut(), |sampler| {
+            let Some((weight, scattered)) = material.scatter(&ray, &hit, sampler) else {
+                return [0.0; 3];
+            };
+            let pdf = material.pdf(&ray, &hit, &scattered.dir);
+            if pdf > 0.0 {
+                let expected = channels(&(material.eval(&ray, &hit, &scattered.dir) * (1.0 / pdf)));
+                let weight = channels(&weight);
+                if (0..3).any(|c| (weight[c] - expected[c]).abs() > EPSILON * expected[c].max(1.0))
+                {
+                    inconsistent += 1;
+                }
+            }
+            channels(&weight)
+        });
+
+        let (mean, error) = (albedo.mean(), albedo.error());
+        report.check(
+            (0..3).all(|c| mean[c] <= 1.0 + TOLERANCE * error[c] + EPSILON),
+            &format!("{}, albedo at most 1", name),
+            format(&mean),
+        );
+        if let Some(expected) = self.albedo {
+            report.expect(&format!("{}, albedo", name), &albedo, expected);
+        }
+        if self.has_bsdf {
+            report.check(
+                inconsistent == 0,
+                &format!("{}, weights are eval / pdf", name),
+                format!("{} inconsistent", inconsistent),
+            );
+            let integral = Estimate::new(report.sampler("independent").as_mut(), |sampler| {
+                let direction = uniform_sphere(sampler.next_2d());
+                channels(&(material.eval(&ray, &hit, &direction) * (1.0 / uniform_sphere_pdf())))
+            });
+            let (other, other_error) = (integral.mean(), integral.error());
+            report.check(
+                (0..3).all(|c| {
+                    let error = (error[c] * error[c] + other_error[c] * other_error[c]).sqrt();
+                    (mean[c] - other[c]).abs() <= TOLERANCE * error + EPSILON
+                }),
+                &format!("{}, albedo matches the integral of eval", name),
+                format(&other),
+            );
+        }
+    }
+}
@@ -0,0 +1,848 @@
+use crate::color::{linear_to_srgb, srgb_to_linear};
+use crate::exr::{self, ExrCompression, ExrLayer, ExrPixelType};
+use crate::framebuffer::Framebuffer;
+use crate::vector::Vec3f;
+use crate::zlib;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// The file formats a render can be saved as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    // Binary (P6) 8-bit sRGB PPM.
+    Ppm,
+    // sRGB PNG with 8 or 16 bits per channel.
+    Png {
+        bit_depth: u8,
+    },
+    // Portable float map: linear, unclamped 32-bit floats.
+    Pfm,
+    // Radiance RGBE: linear, with a shared 8-bit exponent per pixel.
+    Hdr,
+    // Scanline OpenEXR with half or full float channels.
+    Exr {
+        pixel_type: ExrPixelType,
+        compression: ExrCompression,
+    },
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+impl ImageFormat {
+    // Picks the format from the extension of `path`. `bit_depth` selects 8 or
+    // 16-bit PNGs and 16 (half) or 32-bit (float) EXRs, and `compression`
+    // ("zip" or "none") applies to EXRs.
+    pub fn from_path(
+        path: &Path,
+        bit_depth: Option<u8>,
+        compression: Option<&str>,
+    ) -> Result<Self, io::Error> {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("ppm") => Ok(ImageFormat::Ppm),
+            Some("png") => match bit_depth.unwrap_or(8) {
+                bit_depth @ (8 | 16) => Ok(ImageFormat::Png { bit_depth }),
+                bit_depth => Err(invalid_input(format!(
+                    "PNG bit depth must be 8 or 16, not {}",
+                    bit_depth
+                ))),
+            },
+            Some("pfm") => Ok(ImageFormat::Pfm),
+            Some("hdr") => Ok(ImageFormat::Hdr),
+            Some("exr") => {
+                let pixel_type = match bit_depth.unwrap_or(16) {
+                    16 => ExrPixelType::Half,
+                    32 => ExrPixelType::Float,
+                    bit_depth => {
+                        return Err(invalid_input(format!(
+                            "EXR bit depth must be 16 or 32, not {}",
+                            bit_depth
+                        )))
+                    }
+                };
+                let compression = match compression.unwrap_or("zip") {
+                    "zip" => ExrCompression::Zip,
+                    "none" => ExrCompression::None,
+                    compression => {
+                        return Err(invalid_input(format!(
+                            "unknown EXR compression `{}`, expected zip or none",
+                            compression
+                        )))
+                    }
+                };
+                Ok(ImageFormat::Exr {
+                    pixel_type,
+                    compression,
+                })
+            }
+            _ => Err(invalid_input(
+                "unsupported image format, expected .ppm, .png, .pfm, .hdr or .exr".to_string(),
+            )),
+        }
+    }
+}
+
+// Writes `framebuffer` to `path`. Only EXR files can hold `aovs`, as extra
+// layers next to the beauty render.
+pub fn write_image(
+    path: &Path,
+    format: ImageFormat,
+    framebuffer: &Framebuffer,
+    aovs: &[ExrLayer],
+) -> Result<(), io::Error> {
+    if !aovs.is_empty() && !matches!(format, ImageFormat::Exr { .. }) {
+        return Err(invalid_input(
+            "only .exr files can hold AOV layers".to_string(),
+        ));
+    }
+    // File::create truncates, so overwriting a bigger file leaves no trailing data.
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        ImageFormat::Ppm => write_ppm(&mut writer, framebuffer)?,
+        ImageFormat::Png { bit_depth } => write_png(&mut writer, framebuffer, bit_depth)?,
+        ImageFormat::Pfm => write_pfm(&mut writer, framebuffer)?,
+        ImageFormat::Hdr => write_hdr(&mut writer, framebuffer)?,
+        ImageFormat::Exr {
+            pixel_type,
+            compression,
+        } => {
+            let mut layers = vec![ExrLayer {
+                name: "",
+                framebuffer,
+            }];
+            layers.extend(aovs.iter().map(|aov| ExrLayer {
+                name: aov.name,
+                framebuffer: aov.framebuffer,
+            }));
+            exr::write_exr(&mut writer, &layers, pixel_type, compression)?
+        }
+    }
+    writer.flush()
+}
+
+// Encodes a linear value to sRGB and scales it to [0, max].
+fn quantize(x: f64, max: f64) -> u16 {
+    (linear_to_srgb(x) * max).round() as u16
+}
+
+fn write_ppm(writer: &mut impl Write, framebuffer: &Framebuffer) -> Result<(), io::Error> {
+    write!(
+        writer,
+        "P6\n{} {}\n255\n",
+        framebuffer.width, framebuffer.height
+    )?;
+    let mut data = Vec::with_capacity(framebuffer.width * framebuffer.height * 3);
+    for row in 0..framebuffer.height {
+        for col in 0..framebuffer.width {
+            let pixel = framebuffer.pixel(row, col);
+            for x in [pixel.x, pixel.y, pixel.z] {
+                data.push(quantize(x, 255.0) as u8);
+            }
+        }
+    }
+    writer.write_all(&data)
+}
+
+fn write_png_chunk(
+    writer: &mut impl Write,
+    chunk_type: &[u8; 4],
+    data: &[u8],
+) -> Result<(), io::Error> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(chunk_type)?;
+    writer.write_all(data)?;
+    let crc = zlib::crc32_update(zlib::crc32(chunk_type), data);
+    writer.write_all(&crc.to_be_bytes())
+}
+
+fn write_png(
+    writer: &mut impl Write,
+    framebuffer: &Framebuffer,
+    bit_depth: u8,
+) -> Result<(), io::Error> {
+    writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = vec![];
+    header.extend_from_slice(&(framebuffer.width as u32).to_be_bytes());
+    header.extend_from_slice(&(framebuffer.height as u32).to_be_bytes());
+    // Bit depth, truecolor, deflate, adaptive filtering, no interlacing.
+    header.extend_from_slice(&[bit_depth, 2, 0, 0, 0]);
+    write_png_chunk(writer, b"IHDR", &header)?;
+    // Tell viewers the samples are sRGB encoded (perceptual rendering intent).
+    write_png_chunk(writer, b"sRGB", &[0])?;
+
+    let bytes_per_pixel = 3 * bit_depth as usize / 8;
+    let mut scanlines =
+        Vec::with_capacity((framebuffer.width * bytes_per_pixel + 1) * framebuffer.height);
+    for row in 0..framebuffer.height {
+        // Every scanline starts with its filter type, 0 being no filter.
+        scanlines.push(0);
+        for col in 0..framebuffer.width {
+            let pixel = framebuffer.pixel(row, col);
+            for x in [pixel.x, pixel.y, pixel.z] {
+                if bit_depth == 16 {
+                    scanlines.extend_from_slice(&quantize(x, 65535.0).to_be_bytes());
+                } else {
+                    scanlines.push(quantize(x, 255.0) as u8);
+                }
+            }
+        }
+    }
+    write_png_chunk(writer, b"IDAT", &zlib::compress_stored(&scanlines))?;
+    write_png_chunk(writer, b"IEND", &[])
+}
+
+fn write_pfm(writer: &mut impl Write, framebuffer: &Framebuffer) -> Result<(), io::Error> {
+    // A negative scale means the floats are little-endian.
+    write!(
+        writer,
+        "PF\n{} {}\n-1.0\n",
+        framebuffer.width, framebuffer.height
+    )?;
+    let mut data = Vec::with_capacity(framebuffer.width * framebuffer.height * 12);
+    // PFM stores its rows from the bottom of the image up.
+    for row in (0..framebuffer.height).rev() {
+        for col in 0..framebuffer.width {
+            let pixel = framebuffer.pixel(row, col);
+            for x in [pixel.x, pixel.y, pixel.z] {
+                data.extend_from_slice(&(x as f32).to_le_bytes());
+            }
+        }
+    }
+    writer.write_all(&data)
+}
+
+// Converts a color to Radiance's RGBE: three 8-bit mantissas sharing the
+// exponent of the largest component.
+fn to_rgbe(pixel: &Vec3f) -> [u8; 4] {
+    let (r, g, b) = (pixel.x.max(0.0), pixel.y.max(0.0), pixel.z.max(0.0));
+    let max = r.max(g).max(b);
+    if max < 1e-32 || !max.is_finite() {
+        return [0, 0, 0, 0];
+    }
+    // max = mantissa * 2^exponent, with the mantissa in [0.5, 1).
+    let mut exponent = max.log2().floor() as i32 + 1;
+    if max / 2f64.powi(exponent) >= 1.0 {
+        exponent += 1;
+    }
+    let scale = 256.0 / 2f64.powi(exponent);
+    [
+        (r * scale) as u8,
+        (g * scale) as u8,
+        (b * scale) as u8,
+        (exponent + 128).clamp(0, 255) as u8,
+    ]
+}
+
+// Run-length encodes one component of an RGBE scanline. Runs are written as
+// (128 + length, value), anything else as (length, values...).
+fn write_rle(out: &mut Vec<u8>, data: &[u8]) {
+    const MIN_RUN: usize = 4;
+    let mut i = 0;
+    while i < data.len() {
+        // Find the next run long enough to be worth encoding as one.
+        let mut run_start = i;
+        let mut run_length = 0;
+        while run_start < data.len() {
+            run_length = data[run_start..]
+                .iter()
+                .take(127)
+                .take_while(|&&v| v == data[run_start])
+                .count();
+            if run_length >= MIN_RUN {
+                break;
+            }
+            run_start += run_length;
+        }
+        if run_start >= data.len() {
+            run_start = data.len();
+            run_length = 0;
+        }
+
+        while i < run_start {
+            let length = (run_start - i).min(128);
+            out.push(length as u8);
+            out.extend_from_slice(&data[i..i + length]);
+            i += length;
+        }
+        if run_length >= MIN_RUN {
+            out.push(128 + run_length as u8);
+            out.push(data[run_start]);
+            i += run_length;
+        }
+    }
+}
+
+fn write_hdr(writer: &mut impl Write, framebuffer: &Framebuffer) -> Result<(), io::Error> {
+    write!(
+        writer,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        framebuffer.height, framebuffer.width
+    )?;
+    let width = framebuffer.width;
+    let mut data = vec![];
+    for row in 0..framebuffer.height {
+        let pixels: Vec<[u8; 4]> = (0..width)
+            .map(|col| to_rgbe(&framebuffer.pixel(row, col)))
+            .collect();
+        // Run-length encoding is only defined for scanlines of 8 to 32767 pixels.
+        if !(8..=32767).contains(&width) {
+            data.extend(pixels.iter().flatten());
+            continue;
+        }
+        data.extend_from_slice(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8]);
+        for component in 0..4 {
+            let values: Vec<u8> = pixels.iter().map(|p| p[component]).collect();
+            write_rle(&mut data, &values);
+        }
+    }
+    writer.write_all(&data)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Splits the whitespace separated header of a Netpbm-style file into its
+// first `count` fields, skipping comments. Returns the fields and the offset
+// of the data that follows them.
+fn read_header(data: &[u8], count: usize) -> Result<(Vec<String>, usize), io::Error> {
+    let mut fields = vec![];
+    let mut i = 0;
+    while fields.len() < count {
+        match data.get(i) {
+            None => return Err(invalid_data("truncated header".to_string())),
+            Some(b'#') => {
+                while i < data.len() && data[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            Some(c) if c.is_ascii_whitespace() => i += 1,
+            Some(_) => {
+                let start = i;
+                while i < data.len() && !data[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                fields.push(String::from_utf8_lossy(&data[start..i]).into_owned());
+            }
+        }
+    }
+    // A single whitespace character separates the header from the data.
+    Ok((fields, i + 1))
+}
+
+// The number of values in an image of the given dimensions, which a
+// malformed header could make overflow.
+fn value_count(dimensions: &[usize]) -> Result<usize, io::Error> {
+    dimensions
+        .iter()
+        .try_fold(1usize, |count, &n| count.checked_mul(n))
+        .ok_or_else(|| invalid_data("image too large".to_string()))
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str) -> Result<T, io::Error> {
+    field
+        .parse()
+        .map_err(|_| invalid_data(format!("invalid header field `{}`", field)))
+}
+
+// Reads a P3, P6, PNG or PFM image into a linear framebuffer. Integer PPM
+// and PNG values are decoded from sRGB.
+pub fn read_image(path: &Path) -> Result<Framebuffer, io::Error> {
+    let data = fs::read(path)?;
+    let magic = data.get(..2).unwrap_or(&[]);
+    let result = match magic {
+        b"P3" | b"P6" => read_ppm(&data),
+        b"PF" | b"Pf" => read_pfm(&data),
+        b"\x89P" => read_png(&data),
+        _ => Err(invalid_data("not a PPM, PNG or PFM file".to_string())),
+    };
+    result.map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))
+}
+
+fn read_ppm(data: &[u8]) -> Result<Framebuffer, io::Error> {
+    let (fields, offset) = read_header(data, 4)?;
+    let width: usize = parse_field(&fields[1])?;
+    let height: usize = parse_field(&fields[2])?;
+    let max_value: u32 = parse_field(&fields[3])?;
+    if max_value == 0 || max_value > 65535 {
+        return Err(invalid_data(format!("invalid maximum value {}", max_value)));
+    }
+
+    let num_values = value_count(&[width, height, 3])?;
+    let values: Vec<u32> = if fields[0] == "P3" {
+        String::from_utf8_lossy(data.get(offset..).unwrap_or(&[]))
+            .split_ascii_whitespace()
+            .take(num_values)
+            .map(parse_field)
+            .collect::<Result<_, _>>()?
+    } else {
+        let bytes_per_value = if max_value < 256 { 1 } else { 2 };
+        data.get(offset..)
+            .unwrap_or(&[])
+            .chunks_exact(bytes_per_value)
+            .take(num_values)
+            .map(|bytes| bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u32))
+            .collect()
+    };
+    if values.len() < num_values {
+        return Err(invalid_data("truncated pixel data".to_string()));
+    }
+
+    let mut framebuffer = Framebuffer::new(width, height);
+    let decode = |value: u32| srgb_to_linear(value as f64 / max_value as f64);
+    for (i, rgb) in values.chunks_exact(3).enumerate() {
+        framebuffer.set_pixel(
+            i / width,
+            i % width,
+            Vec3f::new(decode(rgb[0]), decode(rgb[1]), decode(rgb[2])),
+        );
+    }
+    Ok(framebuffer)
+}
+
+fn read_pfm(data: &[u8]) -> Result<Framebuffer, io::Error> {
+    let (fields, offset) = read_header(data, 4)?;
+    let channels = if fields[0] == "PF" { 3 } else { 1 };
+    let width: usize = parse_field(&fields[1])?;
+    let height: usize = parse_field(&fields[2])?;
+    let scale: f64 = parse_field(&fields[3])?;
+    let little_endian = scale < 0.0;
+    let num_values = value_count(&[width, height, channels])?;
+
+    let values: Vec<f64> = data
+        .get(offset..)
+        .unwrap_or(&[])
+        .chunks_exact(4)
+        .take(num_values)
+        .map(|bytes| {
+            let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            let value = if little_endian {
+                f32::from_le_bytes(bytes)
+            } else {
+                f32::from_be_bytes(bytes)
+            };
+            value as f64 * scale.abs()
+        })
+        .collect();
+    if values.len() < num_values {
+        return Err(invalid_data("truncated pixel data".to_string()));
+    }
+
+    let mut framebuffer = Framebuffer::new(width, height);
+    for (i, pixel) in values.chunks_exact(channels).enumerate() {
+        let value = match pixel {
+            [r, g, b] => Vec3f::new(*r, *g, *b),
+            _ => Vec3f::new(pixel[0], pixel[0], pixel[0]),
+        };
+        // Rows are stored bottom to top.
+        framebuffer.set_pixel(height - 1 - i / width, i % width, value);
+    }
+    Ok(framebuffer)
+}
+
+// Undoes the filter of every PNG scanline in place. `bytes_per_pixel` is
+// rounded up to 1 for bit depths below 8, as the specification says.
+fn unfilter_png(
+    data: &mut [u8],
+    height: usize,
+    stride: usize,
+    bytes_per_pixel: usize,
+) -> Result<(), io::Error> {
+    for row in 0..height {
+        let (previous, current) = data.split_at_mut(row * (stride + 1));
+        let previous = match row {
+            0 => None,
+            _ => Some(&previous[previous.len() - stride..]),
+        };
+        let (filter, line) = current[..stride + 1].split_first_mut().unwrap();
+        for i in 0..stride {
+            let left = if i >= bytes_per_pixel {
+                line[i - bytes_per_pixel]
+            } else {
+                0
+            };
+            let up = previous.map_or(0, |p| p[i]);
+            let up_left = match previous {
+                Some(p) if i >= bytes_per_pixel => p[i - bytes_per_pixel],
+                _ => 0,
+            };
+            let predictor = match *filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => {
+                    // Paeth: whichever neighbour is closest to left + up - up_left.
+                    let p = left as i16 + up as i16 - up_left as i16;
+                    let (pa, pb, pc) = (
+                        (p - left as i16).abs(),
+                        (p - up as i16).abs(),
+                        (p - up_left as i16).abs(),
+                    );
+                    if pa <= pb && pa <= pc {
+                        left
+                    } else if pb <= pc {
+                        up
+                    } else {
+                        up_left
+                    }
+                }
+                filter => return Err(invalid_data(format!("invalid PNG filter {}", filter))),
+            };
+            line[i] = line[i].wrapping_add(predictor);
+        }
+    }
+    Ok(())
+}
+
+fn read_png(data: &[u8]) -> Result<Framebuffer, io::Error> {
+    if data.get(..8) != Some(b"\x89PNG\r\n\x1a\n") {
+        return Err(invalid_data("invalid PNG signature".to_string()));
+    }
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut compressed = vec![];
+    let mut i = 8;
+    while i + 8 <= data.len() {
+        let length = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize;
+        let chunk_type = &data[i + 4..i + 8];
+        let chunk = data
+            .get(i + 8..i + 8 + length)
+            .ok_or_else(|| invalid_data("truncated PNG chunk".to_string()))?;
+        match chunk_type {
+            b"IHDR" if length == 13 => header = Some(chunk),
+            b"PLTE" => palette = chunk,
+            b"IDAT" => compressed.extend_from_slice(chunk),
+            b"IEND" => break,
+            _ => {}
+        }
+        // Skip the chunk and its CRC.
+        i += 12 + length;
+    }
+
+    let header = header.ok_or_else(|| invalid_data("missing PNG header".to_string()))?;
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (bit_depth, color_type, interlace) = (header[8] as usize, header[9], header[12]);
+    if interlace != 0 {
+        return Err(invalid_data(
+            "interlaced PNGs are not supported".to_string(),
+        ));
+    }
+    let channels = match (color_type, bit_depth) {
+        (0, 1 | 2 | 4 | 8 | 16) | (3, 1 | 2 | 4 | 8) => 1,
+        (4, 8 | 16) => 2,
+        (2, 8 | 16) => 3,
+        (6, 8 | 16) => 4,
+        _ => {
+            return Err(invalid_data(format!(
+                "unsupported PNG color type {} with bit depth {}",
+                color_type, bit_depth
+            )))
+        }
+    };
+
+    let stride = (width * channels * bit_depth).div_ceil(8);
+    let bytes_per_pixel = (channels * bit_depth).div_ceil(8);
+    let mut scanlines = zlib::decompress(&compressed)?;
+    if scanlines.len() < value_count(&[stride + 1, height])? {
+        return Err(invalid_data("truncated PNG image data".to_string()));
+    }
+    unfilter_png(&mut scanlines, height, stride, bytes_per_pixel)?;
+
+    let max_value = ((1u32 << bit_depth) - 1) as f64;
+    let mut framebuffer = Framebuffer::new(width, height);
+    for row in 0..height {
+        let line = &scanlines[row * (stride + 1) + 1..(row + 1) * (stride + 1)];
+        // Samples are packed most significant bits first.
+        let sample = |index: usize| -> u32 {
+            match bit_depth {
+                16 => u16::from_be_bytes([line[2 * index], line[2 * index + 1]]) as u32,
+                8 => line[index] as u32,
+                _ => {
+                    let bit = index * bit_depth;
+                    let shift = 8 - bit_depth - bit % 8;
+                    (line[bit / 8] as u32 >> shift) & ((1 << bit_depth) - 1)
+                }
+            }
+        };
+        for col in 0..width {
+            let decode = |value: u32| srgb_to_linear(value as f64 / max_value);
+            let base = col * channels;
+            let pixel = match color_type {
+                3 => {
+                    let entry = sample(base) as usize * 3;
+                    let rgb = palette.get(entry..entry + 3).ok_or_else(|| {
+                        invalid_data("PNG palette index out of range".to_string())
+                    })?;
+                    let decode = |value: u8| srgb_to_linear(value as f64 / 255.0);
+                    Vec3f::new(decode(rgb[0]), decode(rgb[1]), decode(rgb[2]))
+                }
+                // Gray and gray with alpha. Alpha is ignored.
+                0 | 4 => {
+                    let gray = decode(sample(base));
+                    Vec3f::new(gray, gray, gray)
+                }
+                _ => Vec3f::new(
+                    decode(sample(base)),
+                    decode(sample(base + 1)),
+                    decode(sample(base + 2)),
+                ),
+            };
+            framebuffer.set_pixel(row, col, pixel);
+        }
+    }
+    Ok(framebuffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+    use crate::zlib;
+
+    // Every pixel different, spanning [0, 1] times `scale`.
+    fn gradient(width: usize, height: usize, scale: f64) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(width, height);
+        for row in 0..height {
+            for col in 0..width {
+                let x = col as f64 / (width - 1) as f64;
+                let y = row as f64 / (height - 1) as f64;
+                framebuffer.set_pixel(row, col, &Vec3f::new(x, y, x * y) * scale);
+            }
+        }
+        framebuffer
+    }
+
+    fn round_trip(name: &str, format: ImageFormat, framebuffer: &Framebuffer) -> Framebuffer {
+        let dir = TempDir::new();
+        let path = dir.path(name);
+        write_image(&path, format, framebuffer, &[]).unwrap();
+        let image = read_image(&path).unwrap();
+        assert_eq!(
+            (image.width, image.height),
+            (framebuffer.width, framebuffer.height)
+        );
+        image
+    }
+
+    // Checks that every channel of every pixel matches after going through
+    // `encode`, within `tolerance`.
+    fn assert_close(a: &Framebuffer, b: &Framebuffer, encode: impl Fn(f64) -> f64, tolerance: f64) {
+        for row in 0..a.height {
+            for col in 0..a.width {
+                let (p, q) = (a.pixel(row, col), b.pixel(row, col));
+                for c in 0..3 {
+                    let error = (encode(p[c]) - encode(q[c])).abs();
+                    assert!(
+                        error <= tolerance,
+                        "pixel ({}, {}) channel {}: {} vs {}",
+                        row,
+                        col,
+                        c,
+                        p[c],
+                        q[c]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ppm_round_trip() {
+        let framebuffer = gradient(7, 5, 1.0);
+        let image = round_trip("round-trip.ppm", ImageFormat::Ppm, &framebuffer);
+        assert_close(&framebuffer, &image, linear_to_srgb, 0.5 / 255.0 + 1e-9);
+    }
+
+    #[test]
+    fn png_8_bit_round_trip() {
+        let framebuffer = gradient(7, 5, 1.0);
+        let format = ImageFormat::Png { bit_depth: 8 };
+        let image = round_trip("round-trip-8.png", format, &framebuffer);
+        assert_close(&framebuffer, &image, linear_to_srgb, 0.5 / 255.0 + 1e-9);
+    }
+
+    #[test]
+    fn png_16_bit_round_trip() {
+        let framebuffer = gradient(7, 5, 1.0);
+        let format = ImageFormat::Png { bit_depth: 16 };
+        let image = round_trip("round-trip-16.png", format, &framebuffer);
+        assert_close(&framebuffer, &image, linear_to_srgb, 0.5 / 65535.0 + 1e-9);
+    }
+
+    #[test]
+    fn pfm_round_trip() {
+        // Floats keep values above 1, with the precision of an f32.
+        let framebuffer = gradient(7, 5, 20.0);
+        let image = round_trip("round-trip.pfm", ImageFormat::Pfm, &framebuffer);
+        assert_close(&framebuffer, &image, |x| x, 20.0 * f32::EPSILON as f64);
+    }
+
+    #[test]
+    fn overwriting_a_larger_file_truncates_it() {
+        let dir = TempDir::new();
+        let path = dir.path("overwrite.ppm");
+        let small = gradient(2, 2, 1.0);
+        write_image(&path, ImageFormat::Pfm, &gradient(16, 16, 1.0), &[]).unwrap();
+        write_image(&path, ImageFormat::Ppm, &small, &[]).unwrap();
+        // "P6\n2 2\n255\n" and 3 bytes per pixel.
+        let length = fs::metadata(&path).unwrap().len();
+        assert_eq!(length, 11 + 2 * 2 * 3);
+        let image = read_image(&path).unwrap();
+        assert_close(&small, &image, linear_to_srgb, 0.5 / 255.0 + 1e-9);
+    }
+
+    fn read_error(name: &str, contents: &[u8]) -> String {
+        let dir = TempDir::new();
+        let path = dir.write(name, contents);
+        let error = read_image(&path).map(|_| ()).unwrap_err().to_string();
+        error[path.display().to_string().len() + 2..].to_string()
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_images() {
+        assert_eq!(
+            read_error("short.ppm", b"P6\n2 2\n255\n\x00\x01\x02"),
+            "truncated pixel data"
+        );
+        assert_eq!(
+            read_error("short.pfm", b"PF\n1 1\n-1.0\n\x00\x00"),
+            "truncated pixel data"
+        );
+        // Dimensions whose product doesn't fit in a usize.
+        let huge = format!("{} 3", usize::MAX / 2);
+        let ppm = format!("P6\n{}\n255\n\x00", huge);
+        assert_eq!(read_error("huge.ppm", ppm.as_bytes()), "image too large");
+        let pfm = format!("PF\n{}\n-1.0\n\x00\x00\x00\x00", huge);
+        assert_eq!(read_error("huge.pfm", pfm.as_bytes()), "image too large");
+        // The largest PNG header there is, 16-bit RGBA, with no image data.
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let chunk = |png: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]| {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            png.extend_from_slice(chunk_type);
+            png.extend_from_slice(data);
+            png.extend_from_slice(&[0; 4]);
+        };
+        chunk(
+            &mut png,
+            b"IHDR",
+            &[255, 255, 255, 255, 255, 255, 255, 255, 16, 6, 0, 0, 0],
+        );
+        chunk(&mut png, b"IDAT", &zlib::compress(b""));
+        chunk(&mut png, b"IEND", b"");
+        assert_eq!(read_error("huge.png", &png), "image too large");
+    }
+
+    #[test]
+    fn rgbe_shares_the_exponent_of_the_largest_component() {
+        assert_eq!(to_rgbe(&Vec3f::new(0.0, 0.0, 0.0)), [0, 0, 0, 0]);
+        assert_eq!(to_rgbe(&Vec3f::new(-1.0, 0.0, 1e-40)), [0, 0, 0, 0]);
+        assert_eq!(to_rgbe(&Vec3f::new(f64::INFINITY, 1.0, 1.0)), [0, 0, 0, 0]);
+        // 1 = 0.5 * 2^1, and just below 1 the exponent drops by one.
+        assert_eq!(to_rgbe(&Vec3f::new(1.0, 0.5, 0.25)), [128, 64, 32, 129]);
+        assert_eq!(to_rgbe(&Vec3f::new(0.999, 0.0, 0.0)), [255, 0, 0, 128]);
+        assert_eq!(to_rgbe(&Vec3f::new(2.0, 0.0, 0.0)), [128, 0, 0, 130]);
+        assert_eq!(to_rgbe(&Vec3f::new(1.999, 0.0, 0.0)), [255, 0, 0, 129]);
+        assert_eq!(to_rgbe(&Vec3f::new(0.0, 0.0, 0.5)), [0, 0, 128, 128]);
+        assert_eq!(to_rgbe(&Vec3f::new(0.0, 0.0, 0.499)), [0, 0, 255, 127]);
+        assert_eq!(to_rgbe(&Vec3f::new(1024.0, 1.0, 0.0)), [128, 0, 0, 139]);
+    }
+
+    // Radiance's run-length decoding, for one component of a scanline.
+    fn read_rle(data: &[u8], count: usize) -> (Vec<u8>, usize) {
+        let mut values = vec![];
+        let mut i = 0;
+        while values.len() < count {
+            let length = data[i] as usize;
+            if length > 128 {
+                values.extend(std::iter::repeat_n(data[i + 1], length - 128));
+                i += 2;
+            } else {
+                assert!(length > 0, "empty literal run at byte {}", i);
+                values.extend_from_slice(&data[i + 1..i + 1 + length]);
+                i += 1 + length;
+            }
+        }
+        assert_eq!(
+            values.len(),
+            count,
+            "a run went past the end of the scanline"
+        );
+        (values, i)
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        let mut long_run = vec![7; 300];
+        long_run.extend((0..=255).cycle().take(400));
+        let cases: Vec<Vec<u8>> = vec![
+            vec![1],
+            vec![5, 5, 5],
+            vec![5, 5, 5, 5],
+            vec![1, 2, 3, 3, 3, 3, 3, 4, 4, 5],
+            (0..=255).collect(),
+            long_run,
+        ];
+        for data in cases.iter() {
+            let mut encoded = vec![];
+            write_rle(&mut encoded, data);
+            let (decoded, used) = read_rle(&encoded, data.len());
+            assert_eq!(&decoded, data);
+            assert_eq!(used, encoded.len());
+        }
+
+        // 300 equal values take three runs.
+        let mut encoded = vec![];
+        write_rle(&mut encoded, &[9; 300]);
+        assert_eq!(encoded, [255, 9, 255, 9, 128 + 46, 9]);
+    }
+
+    #[test]
+    fn hdr_scanlines_decode_to_rgbe() {
+        let framebuffer = gradient(16, 3, 4.0);
+        let mut data = vec![];
+        write_hdr(&mut data, &framebuffer).unwrap();
+        let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 3 +X 16\n";
+        assert_eq!(&data[..header.len()], header);
+
+        let mut i = header.len();
+        for row in 0..3 {
+            assert_eq!(data[i..i + 4], [2, 2, 0, 16]);
+            i += 4;
+            let mut components = vec![];
+            for _ in 0..4 {
+                let (values, used) = read_rle(&data[i..], 16);
+                components.push(values);
+                i += used;
+            }
+            for col in 0..16 {
+                let decoded: Vec<u8> = components.iter().map(|values| values[col]).collect();
+                assert_eq!(decoded, to_rgbe(&framebuffer.pixel(row, col)));
+            }
+        }
+        assert_eq!(i, data.len());
+    }
+
+    #[test]
+    fn only_exr_files_take_aovs() {
+        let dir = TempDir::new();
+        let framebuffer = gradient(4, 4, 1.0);
+        let aov = ExrLayer {
+            name: "depth",
+            framebuffer: &framebuffer,
+        };
+        let error = write_image(&dir.path("a.pfm"), ImageFormat::Pfm, &framebuffer, &[aov]);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "only .exr files can hold AOV layers"
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use crate::object::{orthonormal_basis, Hit, Object, World};
+use crate::sampler::Sampler;
+use crate::sampling::cosine_hemisphere;
+use crate::vector::{Ray, Vec3f};
+use std::sync::Arc;
+
+// Paths are cut off after this many bounces: by then they carry almost no
+// light.
+const MAX_DEPTH: usize = 50;
+// Paths longer than this are randomly terminated based on how much light they
+// can still carry (Russian roulette).
+const RUSSIAN_ROULETTE_DEPTH: usize = 3;
+
+// A light transport algorithm. Integrators only read the world, so the same
+// scene can be rendered with any of them.
+pub trait Integrator {
+    // The light arriving at the origin of `ray` from its direction, taking
+    // every random decision with numbers from `sampler`.
+    fn radiance(&self, world: &World, ray: &Ray, sampler: &mut dyn Sampler) -> Vec3f;
+}
+
+pub const INTEGRATOR_NAMES: &str = "path, whitted, ao and direct";
+
+// Picks an integrator by the name it's given on the command line.
+pub fn from_name(name: &str) -> Option<Arc<dyn Integrator + Send + Sync>> {
+    match name {
+        "path" => Some(Arc::new(PathTracer {
+            max_depth: MAX_DEPTH,
+        })),
+        "whitted" => Some(Arc::new(Whitted {
+            max_depth: MAX_DEPTH,
+        })),
+        "ao" => Some(Arc::new(AmbientOcclusion {
+            distance: f64::INFINITY,
+        })),
+        "direct" => Some(Arc::new(DirectLighting {
+            max_depth: MAX_DEPTH,
+        })),
+        _ => None,
+    }
+}
+
+// Weighs a sample taken with density `pdf` against another strategy that
+// could have produced it with density `other_pdf`, using Veach's power
+// heuristic with an exponent of 2.
+fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0.0 {
+        0.0
+    } else {
+        a / (a + b)
+    }
+}
+
+// The light emitted by a surface that `ray` hit. `scatter_pdf` is the density
+// with which the previous bounce picked `ray`, or None for camera rays and
+// specular bounces, which light sampling can't reproduce. When light sampling
+// could have found the same light, its share is weighted by MIS.
+fn emission(
+    world: &World,
+    ray: &Ray,
+    hit: &Hit,
+    object: &Object,
+    scatter_pdf: Option<f64>,
+) -> Vec3f {
+    if !object.material.is_emissive() {
+        return Vec3f::new(0.0, 0.0, 0.0);
+    }
+    let weight = match scatter_pdf {
+        Some(pdf) => {
+            let light_pdf =
+                object.shape.direction_pdf(&ray.origin, &ray.dir) / world.lights.len() as f64;
+            power_heuristic(pdf, light_pdf)
+        }
+        None => 1.0,
+    };
+    object.material.emitted(ray, hit) * weight
+}
+
+// Samples a direction towards the light with the given object index and
+// returns the light it reflects back along `ray` (not yet divided by the
+// density), the density of the direction and the direction itself. Returns
+// None if the light is occluded or the surface doesn't reflect it. Media in
+// the way only dim the light.
+fn light_sample(
+    world: &World,
+    ray: &Ray,
+    hit: &Hit,
+    object: &Object,
+    light_index: usize,
+    sampler: &mut dyn Sampler,
+) -> Option<(Vec3f, f64, Vec3f)> {
+    let light = &world.objects[light_index];
+    let point = ray.interpolate(hit.t);
+    let (direction, pdf) = light.shape.sample_direction(&point, sampler)?;
+    let reflectance = object.material.eval(ray, hit, &direction);
+    if pdf <= 0.0 || reflectance.sq_norm() == 0.0 {
+        return None;
+    }
+
+    let shadow_ray = Ray {
+        origin: point,
+        dir: direction.clone(),
+    };
+    let (light_hit, hit_object) = world.intersect_surface(&shadow_ray)?;
+    if !std::ptr::eq(hit_object, light) {
+        return None;
+    }
+    let transmittance = world.transmittance(&shadow_ray, light_hit.t, sampler);
+    if transmittance > 0.0 {
+        Some((
+            reflectance * light.material.emitted(&shadow_ray, &light_hit) * transmittance,
+            pdf,
+            direction,
+        ))
+    } else {
+        None
+    }
+}
+
+// Estimates the light reaching a hit point directly from one randomly picked
+// light, weighted by MIS against finding it by scattering.
+fn sample_one_light(
+    world: &World,
+    ray: &Ray,
+    hit: &Hit,
+    object: &Object,
+    sampler: &mut dyn Sampler,
+) -> Vec3f {
+    if world.lights.is_empty() {
+        return Vec3f::new(0.0, 0.0, 0.0);
+    }
+    let pick = (sampler.next_1d() * world.lights.len() as f64) as usize;
+    let light_index = world.lights[pick.min(world.lights.len() - 1)];
+    match light_sample(world, ray, hit, object, light_index, sampler) {
+        Some((light, pdf, direction)) => {
+            let light_pdf = pdf / world.lights.len() as f64;
+            let weight = power_heuristic(light_pdf, object.material.pdf(ray, hit, &direction));
+            light * (weight / light_pdf)
+        }
+        None => Vec3f::new(0.0, 0.0, 0.0),
+    }
+}
+
+// Traces a path through the scene, adding up the light that reaches every
+// vertex along the way, weighted by how much of it the previous bounces let
+// through.
+//
+// Light gets to a vertex in two ways: by sampling a point on a light and
+// casting a shadow ray towards it (next-event estimation), and by the
+// scattered ray hitting a light. Both are combined with multiple importance
+// sampling, so that each strategy counts most where it's better: light
+// sampling for small lights, scattering for big lights and glossy surfaces.
+pub struct PathTracer {
+    pub max_depth: usize,
+}
+
+impl Integrator for PathTracer {
+    fn radiance(&self, world: &World, ray: &Ray, sampler: &mut dyn Sampler) -> Vec3f {
+        let mut r = ray.clone();
+        let mut throughput = Vec3f::new(1.0, 1.0, 1.0);
+        let mut radiance = Vec3f::new(0.0, 0.0, 0.0);
+        let mut scatter_pdf: Option<f64> = None;
+        for depth in 0..self.max_depth {
+            let Some((hit, object)) = world.intersect(&r, sampler) else {
+                return radiance + throughput * world.background.color(&r);
+            };
+            radiance = radiance + &throughput * &emission(world, &r, &hit, object, scatter_pdf);
+            radiance = radiance + &throughput * &sample_one_light(world, &r, &hit, object, sampler);
+
+            let Some((atten, scattered)) = object.material.scatter(&r, &hit, sampler) else {
+                return radiance;
+            };
+            let pdf = object.material.pdf(&r, &hit, &scattered.dir);
+            scatter_pdf = if pdf > 0.0 { Some(pdf) } else { None };
+            throughput = throughput * atten;
+            r = scattered;
+
+            if depth >= RUSSIAN_ROULETTE_DEPTH {
+                let survival = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+                if sampler.next_1d() >= survival {
+                    return radiance;
+                }
+                throughput = throughput * (1.0 / survival);
+            }
+        }
+        radiance
+    }
+}
+
+// Direct lighting only: light reaching the first surface straight from the
+// lights and the background, seen directly or through mirrors and glass.
+pub struct DirectLighting {
+    pub max_depth: usize,
+}
+
+impl Integrator for DirectLighting {
+    fn radiance(&self, world: &World, ray: &Ray, sampler: &mut dyn Sampler) -> Vec3f {
+        let mut r = ray.clone();
+        let mut throughput = Vec3f::new(1.0, 1.0, 1.0);
+        let mut radiance = Vec3f::new(0.0, 0.0, 0.0);
+        let mut scatter_pdf: Option<f64> = None;
+        for _ in 0..self.max_depth {
+            let Some((hit, object)) = world.intersect(&r, sampler) else {
+                return radiance + throughput * world.background.color(&r);
+            };
+            radiance = radiance + &throughput * &emission(world, &r, &hit, object, scatter_pdf);
+            // After a non-specular bounce, only the light found by the
+            // scattered ray itself counts.
+            if scatter_pdf.is_some() {
+                return radiance;
+            }
+            radiance = radiance + &throughput * &sample_one_light(world, &r, &hit, object, sampler);
+
+            let Some((atten, scattered)) = object.material.scatter(&r, &hit, sampler) else {
+                return radiance;
+            };
+            let pdf = object.material.pdf(&r, &hit, &scattered.dir);
+            scatter_pdf = if pdf > 0.0 { Some(pdf) } else { None };
+            throughput = throughput * atten;
+            r = scattered;
+        }
+        radiance
+    }
+}
+
+// Whitted-style ray tracing: every surface is lit by a shadow ray to each
+// light, and only perfectly specular surfaces spawn further rays, so there is
+// no indirect diffuse light at all.
+pub struct Whitted {
+    pub max_depth: usize,
+}
+
+impl Whitted {
+    fn trace(&self, world: &World, ray: &Ray, depth: usize, sampler: &mut dyn Sampler) -> Vec3f {
+        let Some((hit, object)) = world.intersect(ray, sampler) else {
+            return world.background.color(ray);
+        };
+        let mut radiance = object.material.emitted(ray, &hit);
+        for &light_index in world.lights.iter() {
+            if let Some((light, pdf, _)) =
+                light_sample(world, ray, &hit, object, light_index, sampler)
+            {
+                radiance = radiance + light * (1.0 / pdf);
+            }
+        }
+
+        if depth + 1 < self.max_depth {
+            if let Some((atten, scattered)) = object.material.scatter(ray, &hit, sampler) {
+                if object.material.pdf(ray, &hit, &scattered.dir) == 0.0 {
+                    radiance = radiance + atten * self.trace(world, &scattered, depth + 1, sampler);
+                }
+            }
+        }
+        radiance
+    }
+}
+
+impl Integrator for Whitted {
+    fn radiance(&self, world: &World, ray: &Ray, sampler: &mut dyn Sampler) -> Vec3f {
+        self.trace(world, ray, 0, sampler)
+    }
+}
+
+// Ambient occlusion: how much of the hemisphere above the first surface is
+// open, ignoring materials and lights. Surfaces further away than `distance`
+// don't occlude anything. Rays that miss the scene are white.
+pub struct AmbientOcclusion {
+    pub distance: f64,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(&self, world: &World, ray: &Ray, sampler: &mut dyn Sampler) -> Vec3f {
+        let Some((hit, _)) = world.intersect(ray, sampler) else {
+            return Vec3f::new(1.0, 1.0, 1.0);
+        };
+        // Cosine weighted, like the light a diffuse surface would receive.
+        let local = cosine_hemisphere(sampler.next_2d());
+        let (u, v) = orthonormal_basis(&hit.normal);
+        let occlusion_ray = Ray {
+            origin: ray.interpolate(hit.t),
+            dir: u * local.x + v * local.y + &hit.normal * local.z,
+        };
+        let occluded = world
+            .intersect(&occlusion_ray, sampler)
+            .is_some_and(|(occluder, _)| occluder.t * occlusion_ray.dir.norm() < self.distance);
+        if occluded {
+            Vec3f::new(0.0, 0.0, 0.0)
+        } else {
+            Vec3f::new(1.0, 1.0, 1.0)
+        }
+    }
+}
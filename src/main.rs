@@ -1,12 +1,36 @@
 #![feature(trait_alias)]
 
+mod bvh;
 mod camera;
 mod color;
+mod csg;
+mod exr;
+mod framebuffer;
+#[cfg(test)]
+mod furnace;
+mod image;
+mod integrator;
+mod medium;
+mod mesh;
+mod microfacet;
+mod obj;
 mod object;
 mod ppm;
+mod primitive;
+mod principled;
+mod quadric;
 mod rasterizer;
+mod sampler;
+mod sampling;
+mod scene;
+mod sdf;
+#[cfg(test)]
+mod temp_dir;
+mod texture;
 mod vector;
-use camera::Camera;
+mod voxel;
+mod zlib;
+use camera::{Aov, Camera};
 use clap::Parser;
 use color::Color;
 use egui::{Align, Align2, Context, Shadow, Visuals};
@@ -19,29 +43,81 @@ use egui_winit::winit::{
     window::{Window, WindowAttributes, WindowId},
 };
 use egui_winit::State;
-use object::*;
+use exr::ExrLayer;
+use framebuffer::Framebuffer;
+use image::ImageFormat;
+use integrator::Integrator;
+use object::World;
 use ppm::PPM;
 use rasterizer::Rasterizer;
+use sampler::Sampler;
 use softbuffer::Surface;
 use std::{
     num::NonZeroU32,
+    path::Path,
     sync::{Arc, Mutex},
 };
-use vector::{Vec3f, ORIGIN};
+use vector::Vec3f;
 
 #[derive(Parser)]
 struct Args {
+    /// How to draw the scene: raytracer or rasterizer.
     #[arg(short, long)]
     method: String,
 
+    /// Number of threads rendering pixels in parallel.
     #[arg(long)]
     num_threads: Option<usize>,
 
+    /// Width of the image in pixels. The height follows from the scene's
+    /// aspect ratio.
     #[arg(short, long)]
     width: Option<usize>,
 
+    /// Number of samples averaged for each pixel.
     #[arg(long)]
     samples_per_pixel: Option<usize>,
+
+    /// Wavefront OBJ files to add to the scene. May be given multiple times.
+    #[arg(long)]
+    obj: Vec<String>,
+
+    /// Scene file to render instead of the built-in random spheres.
+    #[arg(long)]
+    scene: Option<String>,
+
+    /// Render straight to this file instead of opening a window. The format is
+    /// picked from the extension: .ppm, .png, .pfm, .hdr or .exr.
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Bits per channel: 8 (the default) or 16 for PNG output, 16 (half, the
+    /// default) or 32 (float) for EXR output.
+    #[arg(long)]
+    bit_depth: Option<u8>,
+
+    /// Compression of EXR output: zip (the default) or none.
+    #[arg(long)]
+    compression: Option<String>,
+
+    /// The rendering algorithm: path (the default), whitted, ao or direct.
+    #[arg(long)]
+    integrator: Option<String>,
+
+    /// How the random numbers of the samples are generated: sobol (the
+    /// default), halton, stratified or independent.
+    #[arg(long)]
+    sampler: Option<String>,
+
+    /// Extra layers to add to EXR output: normal or depth. May be given
+    /// multiple times.
+    #[arg(long)]
+    aov: Vec<String>,
+
+    /// Seeds every random choice: the same seed renders the same image,
+    /// whatever the number of threads. Defaults to 0.
+    #[arg(long)]
+    seed: Option<u64>,
 }
 
 struct App {
@@ -50,6 +126,8 @@ struct App {
     surface: Option<Surface<Arc<Window>, Arc<Window>>>,
     camera: Camera,
     world: Arc<World>,
+    integrator: Arc<dyn Integrator + Send + Sync>,
+    sampler: Arc<dyn Sampler + Send + Sync>,
     num_threads: Option<usize>,
     egui_ctx: Context,
     egui_state: Option<State>,
@@ -60,6 +138,8 @@ impl App {
         size: (usize, usize),
         camera: Camera,
         world: Arc<World>,
+        integrator: Arc<dyn Integrator + Send + Sync>,
+        sampler: Arc<dyn Sampler + Send + Sync>,
         num_threads: Option<usize>,
     ) -> Self {
         let visuals = Visuals {
@@ -75,6 +155,8 @@ impl App {
             surface: None,
             camera,
             world,
+            integrator,
+            sampler,
             num_threads,
             egui_ctx: egui_context,
             egui_state: None,
@@ -145,8 +227,13 @@ impl ApplicationHandler for App {
 
                 let buffer = Arc::new(Mutex::new(surface.buffer_mut().unwrap()));
 
-                self.camera
-                    .write_buffer(self.world.clone(), self.num_threads, buffer.clone());
+                self.camera.write_buffer(
+                    self.world.clone(),
+                    self.integrator.clone(),
+                    self.sampler.clone(),
+                    self.num_threads,
+                    buffer.clone(),
+                );
 
                 let raw_input = egui_state.take_egui_input(&window);
                 println!("{:?}", raw_input);
@@ -232,108 +319,156 @@ fn rasterize() {
     ppm.write_to_file(String::from("rasterized.ppm")).unwrap();
 }
 
+// Renders the whole image without an event loop and writes it to `output`.
+#[allow(clippy::too_many_arguments)]
+fn render_to_file(
+    camera: &Camera,
+    world: Arc<World>,
+    integrator: Arc<dyn Integrator + Send + Sync>,
+    sampler: Arc<dyn Sampler + Send + Sync>,
+    num_threads: Option<usize>,
+    output: &Path,
+    format: ImageFormat,
+    aovs: &[Aov],
+) -> Result<(), std::io::Error> {
+    if !aovs.is_empty() && !matches!(format, ImageFormat::Exr { .. }) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "only .exr files can hold AOV layers",
+        ));
+    }
+    let mut framebuffer = Framebuffer::new(camera.width, camera.height);
+    let aov_framebuffers: Vec<Framebuffer> = aovs
+        .iter()
+        .map(|&aov| {
+            let mut framebuffer = Framebuffer::new(camera.width, camera.height);
+            camera.write_aov(&world, aov, &mut framebuffer);
+            framebuffer
+        })
+        .collect();
+    camera.write(world, integrator, sampler, num_threads, &mut framebuffer);
+    let layers: Vec<ExrLayer> = aovs
+        .iter()
+        .zip(aov_framebuffers.iter())
+        .map(|(aov, framebuffer)| ExrLayer {
+            name: aov.name(),
+            framebuffer,
+        })
+        .collect();
+    image::write_image(output, format, &framebuffer, &layers)
+}
+
 fn raytrace(args: Args) {
-    let aspect_ratio = 16.0 / 9.0;
-    let img_width = args.width.unwrap_or(1920);
-    let img_height = (img_width as f64 / aspect_ratio) as usize;
-    let origin = Vec3f::new(13.0, 2.0, 3.0);
-    let lookat = ORIGIN;
-    let camera = Camera::new(
-        (img_height as f64 * aspect_ratio) as usize,
-        img_height,
-        origin,
-        lookat,
-        Vec3f::new(0.0, -1.0, 0.0),
-        40.0,
-        args.samples_per_pixel.unwrap_or(100),
-    );
+    let integrator_name = args.integrator.as_deref().unwrap_or("path");
+    let Some(integrator) = integrator::from_name(integrator_name) else {
+        eprintln!(
+            "Unknown integrator `{}`. Available options are {}.",
+            integrator_name,
+            integrator::INTEGRATOR_NAMES
+        );
+        std::process::exit(1);
+    };
+
+    let seed = args.seed.unwrap_or(0);
+    let mut scene = match &args.scene {
+        Some(path) => match scene::load_scene(Path::new(path), seed) {
+            Ok(scene) => scene,
+            Err(e) => {
+                eprintln!("Failed to load scene: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => scene::random_spheres(seed),
+    };
+
+    // Command line settings take precedence over the ones in the scene.
+    if let Some(width) = args.width {
+        let aspect_ratio = scene.render.width as f64 / scene.render.height as f64;
+        scene.render.width = width;
+        scene.render.height = (width as f64 / aspect_ratio) as usize;
+    }
+    if let Some(samples_per_pixel) = args.samples_per_pixel {
+        scene.render.samples_per_pixel = samples_per_pixel;
+    }
+    if args.num_threads.is_some() {
+        scene.render.num_threads = args.num_threads;
+    }
 
-    let mut objects: Vec<Object> = vec![];
-    objects.push(Object {
-        shape: Arc::new(Sphere {
-            center: Vec3f::new(0.0, -1000.0, -0.0),
-            radius: 1000.0,
-        }),
-        material: Arc::new(DiffuseMaterial {
-            color: Color::new(125, 125, 125),
-        }),
-    });
-
-    let small_sphere_radius = 0.2;
-
-    for i in -11..11 {
-        for j in -11..11 {
-            let material_seed = rand::random::<f64>();
-            let center = Vec3f::new(
-                i as f64 + 0.9 * rand::random::<f64>(),
-                small_sphere_radius,
-                j as f64 + 0.9 * rand::random::<f64>(),
-            );
-
-            if (&center - &Vec3f::new(4.0, 0.2, 0.0)).norm() > 0.9 {
-                let sphere = Arc::new(Sphere {
-                    center,
-                    radius: small_sphere_radius,
-                });
-                if material_seed < 0.8 {
-                    let random_color = Color::random();
-                    objects.push(Object {
-                        shape: sphere,
-                        material: Arc::new(DiffuseMaterial {
-                            color: random_color,
-                        }),
-                    })
-                } else if material_seed < 0.9 {
-                    let fuzz = rand::random::<f64>();
-                    objects.push(Object {
-                        shape: sphere,
-                        material: Arc::new(MetalMaterial {
-                            attenuation: Color::random(),
-                            fuzz,
-                        }),
-                    })
-                } else {
-                    objects.push(Object {
-                        shape: sphere,
-                        material: Arc::new(DielectricMaterial { eta_ratio: 0.5 }),
-                    })
-                }
+    for path in args.obj.iter() {
+        match obj::load_obj(Path::new(path)) {
+            Ok(meshes) => scene.objects.extend(meshes),
+            Err(e) => {
+                eprintln!("Failed to load {}: {}", path, e);
+                std::process::exit(1);
             }
         }
     }
 
-    objects.push(Object {
-        shape: Arc::new(Sphere {
-            center: Vec3f::new(-4.0, 1.0, 0.0),
-            radius: 1.0,
-        }),
-        material: Arc::new(DiffuseMaterial {
-            color: Color::new(100, 50, 25),
-        }),
-    });
-
-    objects.push(Object {
-        shape: Arc::new(Sphere {
-            center: Vec3f::new(0.0, 1.0, 0.0),
-            radius: 1.0,
-        }),
-        material: Arc::new(DielectricMaterial { eta_ratio: 0.5 }),
-    });
-
-    objects.push(Object {
-        shape: Arc::new(Sphere {
-            center: Vec3f::new(4.0, 1.0, 0.0),
-            radius: 1.0,
-        }),
-        material: Arc::new(MetalMaterial {
-            attenuation: Color::new(120, 120, 120),
-            fuzz: 0.0,
-        }),
-    });
-    let world = Arc::new(World { objects });
+    let sampler_name = args.sampler.as_deref().unwrap_or("sobol");
+    let Some(sampler) = sampler::from_name(sampler_name, scene.render.samples_per_pixel, seed)
+    else {
+        eprintln!(
+            "Unknown sampler `{}`. Available options are {}.",
+            sampler_name,
+            sampler::SAMPLER_NAMES
+        );
+        std::process::exit(1);
+    };
+
+    let mut aovs = vec![];
+    for name in args.aov.iter() {
+        match Aov::from_name(name) {
+            Some(aov) => aovs.push(aov),
+            None => {
+                eprintln!(
+                    "Unknown AOV `{}`. Available options are {}.",
+                    name,
+                    camera::AOV_NAMES
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+    if !aovs.is_empty() && args.output.is_none() {
+        eprintln!("AOVs can only be written to an .exr file given with --output.");
+        std::process::exit(1);
+    }
+
+    let mut camera = scene.camera();
+    let size = (scene.render.width, scene.render.height);
+    let num_threads = scene.render.num_threads;
+    let mut world = World::new(scene.objects);
+    world.background = scene.background;
+    if scene.view.autofocus {
+        camera.autofocus(&world);
+    }
+    let world = Arc::new(world);
+
+    if let Some(output) = args.output {
+        let output = Path::new(&output);
+        // Bail out before spending minutes rendering something we can't save.
+        let result = ImageFormat::from_path(output, args.bit_depth, args.compression.as_deref())
+            .and_then(|format| {
+                render_to_file(
+                    &camera,
+                    world,
+                    integrator,
+                    sampler,
+                    num_threads,
+                    output,
+                    format,
+                    &aovs,
+                )
+            });
+        if let Err(e) = result {
+            eprintln!("Failed to write {}: {}", output.display(), e);
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let event_loop: EventLoop<()> = EventLoop::new().unwrap();
-    let mut app = App::new((img_width, img_height), camera, world, args.num_threads);
+    let mut app = App::new(size, camera, world, integrator, sampler, num_threads);
     event_loop.run_app(&mut app).unwrap();
 }
 
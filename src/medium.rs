@@ -0,0 +1,387 @@
+use crate::bvh::Aabb;
+use crate::color::blackbody;
+use crate::object::{
+    orthonormal_basis, texture_at, Hit, Material, Object, Shape, MIN_HIT_DISTANCE,
+};
+use crate::primitive::Cuboid;
+use crate::sampler::{Rng, Sampler};
+use crate::texture::Texture;
+use crate::vector::{Ray, Transform, Vec3f};
+use crate::voxel::VoxelGrid;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// A volume of smoke, fog or any other homogeneous participating medium,
+// filling a solid `boundary`. A ray travelling through it is scattered after
+// an exponentially distributed distance, with a mean of 1 / `density`; the
+// phase function of the object's material (see `VolumeMaterial`) then decides
+// where it goes. Rays that make it through the whole volume pass it by.
+//
+// The boundary must enclose a volume, i.e. report its intervals like spheres,
+// boxes and CSG shapes do.
+pub struct ConstantMedium {
+    pub boundary: Arc<dyn Shape + Send + Sync>,
+    pub density: f64,
+}
+
+impl Shape for ConstantMedium {
+    fn intersect(&self, _ray: &Ray) -> Option<Hit> {
+        None
+    }
+
+    fn intersect_medium(&self, ray: &Ray, rng: &mut Rng) -> Option<Hit> {
+        let length = ray.dir.norm();
+        // The distance to travel before scattering, carried over from one
+        // stretch of the medium to the next, as exponential distributions
+        // are memoryless.
+        let mut remaining = -(1.0 - rng.next_f64()).ln() / self.density;
+        for interval in self.boundary.intervals(ray) {
+            let start = interval.enter.t.max(MIN_HIT_DISTANCE);
+            if interval.exit.t <= start {
+                continue;
+            }
+            let inside = (interval.exit.t - start) * length;
+            if remaining < inside {
+                let t = start + remaining / length;
+                // Media have no surface: any normal will do, as long as it
+                // faces the ray.
+                let normal = &ray.dir * (-1.0 / length);
+                return Some(Hit::new(ray, t, normal, (0.0, 0.0)));
+            }
+            remaining -= inside;
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.boundary.bounding_box()
+    }
+
+    fn is_medium(&self) -> bool {
+        true
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: f64, _rng: &mut Rng) -> f64 {
+        let inside: f64 = self
+            .boundary
+            .intervals(ray)
+            .iter()
+            .map(|interval| {
+                let start = interval.enter.t.max(MIN_HIT_DISTANCE);
+                (interval.exit.t.min(t_max) - start).max(0.0)
+            })
+            .sum();
+        (-self.density * inside * ray.dir.norm()).exp()
+    }
+}
+
+// Where `ray` is inside the unit cube, as the range of distances along it,
+// starting no closer than MIN_HIT_DISTANCE.
+fn unit_cube_span(ray: &Ray) -> Option<(f64, f64)> {
+    let (mut enter, mut exit) = (MIN_HIT_DISTANCE, f64::INFINITY);
+    for axis in 0..3 {
+        let (o, d) = (ray.origin[axis], ray.dir[axis]);
+        if d == 0.0 {
+            if !(0.0..=1.0).contains(&o) {
+                return None;
+            }
+            continue;
+        }
+        let (near, far) = ((0.0 - o) / d, (1.0 - o) / d);
+        enter = enter.max(near.min(far));
+        exit = exit.min(near.max(far));
+    }
+    if enter < exit {
+        Some((enter, exit))
+    } else {
+        None
+    }
+}
+
+// A medium whose density varies through space, read from a voxel grid. The
+// grid fills the unit cube, to be moved into place with a transform, and its
+// densities are multiplied by `density`.
+//
+// Rays are tracked through it against the highest density of the whole grid,
+// as if the medium were that dense everywhere, with the extra, fictitious,
+// collisions leaving the ray on its way: delta tracking to find where rays
+// scatter, and ratio tracking to estimate how much light gets through. Either
+// is unbiased; both slow down in grids that are mostly empty.
+pub struct GridMedium {
+    pub grid: Arc<VoxelGrid>,
+    pub density: f64,
+}
+
+impl GridMedium {
+    // Steps along `ray` through the grid, from one tentative collision to the
+    // next, at distances drawn from the highest density. Calls `collide` with
+    // the probability that each is a real collision, and stops when it
+    // returns false or when the ray leaves the grid or passes `t_max`.
+    // Returns the distance of the last collision, if it stopped at one.
+    fn track(
+        &self,
+        ray: &Ray,
+        t_max: f64,
+        rng: &mut Rng,
+        mut collide: impl FnMut(f64, &mut Rng) -> bool,
+    ) -> Option<f64> {
+        let majorant = self.density * self.grid.max_density();
+        let (mut t, exit) = unit_cube_span(ray)?;
+        let exit = exit.min(t_max);
+        if majorant <= 0.0 {
+            return None;
+        }
+        let step = 1.0 / (majorant * ray.dir.norm());
+        loop {
+            t -= (1.0 - rng.next_f64()).ln() * step;
+            if t >= exit {
+                return None;
+            }
+            let density = self.density * self.grid.density_at(&ray.interpolate(t));
+            if !collide(density / majorant, rng) {
+                return Some(t);
+            }
+        }
+    }
+}
+
+impl Shape for GridMedium {
+    fn intersect(&self, _ray: &Ray) -> Option<Hit> {
+        None
+    }
+
+    fn intersect_medium(&self, ray: &Ray, rng: &mut Rng) -> Option<Hit> {
+        let t = self.track(ray, f64::INFINITY, rng, |probability, rng| {
+            rng.next_f64() >= probability
+        })?;
+        let normal = &ray.dir * (-1.0 / ray.dir.norm());
+        Some(Hit::new(ray, t, normal, (0.0, 0.0)))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::new(Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(1.0, 1.0, 1.0))
+    }
+
+    fn is_medium(&self) -> bool {
+        true
+    }
+
+    // Every tentative collision lets through the fraction of the light that
+    // a fictitious collision would. Once little light is left, Russian
+    // roulette ends the walk early without biasing the estimate.
+    fn transmittance(&self, ray: &Ray, t_max: f64, rng: &mut Rng) -> f64 {
+        let mut transmittance = 1.0;
+        let stopped = self.track(ray, t_max, rng, |probability, rng| {
+            transmittance *= 1.0 - probability;
+            if transmittance < 0.1 {
+                if rng.next_f64() < 0.5 {
+                    return false;
+                }
+                transmittance *= 2.0;
+            }
+            true
+        });
+        if stopped.is_some() {
+            0.0
+        } else {
+            transmittance
+        }
+    }
+}
+
+// Fog filling the bounding box of `objects`, so that rays leaving the scene
+// still reach the background. Objects without bounds, such as planes, are
+// left out of the box. Returns None if there's nothing to put fog around.
+pub fn fog(
+    objects: &[Object],
+    density: f64,
+    material: Arc<dyn Material + Send + Sync>,
+) -> Option<Object> {
+    let bounds = objects
+        .iter()
+        .map(|object| object.shape.bounding_box())
+        .filter(|bounds| bounds.is_finite())
+        .fold(Aabb::empty(), |all, bounds| all.union(&bounds));
+    if !bounds.is_finite() {
+        return None;
+    }
+    Some(Object {
+        shape: Arc::new(ConstantMedium {
+            boundary: Arc::new(Cuboid::new(bounds.min, bounds.max)),
+            density,
+        }),
+        material,
+    })
+}
+
+// The Henyey-Greenstein phase function: the density of scattering by an angle
+// whose cosine is `cos_theta`. Positive anisotropy favors forward scattering
+// (as in fog and clouds), negative backward scattering, and 0 is isotropic.
+fn henyey_greenstein(cos_theta: f64, anisotropy: f64) -> f64 {
+    let g = anisotropy;
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+}
+
+// What happens to light scattered inside a medium: a fraction `albedo` of it
+// is scattered (the rest is absorbed) in a direction picked by the
+// Henyey-Greenstein phase function. Applies to any medium, `ConstantMedium`s
+// and `GridMedium`s alike.
+pub struct VolumeMaterial {
+    pub albedo: Arc<dyn Texture + Send + Sync>,
+    // Between -1 and 1, 0 for isotropic scattering.
+    pub anisotropy: f64,
+}
+
+impl Material for VolumeMaterial {
+    fn scatter(&self, ray: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Option<(Vec3f, Ray)> {
+        let g = self.anisotropy;
+        let (xi, u) = sampler.next_2d();
+        // Inverting the cumulative distribution of the angle.
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * xi
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * xi);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u;
+        let w = ray.dir.normalize();
+        let (u, v) = orthonormal_basis(&w);
+        let direction = u * (sin_theta * phi.cos()) + v * (sin_theta * phi.sin()) + w * cos_theta;
+        Some((
+            texture_at(self.albedo.as_ref(), ray, hit),
+            Ray {
+                origin: ray.interpolate(hit.t),
+                dir: direction,
+            },
+        ))
+    }
+
+    // Phase functions take the place of the BSDF times the cosine.
+    fn eval(&self, ray: &Ray, hit: &Hit, direction: &Vec3f) -> Vec3f {
+        texture_at(self.albedo.as_ref(), ray, hit) * self.pdf(ray, hit, direction)
+    }
+
+    fn pdf(&self, ray: &Ray, _hit: &Hit, direction: &Vec3f) -> f64 {
+        let cos_theta = ray.dir.dot_ref(direction) / (ray.dir.norm() * direction.norm());
+        henyey_greenstein(cos_theta, self.anisotropy)
+    }
+}
+
+// Fire: a material for grid media whose voxels have temperatures, which
+// emits the light of a black body at the temperature of every collision, on
+// top of scattering like `material` does. `to_local` maps world space to the
+// unit cube of the grid. Hotter regions are much brighter (see `blackbody`);
+// `intensity` scales them all.
+pub struct BlackbodyEmission {
+    pub material: Arc<dyn Material + Send + Sync>,
+    pub grid: Arc<VoxelGrid>,
+    pub to_local: Transform,
+    pub intensity: f64,
+}
+
+impl Material for BlackbodyEmission {
+    fn scatter(&self, ray: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Option<(Vec3f, Ray)> {
+        self.material.scatter(ray, hit, sampler)
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, direction: &Vec3f) -> Vec3f {
+        self.material.eval(ray, hit, direction)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &Hit, direction: &Vec3f) -> f64 {
+        self.material.pdf(ray, hit, direction)
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &Hit) -> Vec3f {
+        let point = self.to_local.apply_point(&ray.interpolate(hit.t));
+        blackbody(self.grid.temperature_at(&point)) * self.intensity
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::furnace::{constant, Furnace};
+    use crate::object::{Sphere, World};
+    use crate::sampler::IndependentSampler;
+    use crate::texture::ConstantTexture;
+
+    fn white_volume() -> Arc<dyn Material + Send + Sync> {
+        Arc::new(VolumeMaterial {
+            albedo: constant(1.0),
+            anisotropy: 0.5,
+        })
+    }
+
+    #[test]
+    fn white_volume_conserves_energy() {
+        Furnace {
+            albedo: Some(1.0),
+            has_bsdf: true,
+            two_sided: false,
+        }
+        .check(white_volume().as_ref());
+    }
+
+    #[test]
+    fn blackbody_emission_scatters_like_its_material() {
+        let material = BlackbodyEmission {
+            material: white_volume(),
+            grid: Arc::new(VoxelGrid::new([1, 1, 1], vec![1.0], Some(vec![1500.0]))),
+            to_local: Transform::identity(),
+            intensity: 1.0,
+        };
+        Furnace {
+            albedo: Some(1.0),
+            has_bsdf: true,
+            two_sided: false,
+        }
+        .check(&material);
+    }
+
+    fn fog_object(density: f64) -> Object {
+        Object {
+            shape: Arc::new(ConstantMedium {
+                boundary: Arc::new(Sphere {
+                    center: Vec3f::new(0.0, 0.0, 0.0),
+                    radius: 1000.0,
+                }),
+                density,
+            }),
+            material: Arc::new(VolumeMaterial {
+                albedo: Arc::new(ConstantTexture {
+                    color: Vec3f::new(1.0, 1.0, 1.0),
+                }),
+                anisotropy: 0.0,
+            }),
+        }
+    }
+
+    // Two media filling the same space must scatter rays as often as a
+    // single one with both densities added up, which only holds if they
+    // draw their distances independently of each other.
+    #[test]
+    fn overlapping_media_scatter_independently() {
+        let world = World::new(vec![fog_object(1.0), fog_object(1.0)]);
+        let mut sampler = IndependentSampler::new(1);
+        let ray = Ray {
+            origin: Vec3f::new(0.0, 0.0, 0.0),
+            dir: Vec3f::new(1.0, 0.0, 0.0),
+        };
+        let count = 20000;
+        let mut total = 0.0;
+        for index in 0..count {
+            sampler.start_pixel_sample((0, 0), index);
+            let (hit, _) = world.intersect(&ray, &mut sampler).unwrap();
+            total += hit.t;
+        }
+        let mean = total / count as f64;
+        assert!((mean - 0.5).abs() < 0.02, "mean distance {}", mean);
+    }
+}
@@ -0,0 +1,349 @@
+use crate::bvh::{Aabb, Bvh};
+use crate::object::{Hit, Shape, MIN_HIT_DISTANCE};
+use crate::sampler::Sampler;
+use crate::sampling::{triangle_pdf, uniform_triangle};
+use crate::vector::{Ray, Vec3f};
+
+pub struct Triangle {
+    pub v0: Vec3f,
+    pub v1: Vec3f,
+    pub v2: Vec3f,
+}
+
+impl Triangle {
+    // Möller–Trumbore intersection. Solves
+    // o + t * d = (1 - b1 - b2) * v0 + b1 * v1 + b2 * v2
+    // for the distance t and the barycentric coordinates (b1, b2) using
+    // Cramer's rule, without ever computing the plane of the triangle.
+    pub fn intersect_barycentric(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        let e1 = &self.v1 - &self.v0;
+        let e2 = &self.v2 - &self.v0;
+        let p = ray.dir.cross(&e2);
+        let det = e1.dot_ref(&p);
+        // Compare the determinant against the size of its inputs, so that
+        // the parallel-ray test doesn't depend on the scale of the scene.
+        if det.abs() <= f64::EPSILON * e1.norm() * e2.norm() * ray.dir.norm() {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let s = &ray.origin - &self.v0;
+        let b1 = s.dot_ref(&p) * inv_det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let q = s.cross(&e1);
+        let b2 = ray.dir.dot_ref(&q) * inv_det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = e2.dot_ref(&q) * inv_det;
+        Some((t, b1, b2))
+    }
+
+    // The normal given by the winding order: counter-clockwise vertices face
+    // towards the viewer.
+    pub fn geometric_normal(&self) -> Vec3f {
+        (&self.v1 - &self.v0)
+            .cross(&(&self.v2 - &self.v0))
+            .normalize()
+    }
+}
+
+impl Shape for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.intersect_barycentric(ray)
+            .filter(|&(t, _, _)| t >= MIN_HIT_DISTANCE)
+            .map(|(t, b1, b2)| {
+                Hit::new(ray, t, self.geometric_normal(), (b1, b2))
+                    .with_tangent(&self.v1 - &self.v0)
+            })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::new(
+            self.v0.min(&self.v1).min(&self.v2),
+            self.v0.max(&self.v1).max(&self.v2),
+        )
+    }
+
+    // Picks a uniformly distributed point on the triangle.
+    fn sample_direction(&self, origin: &Vec3f, sampler: &mut dyn Sampler) -> Option<(Vec3f, f64)> {
+        let (b1, b2) = uniform_triangle(sampler.next_2d());
+        let point = &self.v0 * (1.0 - b1 - b2) + &self.v1 * b1 + &self.v2 * b2;
+        let direction = (&point - origin).normalize();
+        let pdf = self.direction_pdf(origin, &direction);
+        if pdf > 0.0 {
+            Some((direction, pdf))
+        } else {
+            None
+        }
+    }
+
+    // Converts the uniform density over the area of the triangle to one over
+    // solid angle, which grows with distance and at grazing angles.
+    fn direction_pdf(&self, origin: &Vec3f, direction: &Vec3f) -> f64 {
+        let ray = Ray {
+            origin: origin.clone(),
+            dir: direction.clone(),
+        };
+        let Some((t, _, _)) = self.intersect_barycentric(&ray) else {
+            return 0.0;
+        };
+        let cross = (&self.v1 - &self.v0).cross(&(&self.v2 - &self.v0));
+        let area = 0.5 * cross.norm();
+        let distance = t * direction.norm();
+        let cos_theta = (cross.dot_ref(direction) / (cross.norm() * direction.norm())).abs();
+        if t <= 0.0 || area * cos_theta == 0.0 {
+            return 0.0;
+        }
+        triangle_pdf(area) * distance * distance / cos_theta
+    }
+}
+
+// An indexed triangle mesh. Every face is a triple of indices into the vertex
+// arrays; `normals` and `uvs`, when present, have one entry per position.
+pub struct TriangleMesh {
+    pub positions: Vec<Vec3f>,
+    pub normals: Option<Vec<Vec3f>>,
+    pub uvs: Option<Vec<(f64, f64)>>,
+    pub indices: Vec<[usize; 3]>,
+    bvh: Bvh,
+}
+
+impl TriangleMesh {
+    pub fn new(
+        positions: Vec<Vec3f>,
+        normals: Option<Vec<Vec3f>>,
+        uvs: Option<Vec<(f64, f64)>>,
+        indices: Vec<[usize; 3]>,
+    ) -> Self {
+        let mut mesh = TriangleMesh {
+            positions,
+            normals,
+            uvs,
+            indices,
+            bvh: Bvh::new(vec![]),
+        };
+        mesh.bvh = Bvh::new(
+            (0..mesh.indices.len())
+                .map(|face| mesh.triangle(face).bounding_box())
+                .collect(),
+        );
+        mesh
+    }
+
+    pub fn triangle(&self, face: usize) -> Triangle {
+        let [i0, i1, i2] = self.indices[face];
+        Triangle {
+            v0: self.positions[i0].clone(),
+            v1: self.positions[i1].clone(),
+            v2: self.positions[i2].clone(),
+        }
+    }
+
+    // Interpolates the vertex normals of a face, falling back to the
+    // geometric normal for meshes without them.
+    fn shading_normal(&self, face: usize, b1: f64, b2: f64) -> Vec3f {
+        match &self.normals {
+            Some(normals) => {
+                let [i0, i1, i2] = self.indices[face];
+                (&normals[i0] * (1.0 - b1 - b2) + &normals[i1] * b1 + &normals[i2] * b2).normalize()
+            }
+            None => self.triangle(face).geometric_normal(),
+        }
+    }
+
+    // The direction in which u grows on a face: along the texture's u axis
+    // for meshes with texture coordinates, along the first edge otherwise.
+    fn tangent(&self, face: usize) -> Vec3f {
+        let [i0, i1, i2] = self.indices[face];
+        let e1 = &self.positions[i1] - &self.positions[i0];
+        let Some(uvs) = &self.uvs else {
+            return e1;
+        };
+        let e2 = &self.positions[i2] - &self.positions[i0];
+        let (du1, dv1) = (uvs[i1].0 - uvs[i0].0, uvs[i1].1 - uvs[i0].1);
+        let (du2, dv2) = (uvs[i2].0 - uvs[i0].0, uvs[i2].1 - uvs[i0].1);
+        // Solving e1 = du1 * dpdu + dv1 * dpdv and e2 = du2 * dpdu + dv2 * dpdv.
+        let det = du1 * dv2 - du2 * dv1;
+        if det == 0.0 {
+            return e1;
+        }
+        (e1 * dv2 - e2 * dv1) * (1.0 / det)
+    }
+
+    // Interpolated texture coordinates at a point of a face.
+    pub fn uv(&self, face: usize, b1: f64, b2: f64) -> Option<(f64, f64)> {
+        self.uvs.as_ref().map(|uvs| {
+            let [i0, i1, i2] = self.indices[face];
+            let b0 = 1.0 - b1 - b2;
+            (
+                b0 * uvs[i0].0 + b1 * uvs[i1].0 + b2 * uvs[i2].0,
+                b0 * uvs[i0].1 + b1 * uvs[i1].1 + b2 * uvs[i2].1,
+            )
+        })
+    }
+}
+
+impl Shape for TriangleMesh {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.bvh
+            .intersect(ray, |face| {
+                self.triangle(face)
+                    .intersect_barycentric(ray)
+                    .map(|(t, b1, b2)| (t, (b1, b2)))
+            })
+            .map(|(face, t, (b1, b2))| {
+                // Which side was hit is decided by the flat face; interpolated
+                // normals just follow it.
+                let uv = self.uv(face, b1, b2).unwrap_or((b1, b2));
+                let hit = Hit::new(ray, t, self.triangle(face).geometric_normal(), uv)
+                    .with_tangent(self.tangent(face));
+                let normal = self.shading_normal(face, b1, b2);
+                Hit {
+                    normal: if hit.front_face {
+                        normal
+                    } else {
+                        normal * -1.0
+                    },
+                    ..hit
+                }
+            })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.positions
+            .iter()
+            .fold(Aabb::empty(), |acc, p| acc.grow(p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle() -> Triangle {
+        Triangle {
+            v0: Vec3f::new(0.0, 0.0, 0.0),
+            v1: Vec3f::new(2.0, 0.0, 0.0),
+            v2: Vec3f::new(0.0, 2.0, 0.0),
+        }
+    }
+
+    // A ray straight down the z axis onto the point (x, y) of the plane of
+    // the triangle.
+    fn ray_at(x: f64, y: f64, z: f64) -> Ray {
+        Ray {
+            origin: Vec3f::new(x, y, z),
+            dir: Vec3f::new(0.0, 0.0, -1.0),
+        }
+    }
+
+    #[test]
+    fn hits_inside_the_triangle() {
+        let (t, b1, b2) = triangle()
+            .intersect_barycentric(&ray_at(0.5, 1.0, 3.0))
+            .unwrap();
+        assert!((t - 3.0).abs() < 1e-12);
+        assert!((b1 - 0.25).abs() < 1e-12);
+        assert!((b2 - 0.5).abs() < 1e-12);
+
+        let hit = triangle().intersect(&ray_at(0.5, 1.0, 3.0)).unwrap();
+        assert!(hit.front_face);
+        assert!((&hit.normal - &Vec3f::new(0.0, 0.0, 1.0)).norm() < 1e-12);
+        assert_eq!(hit.uv, (b1, b2));
+
+        // From below, the back face is hit and the normal faces the ray.
+        let ray = Ray {
+            origin: Vec3f::new(0.5, 1.0, -3.0),
+            dir: Vec3f::new(0.0, 0.0, 1.0),
+        };
+        let hit = triangle().intersect(&ray).unwrap();
+        assert!(!hit.front_face);
+        assert!((&hit.normal - &Vec3f::new(0.0, 0.0, -1.0)).norm() < 1e-12);
+    }
+
+    #[test]
+    fn hits_edges_and_corners() {
+        // Along each of the three edges, and on each corner.
+        for &(x, y, b1, b2) in [
+            (1.0, 0.0, 0.5, 0.0),
+            (0.0, 1.0, 0.0, 0.5),
+            (1.0, 1.0, 0.5, 0.5),
+            (0.0, 0.0, 0.0, 0.0),
+            (2.0, 0.0, 1.0, 0.0),
+            (0.0, 2.0, 0.0, 1.0),
+        ]
+        .iter()
+        {
+            let (_, hit_b1, hit_b2) = triangle()
+                .intersect_barycentric(&ray_at(x, y, 1.0))
+                .unwrap_or_else(|| panic!("missed ({}, {})", x, y));
+            assert!((hit_b1 - b1).abs() < 1e-12 && (hit_b2 - b2).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn misses_outside_the_triangle() {
+        for &(x, y) in [(-0.01, 1.0), (1.0, -0.01), (1.01, 1.0), (3.0, 3.0)].iter() {
+            assert!(triangle()
+                .intersect_barycentric(&ray_at(x, y, 1.0))
+                .is_none());
+        }
+        // Parallel to the plane of the triangle, even when in it.
+        let ray = Ray {
+            origin: Vec3f::new(-1.0, 0.5, 0.0),
+            dir: Vec3f::new(1.0, 0.0, 0.0),
+        };
+        assert!(triangle().intersect_barycentric(&ray).is_none());
+        // Behind the origin of the ray.
+        let (t, _, _) = triangle()
+            .intersect_barycentric(&ray_at(0.5, 0.5, -1.0))
+            .unwrap();
+        assert!(t < 0.0);
+        assert!(triangle().intersect(&ray_at(0.5, 0.5, -1.0)).is_none());
+    }
+
+    #[test]
+    fn mesh_interpolates_normals_and_uvs() {
+        // A unit square in the z = 0 plane, as two triangles.
+        let positions = vec![
+            Vec3f::new(0.0, 0.0, 0.0),
+            Vec3f::new(1.0, 0.0, 0.0),
+            Vec3f::new(1.0, 1.0, 0.0),
+            Vec3f::new(0.0, 1.0, 0.0),
+        ];
+        let normals = vec![
+            Vec3f::new(-1.0, 0.0, 1.0).normalize(),
+            Vec3f::new(1.0, 0.0, 1.0).normalize(),
+            Vec3f::new(1.0, 0.0, 1.0).normalize(),
+            Vec3f::new(-1.0, 0.0, 1.0).normalize(),
+        ];
+        let uvs = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        let mesh = TriangleMesh::new(
+            positions,
+            Some(normals),
+            Some(uvs),
+            vec![[0, 1, 2], [0, 2, 3]],
+        );
+
+        for &(x, y) in [(0.75, 0.25), (0.25, 0.75), (0.5, 0.5)].iter() {
+            let hit = mesh.intersect(&ray_at(x, y, 2.0)).unwrap();
+            assert!((hit.t - 2.0).abs() < 1e-12);
+            assert!((hit.uv.0 - x).abs() < 1e-12 && (hit.uv.1 - y).abs() < 1e-12);
+            // The normals tilt from -x on the left edge to +x on the right.
+            assert!((hit.normal.norm() - 1.0).abs() < 1e-12);
+            assert!(hit.normal.x * (x - 0.5) >= 0.0);
+            assert!(hit.normal.z > 0.0);
+        }
+
+        // Normals flip along with the face when hit from behind.
+        let ray = Ray {
+            origin: Vec3f::new(0.75, 0.25, -1.0),
+            dir: Vec3f::new(0.0, 0.0, 1.0),
+        };
+        let hit = mesh.intersect(&ray).unwrap();
+        assert!(!hit.front_face && hit.normal.z < 0.0);
+        assert!(mesh.intersect(&ray_at(1.5, 0.5, 1.0)).is_none());
+    }
+}
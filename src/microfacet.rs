@@ -0,0 +1,398 @@
+use crate::object::{orthonormal_basis, texture_at, Hit, Material};
+use crate::sampler::Sampler;
+use crate::sampling::uniform_disk;
+use crate::texture::Texture;
+use crate::vector::{Ray, Vec3f};
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// Below this, roughness is taken to be 0: the distribution of normals would
+// be too peaked to sample and evaluate reliably, so surfaces become mirrors.
+const MIN_ALPHA: f64 = 1e-3;
+
+// A shading frame around a surface normal `n`, with the tangents `u` and
+// `v`, in which the normal is +z.
+pub struct Frame {
+    pub u: Vec3f,
+    pub v: Vec3f,
+    pub n: Vec3f,
+}
+
+impl Frame {
+    pub fn new(normal: &Vec3f) -> Self {
+        let n = normal.normalize();
+        let (u, v) = orthonormal_basis(&n);
+        Frame { u, v, n }
+    }
+
+    // A frame around the normal of a hit whose `u` follows the hit's tangent,
+    // so that it turns with the surface. Hits without a usable tangent get
+    // an arbitrary one.
+    pub fn from_hit(hit: &Hit) -> Self {
+        let n = hit.normal.normalize();
+        if let Some(tangent) = &hit.tangent {
+            let u = tangent - &(&n * n.dot_ref(tangent));
+            if u.norm() > 1e-9 * tangent.norm() {
+                let u = u.normalize();
+                let v = n.cross(&u);
+                return Frame { u, v, n };
+            }
+        }
+        Frame::new(&n)
+    }
+
+    pub fn to_local(&self, w: &Vec3f) -> Vec3f {
+        Vec3f::new(w.dot_ref(&self.u), w.dot_ref(&self.v), w.dot_ref(&self.n))
+    }
+
+    pub fn to_world(&self, w: &Vec3f) -> Vec3f {
+        &self.u * w.x + &self.v * w.y + &self.n * w.z
+    }
+}
+
+// Mirrors `w` about the unit vector `h`.
+pub fn reflect_about(w: &Vec3f, h: &Vec3f) -> Vec3f {
+    &(h * (2.0 * w.dot_ref(h))) - w
+}
+
+// The GGX (or Trowbridge-Reitz) distribution of microfacet normals, in a
+// shading frame where the macroscopic normal is +z. `alpha_x` and `alpha_y`
+// are its widths along the two tangents; they're equal for isotropic
+// surfaces, and different for brushed metal, whose highlights stretch across
+// the direction of the brushing.
+pub struct Ggx {
+    pub alpha_x: f64,
+    pub alpha_y: f64,
+}
+
+impl Ggx {
+    // From the perceptually more even roughness of artists, which is squared
+    // into the width of the distribution. Widths are kept away from 0, which
+    // would be a problem for surfaces that are smooth one way only.
+    pub fn from_roughness(roughness_x: f64, roughness_y: f64) -> Self {
+        Ggx {
+            alpha_x: (roughness_x * roughness_x).max(MIN_ALPHA),
+            alpha_y: (roughness_y * roughness_y).max(MIN_ALPHA),
+        }
+    }
+
+    // Whether the distribution is too narrow to be anything but a mirror.
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) <= MIN_ALPHA
+    }
+
+    // The density of microfacets with normal `h`, per unit solid angle and
+    // unit macroscopic area.
+    pub fn d(&self, h: &Vec3f) -> f64 {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let (x, y) = (h.x / self.alpha_x, h.y / self.alpha_y);
+        let e = x * x + y * y + h.z * h.z;
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    // Smith's auxiliary function, from which the fraction of microfacets
+    // that are visible from `w` follows.
+    fn lambda(&self, w: &Vec3f) -> f64 {
+        if w.z == 0.0 {
+            return f64::INFINITY;
+        }
+        let (x, y) = (self.alpha_x * w.x, self.alpha_y * w.y);
+        let sq_tan = (x * x + y * y) / (w.z * w.z);
+        0.5 * (-1.0 + (1.0 + sq_tan).sqrt())
+    }
+
+    // The fraction of the microfacets facing `w` that aren't hidden from it
+    // by others.
+    pub fn g1(&self, w: &Vec3f) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // The fraction of microfacets visible from both `wo` and `wi`, taking
+    // into account that both directions tend to see the same ones.
+    pub fn g(&self, wo: &Vec3f, wi: &Vec3f) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // The density of the normals of the microfacets seen from `wo`, which
+    // `sample_visible` picks `h` with.
+    pub fn visible_pdf(&self, wo: &Vec3f, h: &Vec3f) -> f64 {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot_ref(h).max(0.0) * self.d(h) / wo.z
+    }
+
+    // Picks the normal of a microfacet seen from `wo`, following Heitz's
+    // "Sampling the GGX Distribution of Visible Normals": the distribution is
+    // stretched into a hemisphere of unit roughness, on which the visible
+    // normals are those of a disk, half of it foreshortened, seen from `wo`.
+    // Unlike sampling the whole distribution, this never picks normals facing
+    // away from `wo`, which would be wasted samples.
+    pub fn sample_visible(&self, wo: &Vec3f, u: (f64, f64)) -> Vec3f {
+        let vh = Vec3f::new(self.alpha_x * wo.x, self.alpha_y * wo.y, wo.z).normalize();
+        let sq_length = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if sq_length > 0.0 {
+            Vec3f::new(-vh.y, vh.x, 0.0) * (1.0 / sq_length.sqrt())
+        } else {
+            Vec3f::new(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(&t1);
+
+        let (p1, p2) = uniform_disk(u);
+        let s = 0.5 * (1.0 + vh.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * p2;
+        let nh = t1 * p1 + t2 * p2 + &vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+        Vec3f::new(
+            self.alpha_x * nh.x,
+            self.alpha_y * nh.y,
+            nh.z.max(f64::EPSILON),
+        )
+        .normalize()
+    }
+}
+
+// The Fresnel reflectance of a conductor with complex index of refraction
+// `eta` + i `k`, against the vacuum, for unpolarized light arriving at an
+// angle whose cosine is `cos_theta`.
+pub fn conductor_fresnel(cos_theta: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_theta.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let (eta2, k2) = (eta * eta, k * k);
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cos_theta.clamp(0.0, 1.0) * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rp + rs)
+}
+
+// The Fresnel reflectance of the boundary with a dielectric, for unpolarized
+// light arriving at an angle whose cosine is `cos_theta` from the side whose
+// index of refraction is `eta` times smaller than the other's. 1 past the
+// critical angle.
+pub fn dielectric_fresnel(cos_theta: f64, eta: f64) -> f64 {
+    let cos_i = cos_theta.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+// Refracts `w` through a surface with the unit normal `h`, on the same side
+// as `w`, into a medium whose index of refraction is `eta` times larger.
+// None past the critical angle.
+pub fn refract_about(w: &Vec3f, h: &Vec3f, eta: f64) -> Option<Vec3f> {
+    let cos_i = w.dot_ref(h);
+    let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(h * (cos_i / eta - cos_t) - w * (1.0 / eta))
+}
+
+// The complex indices of refraction of a few metals, at the red, green and
+// blue wavelengths (650, 550 and 450 nm): the real part `eta`, then the
+// extinction coefficient `k`.
+pub const GOLD: ([f64; 3], [f64; 3]) = ([0.143, 0.374, 1.442], [3.983, 2.385, 1.603]);
+pub const COPPER: ([f64; 3], [f64; 3]) = ([0.200, 0.924, 1.102], [3.912, 2.452, 2.142]);
+pub const ALUMINUM: ([f64; 3], [f64; 3]) = ([1.657, 0.880, 0.521], [9.224, 6.270, 4.837]);
+pub const SILVER: ([f64; 3], [f64; 3]) = ([0.155, 0.117, 0.138], [4.828, 3.122, 2.147]);
+
+// A metal, rough or polished, reflecting light off microfacets with a GGX
+// distribution and the Fresnel reflectance of its complex index of refraction
+// `eta` + i `k` (see the presets above). Roughness goes from 0, a mirror, to
+// 1, and can be different along the two tangents of the surface: `roughness_x`
+// applies along the direction in which the first texture coordinate grows,
+// `roughness_y` across it. `tint` multiplies the reflected light.
+//
+// Light bouncing between microfacets more than once is lost, so rough metals
+// come out darker than they should, by as much as half at a roughness of 1.
+// But unlike `MetalMaterial`, no light is ever created, and rays never leave
+// from below the surface.
+pub struct ConductorMaterial {
+    pub eta: Vec3f,
+    pub k: Vec3f,
+    pub roughness_x: f64,
+    pub roughness_y: f64,
+    pub tint: Arc<dyn Texture + Send + Sync>,
+}
+
+impl ConductorMaterial {
+    fn fresnel(&self, cos_theta: f64) -> Vec3f {
+        Vec3f::new(
+            conductor_fresnel(cos_theta, self.eta.x, self.k.x),
+            conductor_fresnel(cos_theta, self.eta.y, self.k.y),
+            conductor_fresnel(cos_theta, self.eta.z, self.k.z),
+        )
+    }
+
+    fn distribution(&self) -> Ggx {
+        Ggx::from_roughness(self.roughness_x, self.roughness_y)
+    }
+}
+
+impl Material for ConductorMaterial {
+    fn scatter(&self, ray: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Option<(Vec3f, Ray)> {
+        let frame = Frame::from_hit(hit);
+        let wo = frame.to_local(&(&ray.dir * -1.0).normalize());
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let ggx = self.distribution();
+        // Drawn even for mirrors, so that every hit takes the same dimensions.
+        let u = sampler.next_2d();
+        let h = if ggx.is_smooth() {
+            Vec3f::new(0.0, 0.0, 1.0)
+        } else {
+            ggx.sample_visible(&wo, u)
+        };
+        let wi = reflect_about(&wo, &h);
+        if wi.z <= 0.0 {
+            return None;
+        }
+        // The BSDF times the cosine over the density of visible normals
+        // leaves only the Fresnel term and the share of the microfacets that
+        // light can leave from.
+        let masking = if ggx.is_smooth() {
+            1.0
+        } else {
+            ggx.g(&wo, &wi) / ggx.g1(&wo)
+        };
+        let attenuation =
+            self.fresnel(wo.dot_ref(&h)) * texture_at(self.tint.as_ref(), ray, hit) * masking;
+        Some((
+            attenuation,
+            Ray {
+                origin: ray.interpolate(hit.t),
+                dir: frame.to_world(&wi),
+            },
+        ))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, direction: &Vec3f) -> Vec3f {
+        let ggx = self.distribution();
+        let frame = Frame::from_hit(hit);
+        let wo = frame.to_local(&(&ray.dir * -1.0).normalize());
+        let wi = frame.to_local(&direction.normalize());
+        if ggx.is_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3f::new(0.0, 0.0, 0.0);
+        }
+        let h = (&wo + &wi).normalize();
+        // D * G * F / (4 cos(wo) cos(wi)), times cos(wi).
+        let specular = ggx.d(&h) * ggx.g(&wo, &wi) / (4.0 * wo.z);
+        self.fresnel(wo.dot_ref(&h)) * texture_at(self.tint.as_ref(), ray, hit) * specular
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &Hit, direction: &Vec3f) -> f64 {
+        let ggx = self.distribution();
+        let frame = Frame::from_hit(hit);
+        let wo = frame.to_local(&(&ray.dir * -1.0).normalize());
+        let wi = frame.to_local(&direction.normalize());
+        if ggx.is_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        // Reflecting about h turns its density into that of the reflected
+        // direction divided by 4 (wo . h).
+        let h = (&wo + &wi).normalize();
+        ggx.visible_pdf(&wo, &h) / (4.0 * wo.dot_ref(&h))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::furnace::{constant, Furnace};
+
+    fn conductor(eta: Vec3f, k: Vec3f, roughness: f64) -> ConductorMaterial {
+        ConductorMaterial {
+            eta,
+            k,
+            roughness_x: roughness,
+            roughness_y: roughness,
+            tint: constant(1.0),
+        }
+    }
+
+    // An absorption coefficient so high the conductor reflects everything.
+    fn perfect_conductor(roughness: f64) -> ConductorMaterial {
+        conductor(
+            Vec3f::new(1.0, 1.0, 1.0),
+            Vec3f::new(1e4, 1e4, 1e4),
+            roughness,
+        )
+    }
+
+    #[test]
+    fn smooth_perfect_conductor_conserves_energy() {
+        Furnace {
+            albedo: Some(1.0),
+            has_bsdf: false,
+            two_sided: false,
+        }
+        .check(&perfect_conductor(0.0));
+    }
+
+    #[test]
+    fn rough_perfect_conductor_conserves_energy() {
+        Furnace {
+            albedo: None,
+            has_bsdf: true,
+            two_sided: false,
+        }
+        .check(&perfect_conductor(0.5));
+    }
+
+    #[test]
+    fn rough_gold_conserves_energy() {
+        let (eta, k) = GOLD;
+        let material = conductor(
+            Vec3f::new(eta[0], eta[1], eta[2]),
+            Vec3f::new(k[0], k[1], k[2]),
+            0.5,
+        );
+        Furnace {
+            albedo: None,
+            has_bsdf: true,
+            two_sided: false,
+        }
+        .check(&material);
+    }
+
+    #[test]
+    fn frame_follows_the_projected_tangent() {
+        let ray = Ray::from_pts(Vec3f::new(0.0, 0.0, 1.0), Vec3f::new(0.0, 0.0, 0.0));
+        let hit = Hit::new(&ray, 1.0, Vec3f::new(0.0, 0.0, 2.0), (0.0, 0.0));
+
+        // A tangent leaning out of the surface is flattened onto it.
+        let frame = Frame::from_hit(&hit.clone().with_tangent(Vec3f::new(0.0, 3.0, 1.0)));
+        assert!((&frame.u - &Vec3f::new(0.0, 1.0, 0.0)).norm() < 1e-12);
+        assert!((&frame.v - &Vec3f::new(-1.0, 0.0, 0.0)).norm() < 1e-12);
+        assert!((&frame.n - &Vec3f::new(0.0, 0.0, 1.0)).norm() < 1e-12);
+
+        // Missing or degenerate tangents fall back to an arbitrary frame.
+        let fallback = Frame::new(&hit.normal);
+        for hit in [
+            hit.clone(),
+            hit.clone().with_tangent(Vec3f::new(0.0, 0.0, 0.0)),
+            hit.clone().with_tangent(Vec3f::new(0.0, 0.0, 5.0)),
+        ]
+        .iter()
+        {
+            let frame = Frame::from_hit(hit);
+            assert!((&frame.u - &fallback.u).norm() < 1e-12);
+            assert!((&frame.v - &fallback.v).norm() < 1e-12);
+        }
+    }
+}
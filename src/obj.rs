@@ -0,0 +1,583 @@
+use crate::image::read_image;
+use crate::mesh::TriangleMesh;
+use crate::object::*;
+use crate::texture::{ConstantTexture, ImageTexture, Texture, WrapMode};
+use crate::vector::Vec3f;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+// Material used for faces that appear before any `usemtl` statement.
+const DEFAULT_MATERIAL: &str = "";
+
+// The subset of an MTL material definition that we know how to map onto our
+// own materials.
+struct MtlEntry {
+    diffuse: Vec3f,
+    // Replaces `diffuse` rather than being multiplied by it: exporters tend
+    // to write a default gray Kd next to texture maps.
+    diffuse_map: Option<Arc<dyn Texture + Send + Sync>>,
+    specular: Vec3f,
+    shininess: f64,
+    illum: u32,
+    // PBR extensions: metallic (Pm) and roughness (Pr).
+    metallic: Option<f64>,
+    roughness: Option<f64>,
+}
+
+impl Default for MtlEntry {
+    fn default() -> Self {
+        MtlEntry {
+            diffuse: Vec3f::new(0.8, 0.8, 0.8),
+            diffuse_map: None,
+            specular: Vec3f::new(0.0, 0.0, 0.0),
+            shininess: 0.0,
+            illum: 2,
+            metallic: None,
+            roughness: None,
+        }
+    }
+}
+
+impl MtlEntry {
+    // Materials are metallic if they say so through the PBR extension, or if
+    // they use one of the illumination models with ray traced reflections.
+    fn is_metal(&self) -> bool {
+        match self.metallic {
+            Some(metallic) => metallic >= 0.5,
+            None => matches!(self.illum, 3 | 5 | 8),
+        }
+    }
+
+    // Map the Phong exponent onto a roughness with the usual
+    // Blinn-Phong/Beckmann correspondence: alpha = sqrt(2 / (n + 2)).
+    fn fuzz(&self) -> f64 {
+        self.roughness
+            .unwrap_or_else(|| (2.0 / (self.shininess.max(0.0) + 2.0)).sqrt())
+            .clamp(0.0, 1.0)
+    }
+
+    fn to_material(&self) -> Arc<dyn Material + Send + Sync> {
+        if !self.is_metal() {
+            return Arc::new(DiffuseMaterial {
+                color: self.diffuse_map.clone().unwrap_or_else(|| {
+                    Arc::new(ConstantTexture {
+                        color: self.diffuse.clone(),
+                    })
+                }),
+            });
+        }
+
+        let attenuation = if self.specular.sq_norm() > 0.0 {
+            self.specular.clone()
+        } else {
+            self.diffuse.clone()
+        };
+        Arc::new(MetalMaterial {
+            attenuation: Arc::new(ConstantTexture { color: attenuation }),
+            fuzz: self.fuzz(),
+        })
+    }
+}
+
+// Collects the faces of one material while the OBJ file is being read.
+// OBJ faces index positions, texture coordinates and normals separately, so
+// every distinct combination becomes its own vertex of the indexed mesh.
+#[derive(Default)]
+struct MeshBuilder {
+    positions: Vec<Vec3f>,
+    normals: Vec<Vec3f>,
+    uvs: Vec<(f64, f64)>,
+    indices: Vec<[usize; 3]>,
+    vertices: HashMap<(usize, Option<usize>, Option<usize>), usize>,
+    missing_normals: bool,
+    missing_uvs: bool,
+}
+
+impl MeshBuilder {
+    fn vertex(
+        &mut self,
+        key: (usize, Option<usize>, Option<usize>),
+        positions: &[Vec3f],
+        uvs: &[(f64, f64)],
+        normals: &[Vec3f],
+    ) -> usize {
+        if let Some(&index) = self.vertices.get(&key) {
+            return index;
+        }
+        let (position, uv, normal) = key;
+        self.positions.push(positions[position].clone());
+        match uv {
+            Some(uv) => self.uvs.push(uvs[uv]),
+            None => self.missing_uvs = true,
+        }
+        match normal {
+            Some(normal) => self.normals.push(normals[normal].normalize()),
+            None => self.missing_normals = true,
+        }
+        let index = self.positions.len() - 1;
+        self.vertices.insert(key, index);
+        index
+    }
+
+    // Vertex normals and texture coordinates are only kept if every vertex
+    // of the mesh has them.
+    fn build(self) -> TriangleMesh {
+        let normals = if self.missing_normals {
+            None
+        } else {
+            Some(self.normals)
+        };
+        let uvs = if self.missing_uvs {
+            None
+        } else {
+            Some(self.uvs)
+        };
+        TriangleMesh::new(self.positions, normals, uvs, self.indices)
+    }
+}
+
+fn parse_error(path: &Path, line: usize, message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{}: {}", path.display(), line, message),
+    )
+}
+
+fn parse_floats<'a>(
+    path: &Path,
+    line: usize,
+    tokens: impl Iterator<Item = &'a str>,
+) -> Result<Vec<f64>, io::Error> {
+    tokens
+        .map(|token| {
+            token
+                .parse::<f64>()
+                .map_err(|_| parse_error(path, line, format!("invalid number `{}`", token)))
+        })
+        .collect()
+}
+
+fn parse_vec3(path: &Path, line: usize, values: &[f64]) -> Result<Vec3f, io::Error> {
+    match values {
+        [x, y, z, ..] => Ok(Vec3f::new(*x, *y, *z)),
+        _ => Err(parse_error(
+            path,
+            line,
+            format!("expected 3 components, found {}", values.len()),
+        )),
+    }
+}
+
+// Resolves a 1-based (or, if negative, end-relative) OBJ index into an index
+// into an array of `len` elements.
+fn resolve_index(path: &Path, line: usize, token: &str, len: usize) -> Result<usize, io::Error> {
+    let index: i64 = token
+        .parse()
+        .map_err(|_| parse_error(path, line, format!("invalid index `{}`", token)))?;
+    let resolved = if index < 0 {
+        len as i64 + index
+    } else {
+        index - 1
+    };
+    if resolved < 0 || resolved >= len as i64 {
+        return Err(parse_error(
+            path,
+            line,
+            format!("index {} out of range", index),
+        ));
+    }
+    Ok(resolved as usize)
+}
+
+fn load_mtl(path: &Path) -> Result<HashMap<String, MtlEntry>, io::Error> {
+    let contents = fs::read_to_string(path)?;
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlEntry)> = None;
+
+    for (i, raw_line) in contents.lines().enumerate() {
+        let line = i + 1;
+        let mut tokens = raw_line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) if !keyword.starts_with('#') => keyword,
+            _ => continue,
+        };
+
+        if keyword == "newmtl" {
+            let name = tokens.collect::<Vec<_>>().join(" ");
+            if let Some((name, entry)) = current.replace((name, MtlEntry::default())) {
+                materials.insert(name, entry);
+            }
+            continue;
+        }
+
+        let entry = match current.as_mut() {
+            Some((_, entry)) => entry,
+            None => {
+                return Err(parse_error(
+                    path,
+                    line,
+                    format!("`{}` before any `newmtl`", keyword),
+                ))
+            }
+        };
+        match keyword {
+            "Kd" => entry.diffuse = parse_vec3(path, line, &parse_floats(path, line, tokens)?)?,
+            "Ks" => entry.specular = parse_vec3(path, line, &parse_floats(path, line, tokens)?)?,
+            "Ns" | "Pm" | "Pr" | "illum" => {
+                let value = match parse_floats(path, line, tokens)?.first() {
+                    Some(value) => *value,
+                    None => {
+                        return Err(parse_error(
+                            path,
+                            line,
+                            format!("missing value for `{}`", keyword),
+                        ))
+                    }
+                };
+                match keyword {
+                    "Ns" => entry.shininess = value,
+                    "Pm" => entry.metallic = Some(value),
+                    "Pr" => entry.roughness = Some(value),
+                    _ => entry.illum = value as u32,
+                }
+            }
+            "map_Kd" => {
+                // Options such as -s or -o aren't supported; the file name
+                // comes last.
+                let file = tokens.last().ok_or_else(|| {
+                    parse_error(path, line, "missing file name for `map_Kd`".to_string())
+                })?;
+                let directory = path.parent().unwrap_or_else(|| Path::new(""));
+                let image = read_image(&directory.join(file)).map_err(|e| {
+                    parse_error(path, line, format!("failed to load texture: {}", e))
+                })?;
+                entry.diffuse_map = Some(Arc::new(ImageTexture {
+                    image,
+                    wrap: WrapMode::Repeat,
+                }));
+            }
+            // Other statements (ambient color, other texture maps, ...) are
+            // ignored.
+            _ => {}
+        }
+    }
+
+    if let Some((name, entry)) = current {
+        materials.insert(name, entry);
+    }
+    Ok(materials)
+}
+
+// Loads a Wavefront OBJ file, along with the MTL libraries it references, and
+// returns one object per material used in the file. Polygons are split into
+// triangle fans.
+pub fn load_obj(path: &Path) -> Result<Vec<Object>, io::Error> {
+    Ok(read_obj(path)?
+        .into_iter()
+        .map(|(material, mesh)| Object {
+            material: material.to_material(),
+            shape: Arc::new(mesh),
+        })
+        .collect())
+}
+
+// The meshes of an OBJ file, one per material in the order they first appear.
+fn read_obj(path: &Path) -> Result<Vec<(MtlEntry, TriangleMesh)>, io::Error> {
+    let contents = fs::read_to_string(path)?;
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut positions: Vec<Vec3f> = vec![];
+    let mut normals: Vec<Vec3f> = vec![];
+    let mut uvs: Vec<(f64, f64)> = vec![];
+    let mut materials: HashMap<String, MtlEntry> = HashMap::new();
+    // Meshes in the order their material first appears, for determinism.
+    let mut meshes: Vec<(String, MeshBuilder)> = vec![];
+    let mut current = DEFAULT_MATERIAL.to_string();
+
+    for (i, raw_line) in contents.lines().enumerate() {
+        let line = i + 1;
+        let mut tokens = raw_line.split_whitespace();
+        let keyword = match tokens.next() {
+            Some(keyword) if !keyword.starts_with('#') => keyword,
+            _ => continue,
+        };
+
+        match keyword {
+            "v" => positions.push(parse_vec3(path, line, &parse_floats(path, line, tokens)?)?),
+            "vn" => normals.push(parse_vec3(path, line, &parse_floats(path, line, tokens)?)?),
+            "vt" => {
+                let values = parse_floats(path, line, tokens)?;
+                match values.as_slice() {
+                    [u] => uvs.push((*u, 0.0)),
+                    [u, v, ..] => uvs.push((*u, *v)),
+                    [] => {
+                        return Err(parse_error(
+                            path,
+                            line,
+                            "empty texture coordinate".to_string(),
+                        ))
+                    }
+                }
+            }
+            "mtllib" => {
+                for library in tokens {
+                    materials.extend(load_mtl(&directory.join(library))?);
+                }
+            }
+            "usemtl" => {
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                if !materials.contains_key(&name) {
+                    return Err(parse_error(
+                        path,
+                        line,
+                        format!("unknown material `{}`", name),
+                    ));
+                }
+                current = name;
+            }
+            "f" => {
+                let mut face = vec![];
+                for token in tokens {
+                    let mut parts = token.split('/');
+                    let position =
+                        resolve_index(path, line, parts.next().unwrap_or(""), positions.len())?;
+                    let uv = match parts.next() {
+                        Some(part) if !part.is_empty() => {
+                            Some(resolve_index(path, line, part, uvs.len())?)
+                        }
+                        _ => None,
+                    };
+                    let normal = match parts.next() {
+                        Some(part) if !part.is_empty() => {
+                            Some(resolve_index(path, line, part, normals.len())?)
+                        }
+                        _ => None,
+                    };
+                    face.push((position, uv, normal));
+                }
+                if face.len() < 3 {
+                    return Err(parse_error(
+                        path,
+                        line,
+                        format!("face with {} vertices", face.len()),
+                    ));
+                }
+
+                let builder = match meshes.iter().position(|(name, _)| *name == current) {
+                    Some(index) => &mut meshes[index].1,
+                    None => {
+                        meshes.push((current.clone(), MeshBuilder::default()));
+                        &mut meshes.last_mut().unwrap().1
+                    }
+                };
+                let face: Vec<usize> = face
+                    .into_iter()
+                    .map(|key| builder.vertex(key, &positions, &uvs, &normals))
+                    .collect();
+                for k in 1..face.len() - 1 {
+                    builder.indices.push([face[0], face[k], face[k + 1]]);
+                }
+            }
+            // Groups, object names, smoothing groups and such don't affect us.
+            _ => {}
+        }
+    }
+
+    // Each material has a single mesh, so its entry can be moved out.
+    Ok(meshes
+        .into_iter()
+        .map(|(name, builder)| {
+            let material = materials.remove(&name).unwrap_or_default();
+            (material, builder.build())
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{IndependentSampler, Sampler};
+    use crate::temp_dir::TempDir;
+    use crate::vector::Ray;
+
+    #[test]
+    fn resolves_relative_and_absolute_indices() {
+        let path = Path::new("mesh.obj");
+        assert_eq!(resolve_index(path, 1, "1", 4).unwrap(), 0);
+        assert_eq!(resolve_index(path, 1, "4", 4).unwrap(), 3);
+        assert_eq!(resolve_index(path, 1, "-1", 4).unwrap(), 3);
+        assert_eq!(resolve_index(path, 1, "-4", 4).unwrap(), 0);
+        for (token, message) in [
+            ("0", "mesh.obj:3: index 0 out of range"),
+            ("5", "mesh.obj:3: index 5 out of range"),
+            ("-5", "mesh.obj:3: index -5 out of range"),
+            ("x", "mesh.obj:3: invalid index `x`"),
+            ("", "mesh.obj:3: invalid index ``"),
+        ]
+        .iter()
+        {
+            let error = resolve_index(path, 3, token, 4).unwrap_err();
+            assert_eq!(error.to_string(), *message);
+        }
+    }
+
+    fn load(obj: &str) -> Result<Vec<(MtlEntry, TriangleMesh)>, io::Error> {
+        let dir = TempDir::new();
+        read_obj(&dir.write("mesh.obj", obj))
+    }
+
+    #[test]
+    fn polygons_become_triangle_fans() {
+        let meshes = load(
+            "v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+v -1 1 0
+f 1 2 3 4 5
+",
+        )
+        .unwrap();
+        assert_eq!(meshes.len(), 1);
+        let mesh = &meshes[0].1;
+        assert_eq!(mesh.indices, [[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+        assert!(mesh.normals.is_none());
+        assert!(mesh.uvs.is_none());
+    }
+
+    #[test]
+    fn shares_vertices_with_the_same_position_uv_and_normal() {
+        let meshes = load(
+            "v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 2
+f 1/1/1 2/2/1 3/3/1
+f 1/1/1 3/3/1 4/4/1
+f -4/-1/-1 -3/2/1 -2/3/1
+",
+        )
+        .unwrap();
+        let mesh = &meshes[0].1;
+        // The last face uses the first position with a new texture
+        // coordinate, which makes a fifth vertex; the rest are shared.
+        assert_eq!(mesh.indices, [[0, 1, 2], [0, 2, 3], [4, 1, 2]]);
+        assert_eq!(mesh.positions.len(), 5);
+        assert_eq!(mesh.uvs.as_ref().unwrap()[4], (0.0, 1.0));
+        let normals = mesh.normals.as_ref().unwrap();
+        assert_eq!(normals.len(), 5);
+        assert!((normals[0].z - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn reports_bad_faces_with_their_line() {
+        let dir = TempDir::new();
+        for (obj, message) in [
+            (
+                "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n",
+                "4: index 4 out of range",
+            ),
+            (
+                "v 0 0 0\nv 1 0 0\nv 0 1 0\n\nf 1 -4 3\n",
+                "5: index -4 out of range",
+            ),
+            ("v 0 0 0\nv 1 0 0\nf 1 2\n", "3: face with 2 vertices"),
+            (
+                "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1/1 2/1 3/1\n",
+                "4: index 1 out of range",
+            ),
+            ("v 0 0\n", "1: expected 3 components, found 2"),
+            ("usemtl nothing\n", "1: unknown material `nothing`"),
+        ]
+        .iter()
+        {
+            let path = dir.write("bad.obj", obj);
+            let error = read_obj(&path).err().unwrap().to_string();
+            assert_eq!(error, format!("{}:{}", path.display(), message));
+        }
+    }
+
+    #[test]
+    fn maps_mtl_materials() {
+        let dir = TempDir::new();
+        dir.write(
+            "materials.mtl",
+            "newmtl paint
+Kd 0.2 0.4 0.6
+illum 2
+
+newmtl mirror
+Kd 0.1 0.1 0.1
+Ks 0.9 0.8 0.7
+Ns 1e12
+illum 3
+
+newmtl brushed
+Kd 0.5 0.5 0.5
+Pm 1.0
+Pr 0.3
+
+# PBR metallic wins over the illumination model.
+newmtl plastic
+Kd 0.3 0.3 0.3
+illum 3
+Pm 0.0
+",
+        );
+        let path = dir.write(
+            "mesh.obj",
+            "mtllib materials.mtl
+v 0 0 0
+v 1 0 0
+v 0 1 0
+usemtl mirror
+f 1 2 3
+usemtl paint
+f 1 2 3
+usemtl brushed
+f 1 2 3
+usemtl plastic
+f 1 2 3
+usemtl mirror
+f 3 2 1
+",
+        );
+        let meshes = read_obj(&path).unwrap();
+        let metal: Vec<bool> = meshes.iter().map(|(entry, _)| entry.is_metal()).collect();
+        assert_eq!(metal, [true, false, true, false]);
+        // Both faces in the mirror material end up in its mesh.
+        assert_eq!(meshes[0].1.indices.len(), 2);
+        assert!(meshes[0].0.fuzz() < 1e-5);
+        assert_eq!(meshes[2].0.fuzz(), 0.3);
+
+        // A ray straight down onto the triangle, from above.
+        let ray = Ray {
+            origin: Vec3f::new(0.25, 0.25, 1.0),
+            dir: Vec3f::new(0.0, 0.0, -1.0),
+        };
+        let hit = meshes[0].1.intersect(&ray).unwrap();
+        let mut sampler = IndependentSampler::new(0);
+        sampler.start_pixel_sample((0, 0), 0);
+
+        // The mirror reflects the ray straight back, tinted by Ks.
+        let mirror = meshes[0].0.to_material();
+        let (color, reflected) = mirror.scatter(&ray, &hit, &mut sampler).unwrap();
+        assert!((&color - &Vec3f::new(0.9, 0.8, 0.7)).norm() < 1e-12);
+        assert!((&reflected.dir.normalize() - &Vec3f::new(0.0, 0.0, 1.0)).norm() < 1e-5);
+        assert_eq!(mirror.pdf(&ray, &hit, &Vec3f::new(0.0, 0.0, 1.0)), 0.0);
+
+        // The paint scatters diffusely, tinted by Kd.
+        let paint = meshes[1].0.to_material();
+        let (color, _) = paint.scatter(&ray, &hit, &mut sampler).unwrap();
+        assert!((&color - &Vec3f::new(0.2, 0.4, 0.6)).norm() < 1e-12);
+        assert!(paint.pdf(&ray, &hit, &Vec3f::new(0.0, 0.6, 0.8)) > 0.0);
+    }
+}
@@ -1,44 +1,107 @@
-use crate::color::Color;
+use crate::bvh::{Aabb, Bvh};
+use crate::sampler::{hash, Rng, Sampler};
+use crate::sampling::{cosine_hemisphere, cosine_hemisphere_pdf, uniform_sphere};
+use crate::texture::Texture;
 use crate::vector::Ray;
-use crate::vector::Vec3f;
+use crate::vector::{Transform, Vec3f};
 use std::sync::Arc;
 
-const RAY_BOUNCE_DEPTH: usize = 50;
+// Hits closer than this are ignored, so that scattered rays don't immediately
+// re-intersect the surface they start on.
+pub const MIN_HIT_DISTANCE: f64 = 0.001;
+// Scattered rays closer than this to the plane of the surface, in cosine,
+// are absorbed rather than sent grazing along it.
+const MIN_COS_THETA: f64 = 1e-8;
 
 pub struct DiffuseMaterial {
-    pub color: Color,
+    pub color: Arc<dyn Texture + Send + Sync>,
 }
 
 pub struct MetalMaterial {
-    pub attenuation: Color,
+    pub attenuation: Arc<dyn Texture + Send + Sync>,
     pub fuzz: f64,
 }
 
-// Based on snell's law, sin(theta) * eta = sin(theta') * eta'
-// One can prove that
-// R'_perp = eta_ratio * (R + cos(theta) * n) = eta_ratio * (R + (-R * n) * n)
-// R'_par = -sqrt(1 - abs(R'_perp)^2) * n
+// A clear material such as glass or water, which reflects part of the light
+// hitting it and refracts the rest. `ior` is its index of refraction (about
+// 1.5 for glass, 1.33 for water) against the vacuum around it.
+//
+// Light travelling through the material is absorbed following the
+// Beer-Lambert law: after a distance d, a fraction exp(-absorption * d) of
+// each channel is left. Zero absorption gives perfectly clear glass, while
+// small values tint thick parts of an object more than thin ones.
 pub struct DielectricMaterial {
-    pub eta_ratio: f64,
+    pub ior: f64,
+    pub absorption: Vec3f,
 }
 
-pub trait Material {
-    // Given an incident ray (with a point on the ray), and the surface normal,
-    // return a color contribution as well as a new reflected ray.
-    fn scatter(&self, ray: &Ray, normal: &Vec3f, t: f64) -> (Vec3f, Ray);
+// A surface that emits light evenly in every direction and absorbs everything
+// that hits it. The emitted radiance is `color * intensity`, so intensities
+// above 1 are needed for lights that are brighter than white.
+pub struct DiffuseLight {
+    pub color: Arc<dyn Texture + Send + Sync>,
+    pub intensity: f64,
 }
 
-// Returns a random vector in the unit sphere according to the Lambertian distribution.
-pub fn random_in_unit_sphere() -> Vec3f {
-    let rand_one_one = || 2.0 * rand::random::<f64>() - 1.0;
-    loop {
-        let x = rand_one_one();
-        let y = rand_one_one();
-        let z = rand_one_one();
-        if x.powi(2) + y.powi(2) + z.powi(2) < 1.0 {
-            return Vec3f::new(x, y, z).normalize();
-        }
+// Looks a texture up at a hit.
+pub fn texture_at(texture: &(dyn Texture + Send + Sync), ray: &Ray, hit: &Hit) -> Vec3f {
+    texture.value(hit.uv.0, hit.uv.1, &ray.interpolate(hit.t))
+}
+
+pub trait Material {
+    // Given an incident ray and where it hit the surface, return a color
+    // contribution as well as a new reflected ray, or None if the ray is
+    // absorbed. The random numbers come from `sampler`.
+    fn scatter(&self, ray: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Option<(Vec3f, Ray)>;
+
+    // How much of the light arriving from `direction` the surface reflects
+    // back along the incident ray: the BSDF times the cosine of the angle
+    // between `direction` and the normal. The color contribution returned by
+    // `scatter` is this divided by `pdf`.
+    //
+    // Perfectly specular materials only scatter light in a single direction,
+    // which a randomly picked direction never matches, so they keep the
+    // defaults of no contribution and a zero PDF.
+    fn eval(&self, _ray: &Ray, _hit: &Hit, _direction: &Vec3f) -> Vec3f {
+        Vec3f::new(0.0, 0.0, 0.0)
     }
+
+    // The probability density, per unit solid angle, of `scatter` picking
+    // `direction`.
+    fn pdf(&self, _ray: &Ray, _hit: &Hit, _direction: &Vec3f) -> f64 {
+        0.0
+    }
+
+    // Radiance emitted by the surface towards the origin of the incident ray.
+    fn emitted(&self, _ray: &Ray, _hit: &Hit) -> Vec3f {
+        Vec3f::new(0.0, 0.0, 0.0)
+    }
+
+    // Whether `emitted` can return anything but black. Objects made of
+    // emissive materials are sampled as lights.
+    fn is_emissive(&self) -> bool {
+        false
+    }
+}
+
+// Returns a random point inside the unit sphere, all equally likely: a
+// uniformly distributed direction, at a distance that makes up for the
+// outer shells of the sphere being larger than the inner ones.
+pub fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3f {
+    uniform_sphere(sampler.next_2d()) * sampler.next_1d().cbrt()
+}
+
+// Returns two unit vectors that form an orthonormal basis with the unit
+// vector `w`.
+pub fn orthonormal_basis(w: &Vec3f) -> (Vec3f, Vec3f) {
+    let a = if w.x.abs() > 0.9 {
+        Vec3f::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3f::new(1.0, 0.0, 0.0)
+    };
+    let v = w.cross(&a).normalize();
+    let u = w.cross(&v);
+    (u, v)
 }
 
 pub fn reflect(ray: &Ray, normal: &Vec3f, t: f64) -> Ray {
@@ -49,52 +112,244 @@ pub fn reflect(ray: &Ray, normal: &Vec3f, t: f64) -> Ray {
 }
 
 impl Material for DiffuseMaterial {
-    fn scatter(&self, ray: &Ray, normal: &Vec3f, t: f64) -> (Vec3f, Ray) {
-        let intersection_point = ray.interpolate(t);
-        let lambertian_sphere_center = &intersection_point + normal;
-        let og_to_scattered = lambertian_sphere_center + random_in_unit_sphere();
-        (
-            Vec3f::from_color(self.color.clone()),
-            Ray::from_pts(intersection_point, og_to_scattered),
-        )
+    fn scatter(&self, ray: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Option<(Vec3f, Ray)> {
+        let local = cosine_hemisphere(sampler.next_2d());
+        let (u, v) = orthonormal_basis(&hit.normal);
+        let direction = u * local.x + v * local.y + &hit.normal * local.z;
+        // Directions in the plane of the surface would leave along it, or
+        // immediately hit it again.
+        if direction.dot_ref(&hit.normal) <= MIN_COS_THETA {
+            return None;
+        }
+        Some((
+            texture_at(self.color.as_ref(), ray, hit),
+            Ray {
+                origin: ray.interpolate(hit.t),
+                dir: direction,
+            },
+        ))
+    }
+
+    // Lambertian reflection, albedo / pi, sampled proportionally to the cosine.
+    fn eval(&self, ray: &Ray, hit: &Hit, direction: &Vec3f) -> Vec3f {
+        let cos_theta = hit.normal.dot_ref(&direction.normalize()).max(0.0);
+        texture_at(self.color.as_ref(), ray, hit) * (cos_theta / std::f64::consts::PI)
+    }
+
+    fn pdf(&self, _ray: &Ray, hit: &Hit, direction: &Vec3f) -> f64 {
+        cosine_hemisphere_pdf(hit.normal.dot_ref(&direction.normalize()))
     }
 }
 
 impl Material for MetalMaterial {
-    fn scatter(&self, ray: &Ray, normal: &Vec3f, t: f64) -> (Vec3f, Ray) {
-        let reflected_ray = reflect(ray, normal, t);
+    fn scatter(&self, ray: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Option<(Vec3f, Ray)> {
+        let reflected_ray = reflect(ray, &hit.normal, hit.t);
         let r = Ray {
             origin: reflected_ray.origin,
-            dir: reflected_ray.dir + random_in_unit_sphere() * self.fuzz,
+            dir: reflected_ray.dir.normalize() + random_in_unit_sphere(sampler) * self.fuzz,
         };
-        (Vec3f::from_color(self.attenuation.clone()), r)
+        // Fuzz can push the reflection below the surface, or cancel it out
+        // entirely: the surface absorbs those rays.
+        if r.dir.dot_ref(&hit.normal) <= MIN_COS_THETA * r.dir.norm() {
+            return None;
+        }
+        Some((texture_at(self.attenuation.as_ref(), ray, hit), r))
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray: &Ray, _hit: &Hit, _sampler: &mut dyn Sampler) -> Option<(Vec3f, Ray)> {
+        None
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &Hit) -> Vec3f {
+        texture_at(self.color.as_ref(), ray, hit) * self.intensity
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
     }
 }
 
+// Schlick's approximation of the Fresnel reflectance. `cos_theta` has to be
+// the cosine of the angle on the side of the less dense medium, which for
+// rays leaving the material is the angle of the refracted ray.
+fn schlick(cos_theta: f64, eta_ratio: f64) -> f64 {
+    let r0 = ((1.0 - eta_ratio) / (1.0 + eta_ratio)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
+}
+
+// Based on snell's law, sin(theta) * eta = sin(theta') * eta'
+// One can prove that
+// R'_perp = eta_ratio * (R + cos(theta) * n) = eta_ratio * (R + (-R * n) * n)
+// R'_par = -sqrt(1 - abs(R'_perp)^2) * n
 impl Material for DielectricMaterial {
-    fn scatter(&self, ray: &Ray, normal: &Vec3f, t: f64) -> (Vec3f, Ray) {
-        let incident_point = ray.interpolate(t);
+    fn scatter(&self, ray: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Option<(Vec3f, Ray)> {
+        let incident_point = ray.interpolate(hit.t);
+        let eta_ratio = if hit.front_face {
+            1.0 / self.ior
+        } else {
+            self.ior
+        };
         let norm_ray_dir = ray.dir.normalize();
-        let cos_theta = -norm_ray_dir.dot_ref(normal).min(1.0);
+        let cos_theta = (-norm_ray_dir.dot_ref(&hit.normal)).min(1.0);
         let sin_theta = (1.0 - cos_theta.powi(2)).sqrt();
 
-        let direction: Ray;
-        if self.eta_ratio * sin_theta > 1.0 {
-            direction = reflect(ray, normal, t);
+        // Past the critical angle everything is reflected. Otherwise reflect
+        // with the Fresnel probability, so that on average the right share of
+        // light goes each way and the ray carries its full weight.
+        let sin_refracted = eta_ratio * sin_theta;
+        let reflectance = if sin_refracted > 1.0 {
+            1.0
+        } else if eta_ratio > 1.0 {
+            schlick((1.0 - sin_refracted.powi(2)).sqrt(), eta_ratio)
+        } else {
+            schlick(cos_theta, eta_ratio)
+        };
+
+        let direction = if sampler.next_1d() < reflectance {
+            reflect(ray, &hit.normal, hit.t)
         } else {
-            let r_perp = (normal * cos_theta + norm_ray_dir) * self.eta_ratio;
-            let r_par = normal * (1.0 - r_perp.norm().powi(2)).sqrt() * -1.0;
-            direction = Ray {
+            let r_perp = (&hit.normal * cos_theta + norm_ray_dir) * eta_ratio;
+            let r_par = &hit.normal * (1.0 - r_perp.norm().powi(2)).max(0.0).sqrt() * -1.0;
+            Ray {
                 origin: incident_point,
                 dir: (r_par + r_perp),
             }
+        };
+
+        // Hitting the inside of the surface means the ray has just crossed the
+        // material, so apply the absorption along the way.
+        let attenuation = if hit.front_face {
+            Vec3f::new(1.0, 1.0, 1.0)
+        } else {
+            let distance = hit.t * ray.dir.norm();
+            Vec3f::new(
+                (-self.absorption.x * distance).exp(),
+                (-self.absorption.y * distance).exp(),
+                (-self.absorption.z * distance).exp(),
+            )
+        };
+        Some((attenuation, direction))
+    }
+}
+
+// Where a ray hits a shape. `normal` is a unit vector on the side of the
+// surface the ray came from, and `front_face` tells whether that's the
+// outside of the shape, which materials such as glass need to know. `uv` are
+// the texture coordinates of the hit point, and `tangent`, if the shape knows
+// it, the direction along the surface in which u increases. It isn't
+// normalized, and can be zero where u is undefined, such as at the poles of a
+// sphere.
+#[derive(Clone, Debug)]
+pub struct Hit {
+    pub t: f64,
+    pub normal: Vec3f,
+    pub front_face: bool,
+    pub uv: (f64, f64),
+    pub tangent: Option<Vec3f>,
+}
+
+impl Hit {
+    // Builds a hit from the outward facing unit normal at the hit point.
+    pub fn new(ray: &Ray, t: f64, outward_normal: Vec3f, uv: (f64, f64)) -> Self {
+        let front_face = ray.dir.dot_ref(&outward_normal) < 0.0;
+        Hit {
+            t,
+            normal: if front_face {
+                outward_normal
+            } else {
+                outward_normal * -1.0
+            },
+            front_face,
+            uv,
+            tangent: None,
+        }
+    }
+
+    pub fn with_tangent(self, tangent: Vec3f) -> Self {
+        Hit {
+            tangent: Some(tangent),
+            ..self
         }
-        (Vec3f::from_color(Color::new(255, 255, 255)), direction)
     }
 }
 
 pub trait Shape {
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vec3f)>;
+    // The closest hit along the ray that is at least MIN_HIT_DISTANCE away.
+    // Media have no surface to hit: they are found by `intersect_medium`.
+    fn intersect(&self, ray: &Ray) -> Option<Hit>;
+    // Returns a box enclosing the whole shape, used to build the world's BVH.
+    fn bounding_box(&self) -> Aabb;
+
+    // Picks a random direction from `origin` towards the shape, so that
+    // emissive shapes can be sampled as lights. Returns the (unit) direction
+    // and its probability density per unit solid angle, or None if the shape
+    // can't be sampled.
+    fn sample_direction(
+        &self,
+        _origin: &Vec3f,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<(Vec3f, f64)> {
+        None
+    }
+
+    // The probability density of `sample_direction` picking `direction`.
+    fn direction_pdf(&self, _origin: &Vec3f, _direction: &Vec3f) -> f64 {
+        0.0
+    }
+
+    // Every stretch of the line through `ray` that is inside the shape, in
+    // order, including those behind the origin of the ray, so that shapes can
+    // be combined by CSG. Shapes that don't enclose a volume, such as
+    // triangles, have no inside and return nothing.
+    fn intervals(&self, _ray: &Ray) -> Vec<Interval> {
+        vec![]
+    }
+
+    // Whether the shape is a participating medium rather than a surface.
+    // Shadow rays go through media, losing some of their light on the way,
+    // instead of being stopped by them.
+    fn is_medium(&self) -> bool {
+        false
+    }
+
+    // For media, where `ray` is scattered, if it is before leaving the
+    // medium, picked with the random numbers of `rng`.
+    fn intersect_medium(&self, _ray: &Ray, _rng: &mut Rng) -> Option<Hit> {
+        None
+    }
+
+    // For media, the fraction of the light travelling along `ray` that makes
+    // it through the medium up to distance `t_max`, or an unbiased estimate of
+    // it.
+    fn transmittance(&self, _ray: &Ray, _t_max: f64, _rng: &mut Rng) -> f64 {
+        1.0
+    }
+}
+
+// Where a ray enters and then leaves a solid shape.
+#[derive(Clone, Debug)]
+pub struct Interval {
+    pub enter: Hit,
+    pub exit: Hit,
+}
+
+// Pairs up the crossings of a ray with the surface of a closed shape, sorted
+// by distance, into the intervals between them. A ray grazing an edge can
+// cross an odd number of times, in which case it's taken to miss.
+pub fn pair_crossings(mut crossings: Vec<Hit>) -> Vec<Interval> {
+    if !crossings.len().is_multiple_of(2) {
+        return vec![];
+    }
+    crossings.sort_by(|a, b| a.t.total_cmp(&b.t));
+    crossings
+        .chunks(2)
+        .map(|pair| Interval {
+            enter: pair[0].clone(),
+            exit: pair[1].clone(),
+        })
+        .collect()
 }
 
 pub struct Object {
@@ -107,6 +362,15 @@ pub struct Sphere {
     pub radius: f64,
 }
 
+impl Sphere {
+    fn hit_at(&self, ray: &Ray, t: f64) -> Hit {
+        let normal = (&ray.interpolate(t) - &self.center) * (1.0 / self.radius);
+        let uv = sphere_uv(&normal);
+        let tangent = around_y_tangent(&normal);
+        Hit::new(ray, t, normal, uv).with_tangent(tangent)
+    }
+}
+
 // equation for a sphere: (x-c) * (x-c) = r^2
 // equation for a ray: x = d * t + o
 // |d|^2t^2 + |o|^2 + |c|^2 + 2t(d*o - d*c) - 2o*c = r^2
@@ -114,75 +378,612 @@ pub struct Sphere {
 // -> (o-c)^2 - (o^2 + c^2 - 2oc - r^2)
 // -> r^2 - 4oc
 impl Shape for Sphere {
-    fn intersect(&self, ray: &Ray) -> Option<(f64, Vec3f)> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
         let a = ray.dir.sq_norm();
         let b = (ray.dir.dot_ref(&(&ray.origin - &self.center))) * 2.0;
         let c = ray.origin.sq_norm() + self.center.sq_norm()
             - self.radius * self.radius
             - ray.origin.dot_ref(&self.center) * 2.0;
         let disc = b * b - 4.0 * a * c;
-        if disc >= 0.0 {
-            let t = (-b - f64::sqrt(disc)) / (2.0 * a);
-            let normal = (&ray.interpolate(t) - &self.center).normalize();
-            Some((t, normal))
+        if disc < 0.0 {
+            return None;
+        }
+        // Try the nearer root first. The farther one is hit when the ray
+        // starts inside the sphere, e.g. after refracting into it.
+        let sqrt_disc = f64::sqrt(disc);
+        let t = [(-b - sqrt_disc) / (2.0 * a), (-b + sqrt_disc) / (2.0 * a)]
+            .iter()
+            .copied()
+            .find(|&t| t >= MIN_HIT_DISTANCE)?;
+        Some(self.hit_at(ray, t))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let extent = Vec3f::new(self.radius, self.radius, self.radius);
+        Aabb::new(&self.center - &extent, &self.center + &extent)
+    }
+
+    // Samples the cone of directions in which the sphere is visible from
+    // `origin`, or every direction from inside the sphere.
+    fn sample_direction(&self, origin: &Vec3f, sampler: &mut dyn Sampler) -> Option<(Vec3f, f64)> {
+        let to_center = &self.center - origin;
+        let sq_distance = to_center.sq_norm();
+        let (cos_theta_max, w) = if sq_distance <= self.radius * self.radius {
+            (-1.0, Vec3f::new(0.0, 0.0, 1.0))
         } else {
-            None
+            let cos_theta_max = (1.0 - self.radius * self.radius / sq_distance).sqrt();
+            (cos_theta_max, to_center.normalize())
+        };
+
+        let (u1, u2) = sampler.next_2d();
+        let cos_theta = 1.0 - u1 * (1.0 - cos_theta_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * std::f64::consts::PI * u2;
+        let (u, v) = orthonormal_basis(&w);
+        let direction = u * (sin_theta * phi.cos()) + v * (sin_theta * phi.sin()) + w * cos_theta;
+        Some((direction, cone_pdf(cos_theta_max)))
+    }
+
+    fn direction_pdf(&self, origin: &Vec3f, direction: &Vec3f) -> f64 {
+        let to_center = &self.center - origin;
+        let sq_distance = to_center.sq_norm();
+        if sq_distance <= self.radius * self.radius {
+            return cone_pdf(-1.0);
+        }
+        let cos_theta_max = (1.0 - self.radius * self.radius / sq_distance).sqrt();
+        let cos_theta = to_center.dot_ref(direction) / (sq_distance.sqrt() * direction.norm());
+        if cos_theta < cos_theta_max {
+            return 0.0;
+        }
+        cone_pdf(cos_theta_max)
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let to_origin = &ray.origin - &self.center;
+        let a = ray.dir.sq_norm();
+        let half_b = ray.dir.dot_ref(&to_origin);
+        let c = to_origin.sq_norm() - self.radius * self.radius;
+        let disc = half_b * half_b - a * c;
+        if disc <= 0.0 {
+            return vec![];
+        }
+        let sqrt_disc = disc.sqrt();
+        vec![Interval {
+            enter: self.hit_at(ray, (-half_b - sqrt_disc) / a),
+            exit: self.hit_at(ray, (-half_b + sqrt_disc) / a),
+        }]
+    }
+}
+
+// The direction in which the angle of `point` around the y axis grows, which
+// is the first texture coordinate of spheres and solids of revolution.
+pub fn around_y_tangent(point: &Vec3f) -> Vec3f {
+    Vec3f::new(point.z, 0.0, -point.x)
+}
+
+// Texture coordinates of a point on the unit sphere: u goes around the y axis
+// starting from -x, v goes from the bottom (y = -1) to the top (y = 1).
+pub fn sphere_uv(point: &Vec3f) -> (f64, f64) {
+    let theta = (-point.y).clamp(-1.0, 1.0).acos();
+    let phi = (-point.z).atan2(point.x) + std::f64::consts::PI;
+    (
+        phi / (2.0 * std::f64::consts::PI),
+        theta / std::f64::consts::PI,
+    )
+}
+
+// The density of directions picked uniformly in a cone.
+fn cone_pdf(cos_theta_max: f64) -> f64 {
+    1.0 / (2.0 * std::f64::consts::PI * (1.0 - cos_theta_max))
+}
+
+// A shape moved, rotated and scaled into place. The wrapped shape is shared,
+// so the same mesh can be instanced any number of times while being stored
+// once.
+pub struct Transformed {
+    pub shape: Arc<dyn Shape + Send + Sync>,
+    // From the space the shape is defined in to world space.
+    pub transform: Transform,
+}
+
+impl Transformed {
+    pub fn new(shape: Arc<dyn Shape + Send + Sync>, transform: Transform) -> Self {
+        Transformed { shape, transform }
+    }
+
+    fn to_world(&self, mut hit: Hit) -> Hit {
+        // Normals keep the side they face through the inverse transpose.
+        hit.normal = self.transform.apply_normal(&hit.normal).normalize();
+        hit.tangent = hit
+            .tangent
+            .map(|tangent| self.transform.apply_vector(&tangent));
+        hit
+    }
+
+    // Converts the density of the unit direction `local` in object space to
+    // a density over world space directions. A linear map A stretches solid
+    // angles around `local` by |A local|^3 / |det A|, here with A the
+    // object-to-world transform.
+    fn to_world_pdf(&self, local: &Vec3f, pdf: f64) -> f64 {
+        let stretch = self.transform.apply_vector(local).norm().powi(3);
+        pdf * stretch / self.transform.determinant().abs()
+    }
+}
+
+impl Shape for Transformed {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        // The direction isn't normalized in object space, so hits are at the
+        // same t in both spaces.
+        let local_ray = self.transform.inverse().apply_ray(ray);
+        let hit = self.shape.intersect(&local_ray)?;
+        Some(self.to_world(hit))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let local = self.shape.bounding_box();
+        if !local.is_finite() {
+            return local;
+        }
+        (0..8).fold(Aabb::empty(), |bounds, corner| {
+            let point = Vec3f::new(
+                if corner & 1 == 0 {
+                    local.min.x
+                } else {
+                    local.max.x
+                },
+                if corner & 2 == 0 {
+                    local.min.y
+                } else {
+                    local.max.y
+                },
+                if corner & 4 == 0 {
+                    local.min.z
+                } else {
+                    local.max.z
+                },
+            );
+            bounds.grow(&self.transform.apply_point(&point))
+        })
+    }
+
+    fn sample_direction(&self, origin: &Vec3f, sampler: &mut dyn Sampler) -> Option<(Vec3f, f64)> {
+        let local_origin = self.transform.inverse().apply_point(origin);
+        let (local, pdf) = self.shape.sample_direction(&local_origin, sampler)?;
+        let local = local.normalize();
+        let pdf = self.to_world_pdf(&local, pdf);
+        Some((self.transform.apply_vector(&local).normalize(), pdf))
+    }
+
+    fn direction_pdf(&self, origin: &Vec3f, direction: &Vec3f) -> f64 {
+        let inverse = self.transform.inverse();
+        let local = inverse.apply_vector(direction).normalize();
+        let pdf = self
+            .shape
+            .direction_pdf(&inverse.apply_point(origin), &local);
+        self.to_world_pdf(&local, pdf)
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let local_ray = self.transform.inverse().apply_ray(ray);
+        self.shape
+            .intervals(&local_ray)
+            .into_iter()
+            .map(|interval| Interval {
+                enter: self.to_world(interval.enter),
+                exit: self.to_world(interval.exit),
+            })
+            .collect()
+    }
+
+    fn is_medium(&self) -> bool {
+        self.shape.is_medium()
+    }
+
+    fn intersect_medium(&self, ray: &Ray, rng: &mut Rng) -> Option<Hit> {
+        let local_ray = self.transform.inverse().apply_ray(ray);
+        let hit = self.shape.intersect_medium(&local_ray, rng)?;
+        Some(self.to_world(hit))
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: f64, rng: &mut Rng) -> f64 {
+        let local_ray = self.transform.inverse().apply_ray(ray);
+        self.shape.transmittance(&local_ray, t_max, rng)
+    }
+}
+
+// What a ray sees when it escapes the scene without hitting anything.
+#[derive(Clone, Debug)]
+pub enum Background {
+    // A vertical gradient from `horizon` (looking straight down) to `zenith`
+    // (looking straight up).
+    Gradient { horizon: Vec3f, zenith: Vec3f },
+    // The same color in every direction. Black makes the objects of the scene
+    // the only light sources.
+    Solid(Vec3f),
+}
+
+impl Default for Background {
+    // The light blue sky of Ray Tracing in One Weekend.
+    fn default() -> Self {
+        Background::Gradient {
+            horizon: Vec3f::new(1.0, 1.0, 1.0),
+            zenith: Vec3f::new(0.5, 0.7, 1.0),
+        }
+    }
+}
+
+impl Background {
+    pub fn color(&self, ray: &Ray) -> Vec3f {
+        match self {
+            Background::Gradient { horizon, zenith } => {
+                let t = 0.5 * (ray.dir.normalize().y + 1.0);
+                horizon * (1.0 - t) + zenith * t
+            }
+            Background::Solid(color) => color.clone(),
         }
     }
 }
 
 pub struct World {
     pub objects: Vec<Object>,
+    pub background: Background,
+    bvh: Bvh,
+    // Indices of the emissive objects. Those whose shape can't be sampled are
+    // only ever found by scattered rays.
+    pub lights: Vec<usize>,
+    // Indices of the objects that are participating media.
+    media: Vec<usize>,
 }
 
 impl World {
-    pub fn color_at(&self, ray: &Ray) -> Vec3f {
-        let mut j = 0;
-        let mut r = ray.clone();
-        let mut color = Vec3f::new(1.0, 1.0, 1.0);
-        while j < RAY_BOUNCE_DEPTH {
-            if let Some((t, norm, object)) = self.intersect(&r) {
-                if t <= 0.001 {
-                    break;
-                }
-                let pair = object.material.scatter(&r, &norm.normalize(), t);
-                let atten = pair.0;
-                r = pair.1;
-                color = color * atten;
-                // mul_factor *= 0.5;
-                j += 1;
-            } else {
-                break;
-            }
+    pub fn new(objects: Vec<Object>) -> Self {
+        let bvh = Bvh::new(objects.iter().map(|o| o.shape.bounding_box()).collect());
+        let lights = (0..objects.len())
+            .filter(|&i| objects[i].material.is_emissive())
+            .collect();
+        let media = (0..objects.len())
+            .filter(|&i| objects[i].shape.is_medium())
+            .collect();
+        World {
+            objects,
+            background: Background::default(),
+            bvh,
+            lights,
+            media,
         }
+    }
 
-        if j == RAY_BOUNCE_DEPTH {
-            return Vec3f::new(0.0, 0.0, 0.0);
+    // The closest surface along the ray or point where a medium scatters it.
+    pub fn intersect(&self, ray: &Ray, sampler: &mut dyn Sampler) -> Option<(Hit, &Object)> {
+        let seed = self.media_seed(sampler);
+        self.bvh
+            .intersect(ray, |index| {
+                let shape = &self.objects[index].shape;
+                let hit = if shape.is_medium() {
+                    shape.intersect_medium(ray, &mut medium_rng(seed, index))
+                } else {
+                    shape.intersect(ray)
+                };
+                hit.map(|hit| (hit.t, hit))
+            })
+            .map(|(index, _, hit)| (hit, &self.objects[index]))
+    }
+
+    // The closest surface along the ray, going through media.
+    pub fn intersect_surface(&self, ray: &Ray) -> Option<(Hit, &Object)> {
+        self.bvh
+            .intersect(ray, |index| {
+                self.objects[index]
+                    .shape
+                    .intersect(ray)
+                    .map(|hit| (hit.t, hit))
+            })
+            .map(|(index, _, hit)| (hit, &self.objects[index]))
+    }
+
+    // The fraction of the light travelling along `ray` that makes it through
+    // all the media up to distance `t_max`, or an unbiased estimate of it.
+    pub fn transmittance(&self, ray: &Ray, t_max: f64, sampler: &mut dyn Sampler) -> f64 {
+        let seed = self.media_seed(sampler);
+        self.media
+            .iter()
+            .map(|&i| {
+                self.objects[i]
+                    .shape
+                    .transmittance(ray, t_max, &mut medium_rng(seed, i))
+            })
+            .product()
+    }
+
+    // Tracking rays through media takes however many random numbers it
+    // takes, so rather than drawing them from the sampler, which would throw
+    // its later dimensions out of step from one sample to the next, each
+    // query draws a single dimension to seed the generators of the media.
+    // Scenes without media don't draw anything.
+    fn media_seed(&self, sampler: &mut dyn Sampler) -> u64 {
+        if self.media.is_empty() {
+            0
+        } else {
+            sampler.next_1d().to_bits()
+        }
+    }
+}
+
+// Every medium gets a generator of its own, so that those overlapping along a
+// ray scatter it independently of each other.
+fn medium_rng(seed: u64, index: usize) -> Rng {
+    Rng::new(hash(&[seed, index as u64]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::furnace::{constant, Furnace};
+
+    #[test]
+    fn white_diffuse_conserves_energy() {
+        let material = DiffuseMaterial {
+            color: constant(1.0),
+        };
+        Furnace {
+            albedo: Some(1.0),
+            has_bsdf: true,
+            two_sided: false,
+        }
+        .check(&material);
+    }
+
+    #[test]
+    fn fuzzy_metal_conserves_energy() {
+        let material = MetalMaterial {
+            attenuation: constant(1.0),
+            fuzz: 0.3,
+        };
+        Furnace {
+            albedo: None,
+            has_bsdf: false,
+            two_sided: false,
         }
+        .check(&material);
+    }
 
-        let norm_ray_vec = ray.dir.normalize();
-        let t = 0.5 * (norm_ray_vec.y + 1.0);
-        let base = Vec3f::new(1.0, 1.0, 1.0) * (1.0 - t) + Vec3f::new(0.5, 0.7, 1.0) * t;
-        base * color
+    #[test]
+    fn clear_dielectric_conserves_energy() {
+        let material = DielectricMaterial {
+            ior: 1.5,
+            absorption: Vec3f::new(0.0, 0.0, 0.0),
+        };
+        Furnace {
+            albedo: Some(1.0),
+            has_bsdf: false,
+            two_sided: true,
+        }
+        .check(&material);
     }
 
-    pub fn intersect(&self, ray: &Ray) -> Option<(f64, Vec3f, &Object)> {
-        self.objects.iter().fold(None, |acc, obj| {
-            if let Some((t1, n1)) = obj.shape.intersect(&ray) {
-                if t1 < 0.001 {
-                    acc
-                } else if let Some((t2, n2, obj2)) = acc {
-                    if t2 < t1 {
-                        Some((t2, n2, obj2))
-                    } else {
-                        Some((t1, n1, obj))
-                    }
-                } else {
-                    Some((t1, n1, obj))
-                }
+    #[test]
+    fn diffuse_light_scatters_nothing() {
+        let material = DiffuseLight {
+            color: constant(1.0),
+            intensity: 1.0,
+        };
+        Furnace {
+            albedo: Some(0.0),
+            has_bsdf: false,
+            two_sided: false,
+        }
+        .check(&material);
+    }
+
+    fn random_point(rng: &mut Rng, size: f64) -> Vec3f {
+        Vec3f::new(
+            size * (2.0 * rng.next_f64() - 1.0),
+            size * (2.0 * rng.next_f64() - 1.0),
+            size * (2.0 * rng.next_f64() - 1.0),
+        )
+    }
+
+    #[test]
+    fn transformed_sphere_matches_a_world_sphere() {
+        let center = Vec3f::new(1.0, 2.0, 3.0);
+        let transformed = Transformed::new(
+            Arc::new(Sphere {
+                center: Vec3f::new(0.0, 0.0, 0.0),
+                radius: 1.0,
+            }),
+            Transform::scale(&Vec3f::new(2.0, 2.0, 2.0))
+                .then(&Transform::rotate(&Vec3f::new(1.0, 2.0, 0.5), 40.0))
+                .then(&Transform::translate(&center)),
+        );
+        let sphere = Sphere {
+            center: center.clone(),
+            radius: 2.0,
+        };
+
+        let mut rng = Rng::new(3);
+        let mut hits = 0;
+        for i in 0..1000 {
+            // Rays from outside aimed around the sphere, and from inside.
+            let origin = if i % 2 == 0 {
+                &center + &random_point(&mut rng, 6.0)
             } else {
-                acc
+                &center + &random_point(&mut rng, 1.0)
+            };
+            let target = &center + &random_point(&mut rng, 2.5);
+            let ray = Ray::from_pts(origin, target);
+            let expected = sphere.intersect(&ray);
+            let hit = transformed.intersect(&ray);
+            assert_eq!(hit.is_some(), expected.is_some(), "{:?}", ray);
+            if let (Some(hit), Some(expected)) = (hit, expected) {
+                assert!((hit.t - expected.t).abs() < 1e-9);
+                assert!((&hit.normal - &expected.normal).norm() < 1e-9);
+                assert_eq!(hit.front_face, expected.front_face);
+                hits += 1;
             }
-        })
+        }
+        assert!(hits > 500);
+    }
+
+    #[test]
+    fn scaled_sphere_normals_are_perpendicular_to_the_surface() {
+        let ellipsoid = Transformed::new(
+            Arc::new(Sphere {
+                center: Vec3f::new(0.0, 0.0, 0.0),
+                radius: 1.0,
+            }),
+            Transform::scale(&Vec3f::new(3.0, 1.0, 0.25))
+                .then(&Transform::rotate(&Vec3f::new(0.0, 1.0, 1.0), 50.0))
+                .then(&Transform::translate(&Vec3f::new(0.0, 1.0, 0.0))),
+        );
+        let mut rng = Rng::new(4);
+        let mut hits = 0;
+        for _ in 0..500 {
+            let origin = random_point(&mut rng, 8.0);
+            let target = &Vec3f::new(0.0, 1.0, 0.0) + &random_point(&mut rng, 0.5);
+            let Some(hit) = ellipsoid.intersect(&Ray::from_pts(origin.clone(), target.clone()))
+            else {
+                continue;
+            };
+            hits += 1;
+            assert!((hit.normal.norm() - 1.0).abs() < 1e-12);
+            // Nearby points of the surface lie in the plane of the normal.
+            let point = Ray::from_pts(origin.clone(), target.clone()).interpolate(hit.t);
+            for offset in [Vec3f::new(1e-5, 0.0, 0.0), Vec3f::new(0.0, 0.0, 1e-5)].iter() {
+                let ray = Ray::from_pts(origin.clone(), &target + offset);
+                let Some(nearby) = ellipsoid.intersect(&ray) else {
+                    continue;
+                };
+                let step = &ray.interpolate(nearby.t) - &point;
+                if step.norm() > 0.0 {
+                    assert!(hit.normal.dot_ref(&step.normalize()).abs() < 1e-3);
+                }
+            }
+        }
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn u_grows_along_the_tangent() {
+        use crate::mesh::{Triangle, TriangleMesh};
+        use crate::primitive::{Cuboid, Disk, Quad};
+        use crate::quadric::{Quadric, Torus};
+        use crate::sdf::{SdfShape, SdfSphere};
+
+        let unit_sphere = || {
+            Arc::new(Sphere {
+                center: Vec3f::new(0.0, 0.0, 0.0),
+                radius: 1.0,
+            })
+        };
+        let triangle = Triangle {
+            v0: Vec3f::new(0.0, 0.0, 0.0),
+            v1: Vec3f::new(2.0, 0.0, 0.0),
+            v2: Vec3f::new(0.0, 2.0, 0.0),
+        };
+        // Texture coordinates turned a quarter turn from the positions.
+        let mesh = TriangleMesh::new(
+            vec![
+                triangle.v0.clone(),
+                triangle.v1.clone(),
+                triangle.v2.clone(),
+            ],
+            None,
+            Some(vec![(0.0, 0.0), (0.0, 1.0), (-1.0, 0.0)]),
+            vec![[0, 1, 2]],
+        );
+        type SharedShape = Arc<dyn Shape + Send + Sync>;
+        // Each shape, with a ray from `origin` to a point of it.
+        let cases: Vec<(&str, SharedShape, [f64; 3], [f64; 3])> = vec![
+            ("sphere", unit_sphere(), [3.0, 0.3, 0.5], [0.0, 0.0, 0.0]),
+            (
+                "rotated sphere",
+                Arc::new(Transformed::new(
+                    unit_sphere(),
+                    Transform::rotate(&Vec3f::new(1.0, 0.0, 0.0), 90.0),
+                )),
+                [3.0, 0.3, 0.5],
+                [0.0, 0.0, 0.0],
+            ),
+            (
+                "quad",
+                Arc::new(Quad {
+                    corner: Vec3f::new(0.0, 0.0, 0.0),
+                    u: Vec3f::new(1.0, 0.5, 0.0),
+                    v: Vec3f::new(0.0, 0.0, 1.0),
+                }),
+                [0.5, -3.0, 0.5],
+                [0.5, 0.25, 0.5],
+            ),
+            (
+                "disk",
+                Arc::new(Disk {
+                    center: Vec3f::new(0.0, 0.0, 0.0),
+                    normal: Vec3f::new(0.0, 1.0, 0.0),
+                    radius: 1.0,
+                }),
+                [0.3, 2.0, 0.4],
+                [0.3, 0.0, 0.4],
+            ),
+            (
+                "box",
+                Arc::new(Cuboid::new(
+                    Vec3f::new(-1.0, -1.0, -1.0),
+                    Vec3f::new(1.0, 1.0, 1.0),
+                )),
+                [0.2, 0.3, 5.0],
+                [0.2, 0.3, 1.0],
+            ),
+            (
+                "triangle",
+                Arc::new(triangle),
+                [0.5, 0.5, 2.0],
+                [0.5, 0.5, 0.0],
+            ),
+            ("mesh", Arc::new(mesh), [0.5, 0.5, 2.0], [0.5, 0.5, 0.0]),
+            (
+                "cylinder",
+                Arc::new(Quadric::cylinder(1.0, 2.0, true)),
+                [3.0, 1.0, 0.5],
+                [0.0, 1.0, 0.0],
+            ),
+            (
+                "cylinder cap",
+                Arc::new(Quadric::cylinder(1.0, 2.0, true)),
+                [0.3, 5.0, 0.4],
+                [0.3, 0.0, 0.4],
+            ),
+            (
+                "torus",
+                Arc::new(Torus {
+                    major_radius: 1.0,
+                    minor_radius: 0.25,
+                }),
+                [1.0, 3.0, 0.2],
+                [1.0, 0.0, 0.2],
+            ),
+            (
+                "sdf",
+                Arc::new(SdfShape::new(Arc::new(SdfSphere { radius: 1.0 }))),
+                [3.0, 0.3, 0.5],
+                [0.0, 0.0, 0.0],
+            ),
+        ];
+
+        for (name, shape, origin, target) in cases.iter() {
+            let origin = Vec3f::new(origin[0], origin[1], origin[2]);
+            let target = Vec3f::new(target[0], target[1], target[2]);
+            let ray = Ray::from_pts(origin.clone(), target);
+            let hit = shape.intersect(&ray).unwrap();
+            let tangent = hit.tangent.clone().unwrap().normalize();
+            assert!(tangent.dot_ref(&hit.normal).abs() < 1e-3, "{}", name);
+
+            // Aim a little further along the tangent.
+            let point = &ray.interpolate(hit.t) + &(&tangent * 1e-3);
+            let nearby = shape.intersect(&Ray::from_pts(origin, point)).unwrap();
+            let du = nearby.uv.0 - hit.uv.0;
+            let dv = nearby.uv.1 - hit.uv.1;
+            assert!(du > 1e-5, "{}: u changed by {}", name, du);
+            assert!(dv.abs() < 0.1 * du, "{}: v changed by {}", name, dv);
+        }
     }
 }
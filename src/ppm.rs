@@ -45,6 +45,7 @@ impl PPM {
         let f = OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(file_name)?;
         let mut writer = BufWriter::new(f);
         write!(
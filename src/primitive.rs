@@ -0,0 +1,335 @@
+use crate::bvh::Aabb;
+use crate::object::{orthonormal_basis, Hit, Interval, Shape, MIN_HIT_DISTANCE};
+use crate::sampler::Sampler;
+use crate::sampling::uniform_disk;
+use crate::vector::{Ray, Vec3f};
+use std::f64::consts::PI;
+
+// Where `ray` crosses the plane through `point` with normal `normal`, as a
+// distance along the ray. None if the ray is parallel to the plane.
+fn intersect_plane(ray: &Ray, point: &Vec3f, normal: &Vec3f) -> Option<f64> {
+    let denominator = normal.dot_ref(&ray.dir);
+    if denominator.abs() <= f64::EPSILON * normal.norm() * ray.dir.norm() {
+        return None;
+    }
+    Some(normal.dot_ref(&(point - &ray.origin)) / denominator)
+}
+
+// Converts the density of a point picked uniformly on a flat surface of the
+// given area to a density over the directions from `origin`, given where the
+// ray towards that point hits (`t`) and the normal there.
+fn area_to_solid_angle(t: f64, direction: &Vec3f, normal: &Vec3f, area: f64) -> f64 {
+    let distance = t * direction.norm();
+    let cos_theta = (normal.dot_ref(direction) / (normal.norm() * direction.norm())).abs();
+    if t <= 0.0 || area * cos_theta == 0.0 {
+        return 0.0;
+    }
+    distance * distance / (area * cos_theta)
+}
+
+// An infinite plane through `point`. Texture coordinates are distances along
+// two axes of the plane, so image textures tile once per unit.
+pub struct Plane {
+    pub point: Vec3f,
+    pub normal: Vec3f,
+}
+
+impl Shape for Plane {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let normal = self.normal.normalize();
+        let t = intersect_plane(ray, &self.point, &normal).filter(|&t| t >= MIN_HIT_DISTANCE)?;
+        let offset = &ray.interpolate(t) - &self.point;
+        let (u, v) = orthonormal_basis(&normal);
+        let uv = (offset.dot_ref(&u), offset.dot_ref(&v));
+        Some(Hit::new(ray, t, normal, uv).with_tangent(u))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::infinite()
+    }
+}
+
+// A parallelogram with a corner at `corner` and sides `u` and `v`. It faces
+// the side from which `u` turns counter-clockwise into `v`, and its texture
+// coordinates go from (0, 0) at `corner` to (1, 1) at the opposite corner.
+// Axis-aligned rectangles are quads whose sides are along two axes.
+pub struct Quad {
+    pub corner: Vec3f,
+    pub u: Vec3f,
+    pub v: Vec3f,
+}
+
+impl Quad {
+    // The distance along `ray` to the quad's plane and the coordinates of the
+    // crossing along `u` and `v`, if it's inside the quad.
+    fn intersect_uv(&self, ray: &Ray) -> Option<(f64, f64, f64)> {
+        let n = self.u.cross(&self.v);
+        let t = intersect_plane(ray, &self.corner, &n)?;
+        let offset = &ray.interpolate(t) - &self.corner;
+        // Solving offset = a * u + b * v with cross products.
+        let w = &n * (1.0 / n.sq_norm());
+        let a = w.dot_ref(&offset.cross(&self.v));
+        let b = w.dot_ref(&self.u.cross(&offset));
+        if (0.0..=1.0).contains(&a) && (0.0..=1.0).contains(&b) {
+            Some((t, a, b))
+        } else {
+            None
+        }
+    }
+
+    fn area(&self) -> f64 {
+        self.u.cross(&self.v).norm()
+    }
+}
+
+impl Shape for Quad {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (t, a, b) = self
+            .intersect_uv(ray)
+            .filter(|&(t, _, _)| t >= MIN_HIT_DISTANCE)?;
+        let normal = self.u.cross(&self.v).normalize();
+        Some(Hit::new(ray, t, normal, (a, b)).with_tangent(self.u.clone()))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let opposite = &(&self.corner + &self.u) + &self.v;
+        Aabb::empty()
+            .grow(&self.corner)
+            .grow(&(&self.corner + &self.u))
+            .grow(&(&self.corner + &self.v))
+            .grow(&opposite)
+    }
+
+    // Picks a uniformly distributed point on the quad.
+    fn sample_direction(&self, origin: &Vec3f, sampler: &mut dyn Sampler) -> Option<(Vec3f, f64)> {
+        let (a, b) = sampler.next_2d();
+        let point = &self.corner + &(&self.u * a + &self.v * b);
+        let direction = (&point - origin).normalize();
+        let pdf = self.direction_pdf(origin, &direction);
+        if pdf > 0.0 {
+            Some((direction, pdf))
+        } else {
+            None
+        }
+    }
+
+    fn direction_pdf(&self, origin: &Vec3f, direction: &Vec3f) -> f64 {
+        let ray = Ray {
+            origin: origin.clone(),
+            dir: direction.clone(),
+        };
+        match self.intersect_uv(&ray) {
+            Some((t, _, _)) => {
+                area_to_solid_angle(t, direction, &self.u.cross(&self.v), self.area())
+            }
+            None => 0.0,
+        }
+    }
+}
+
+// An axis-aligned box between the corners `min` and `max`, made of six quads
+// facing outwards. Each face has its own texture coordinates.
+pub struct Cuboid {
+    pub min: Vec3f,
+    pub max: Vec3f,
+    pub faces: Vec<Quad>,
+}
+
+impl Cuboid {
+    pub fn new(min: Vec3f, max: Vec3f) -> Self {
+        let d = &max - &min;
+        let dx = Vec3f::new(d.x, 0.0, 0.0);
+        let dy = Vec3f::new(0.0, d.y, 0.0);
+        let dz = Vec3f::new(0.0, 0.0, d.z);
+        let quad = |corner: Vec3f, u: &Vec3f, v: &Vec3f| Quad {
+            corner,
+            u: u.clone(),
+            v: v.clone(),
+        };
+        let faces = vec![
+            quad(min.clone(), &dz, &dy),
+            quad(Vec3f::new(max.x, min.y, min.z), &dy, &dz),
+            quad(min.clone(), &dx, &dz),
+            quad(Vec3f::new(min.x, max.y, min.z), &dz, &dx),
+            quad(min.clone(), &dy, &dx),
+            quad(Vec3f::new(min.x, min.y, max.z), &dx, &dy),
+        ];
+        Cuboid { min, max, faces }
+    }
+
+    fn area(&self) -> f64 {
+        self.faces.iter().map(|face| face.area()).sum()
+    }
+}
+
+impl Shape for Cuboid {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        self.faces
+            .iter()
+            .filter_map(|face| face.intersect(ray))
+            .min_by(|a, b| a.t.total_cmp(&b.t))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::new(self.min.clone(), self.max.clone())
+    }
+
+    // Picks a face with a probability proportional to its area, then a point
+    // on it. Points on the far side of the box are hidden by the near side,
+    // so the density of a direction adds up the faces it passes through.
+    fn sample_direction(&self, origin: &Vec3f, sampler: &mut dyn Sampler) -> Option<(Vec3f, f64)> {
+        let mut pick = sampler.next_1d() * self.area();
+        let face = self
+            .faces
+            .iter()
+            .find(|face| {
+                pick -= face.area();
+                pick < 0.0
+            })
+            .unwrap_or(&self.faces[self.faces.len() - 1]);
+        let (direction, _) = face.sample_direction(origin, sampler)?;
+        let pdf = self.direction_pdf(origin, &direction);
+        if pdf > 0.0 {
+            Some((direction, pdf))
+        } else {
+            None
+        }
+    }
+
+    fn direction_pdf(&self, origin: &Vec3f, direction: &Vec3f) -> f64 {
+        let area = self.area();
+        if area == 0.0 {
+            return 0.0;
+        }
+        self.faces
+            .iter()
+            .map(|face| face.area() / area * face.direction_pdf(origin, direction))
+            .sum()
+    }
+
+    // Every line through a box crosses it at most once, between the last of
+    // the three planes it enters through and the first it leaves through.
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let mut enter = (f64::NEG_INFINITY, 0);
+        let mut exit = (f64::INFINITY, 0);
+        for axis in 0..3 {
+            let (o, d) = (ray.origin[axis], ray.dir[axis]);
+            if d == 0.0 {
+                if o < self.min[axis] || o > self.max[axis] {
+                    return vec![];
+                }
+                continue;
+            }
+            let (mut near, mut far) = ((self.min[axis] - o) / d, (self.max[axis] - o) / d);
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            if near > enter.0 {
+                enter = (near, axis);
+            }
+            if far < exit.0 {
+                exit = (far, axis);
+            }
+        }
+        if enter.0 >= exit.0 || !enter.0.is_finite() || !exit.0.is_finite() {
+            return vec![];
+        }
+        // The face a ray enters through faces against it, and the one it
+        // leaves through faces along it.
+        let face_hit = |(t, axis): (f64, usize), sign: f64| {
+            let mut normal = Vec3f::new(0.0, 0.0, 0.0);
+            let towards = if ray.dir[axis] > 0.0 { sign } else { -sign };
+            match axis {
+                0 => normal.x = towards,
+                1 => normal.y = towards,
+                _ => normal.z = towards,
+            }
+            let face = &self.faces[2 * axis + if towards > 0.0 { 1 } else { 0 }];
+            let offset = &ray.interpolate(t) - &face.corner;
+            let uv = (
+                offset.dot_ref(&face.u) / face.u.sq_norm(),
+                offset.dot_ref(&face.v) / face.v.sq_norm(),
+            );
+            Hit::new(ray, t, normal, uv).with_tangent(face.u.clone())
+        };
+        vec![Interval {
+            enter: face_hit(enter, -1.0),
+            exit: face_hit(exit, 1.0),
+        }]
+    }
+}
+
+// A flat disk around `center`. Texture coordinates are polar: u goes once
+// around the center and v from the center (0) to the rim (1).
+pub struct Disk {
+    pub center: Vec3f,
+    pub normal: Vec3f,
+    pub radius: f64,
+}
+
+impl Disk {
+    // The distance along `ray` to the disk and the hit point relative to the
+    // center, if the ray passes through the disk.
+    fn intersect_offset(&self, ray: &Ray) -> Option<(f64, Vec3f)> {
+        let t = intersect_plane(ray, &self.center, &self.normal)?;
+        let offset = &ray.interpolate(t) - &self.center;
+        if offset.sq_norm() <= self.radius * self.radius {
+            Some((t, offset))
+        } else {
+            None
+        }
+    }
+}
+
+impl Shape for Disk {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (t, offset) = self
+            .intersect_offset(ray)
+            .filter(|(t, _)| *t >= MIN_HIT_DISTANCE)?;
+        let normal = self.normal.normalize();
+        let (a, b) = orthonormal_basis(&normal);
+        let uv = (
+            offset.dot_ref(&b).atan2(offset.dot_ref(&a)) / (2.0 * PI) + 0.5,
+            offset.norm() / self.radius,
+        );
+        // Turning from `a` towards `b`.
+        let tangent = &b * offset.dot_ref(&a) - &a * offset.dot_ref(&b);
+        Some(Hit::new(ray, t, normal, uv).with_tangent(tangent))
+    }
+
+    // Along each axis, the disk reaches as far as the radius times the sine
+    // of the angle between the axis and the normal.
+    fn bounding_box(&self) -> Aabb {
+        let n = self.normal.normalize();
+        let extent = |c: f64| self.radius * (1.0 - c * c).max(0.0).sqrt();
+        let extent = Vec3f::new(extent(n.x), extent(n.y), extent(n.z));
+        Aabb::new(&self.center - &extent, &self.center + &extent)
+    }
+
+    // Picks a uniformly distributed point on the disk.
+    fn sample_direction(&self, origin: &Vec3f, sampler: &mut dyn Sampler) -> Option<(Vec3f, f64)> {
+        let (a, b) = orthonormal_basis(&self.normal.normalize());
+        let (x, y) = uniform_disk(sampler.next_2d());
+        let point = &self.center + &(a * (self.radius * x) + b * (self.radius * y));
+        let direction = (&point - origin).normalize();
+        let pdf = self.direction_pdf(origin, &direction);
+        if pdf > 0.0 {
+            Some((direction, pdf))
+        } else {
+            None
+        }
+    }
+
+    fn direction_pdf(&self, origin: &Vec3f, direction: &Vec3f) -> f64 {
+        let ray = Ray {
+            origin: origin.clone(),
+            dir: direction.clone(),
+        };
+        match self.intersect_offset(&ray) {
+            Some((t, _)) => {
+                area_to_solid_angle(t, direction, &self.normal, PI * self.radius * self.radius)
+            }
+            None => 0.0,
+        }
+    }
+}
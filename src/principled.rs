@@ -0,0 +1,343 @@
+use crate::microfacet::{dielectric_fresnel, reflect_about, refract_about, Frame, Ggx};
+use crate::object::{texture_at, Hit, Material};
+use crate::sampler::Sampler;
+use crate::sampling::{cosine_hemisphere, cosine_hemisphere_pdf};
+use crate::texture::Texture;
+use crate::vector::{Ray, Vec3f};
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// One material for most surfaces, after Disney's principled BSDF: a single
+// set of intuitive parameters, each between 0 and 1 unless stated otherwise,
+// blends between plastic, metal, glass, cloth and lacquered finishes.
+//
+// - `base_color`: the color of diffuse surfaces, of the reflections of metals
+//   and of light going through glass.
+// - `metallic`: from a dielectric, with white reflections over a colored
+//   diffuse base, to a metal, with colored reflections and no diffuse light.
+// - `roughness`: from mirror-like to completely blurry reflections.
+// - `specular`: the strength of the reflections of dielectrics, 0.5 being
+//   that of most of them (a 4% reflectance head-on).
+// - `sheen`: the soft glow at grazing angles of cloth and velvet.
+// - `clearcoat`: the strength of a second, white, specular layer on top, as
+//   on car paint, with its own `clearcoat_roughness`.
+// - `transmission`: from opaque to glass-like, letting light through
+//   according to `ior`, the index of refraction (about 1.5).
+//
+// Every parameter is a texture. Colors are used as they are; the others are
+// the average of the channels of theirs.
+pub struct PrincipledMaterial {
+    pub base_color: Arc<dyn Texture + Send + Sync>,
+    pub metallic: Arc<dyn Texture + Send + Sync>,
+    pub roughness: Arc<dyn Texture + Send + Sync>,
+    pub specular: Arc<dyn Texture + Send + Sync>,
+    pub sheen: Arc<dyn Texture + Send + Sync>,
+    pub clearcoat: Arc<dyn Texture + Send + Sync>,
+    pub clearcoat_roughness: Arc<dyn Texture + Send + Sync>,
+    pub transmission: Arc<dyn Texture + Send + Sync>,
+    pub ior: Arc<dyn Texture + Send + Sync>,
+}
+
+// The parameters of a `PrincipledMaterial` at a hit point, along with the
+// shading frame there.
+struct Parameters {
+    frame: Frame,
+    base_color: Vec3f,
+    metallic: f64,
+    specular: f64,
+    sheen: f64,
+    clearcoat: f64,
+    transmission: f64,
+    // The index of refraction of the side the ray goes into over that of the
+    // side it comes from.
+    eta: f64,
+    ggx: Ggx,
+    clearcoat_ggx: Ggx,
+}
+
+fn luminance(color: &Vec3f) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+fn schlick_weight(cos_theta: f64) -> f64 {
+    (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5)
+}
+
+// Schlick's approximation of the Fresnel reflectance, from the reflectance
+// `f0` head-on.
+fn schlick(f0: &Vec3f, cos_theta: f64) -> Vec3f {
+    let w = schlick_weight(cos_theta);
+    f0 * (1.0 - w) + Vec3f::new(w, w, w)
+}
+
+impl Parameters {
+    // The head-on reflectance of dielectrics: 0.08 times `specular`.
+    fn dielectric_f0(&self) -> f64 {
+        0.08 * self.specular
+    }
+
+    // The reflectance of the main specular lobe: colored like the base by
+    // metals, white and weak for opaque dielectrics. Transparent dielectrics
+    // reflect through the glass lobe instead.
+    fn specular_fresnel(&self, cos_theta: f64) -> Vec3f {
+        let f0 = self.dielectric_f0();
+        let dielectric = schlick(&Vec3f::new(f0, f0, f0), cos_theta)
+            * ((1.0 - self.metallic) * (1.0 - self.transmission));
+        schlick(&self.base_color, cos_theta) * self.metallic + dielectric
+    }
+
+    fn clearcoat_fresnel(&self, cos_theta: f64) -> f64 {
+        self.clearcoat * (0.04 + 0.96 * schlick_weight(cos_theta))
+    }
+
+    // The probabilities of sampling the diffuse and sheen, specular, glass
+    // and clearcoat lobes, roughly in proportion to how much light each
+    // reflects towards `wo`.
+    fn lobe_probabilities(&self, wo: &Vec3f) -> [f64; 4] {
+        let dielectric = (1.0 - self.metallic) * (1.0 - self.transmission);
+        let weights = [
+            dielectric * (luminance(&self.base_color) + self.sheen),
+            luminance(&self.specular_fresnel(wo.z)),
+            (1.0 - self.metallic) * self.transmission,
+            self.clearcoat_fresnel(wo.z),
+        ];
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return [0.0; 4];
+        }
+        weights.map(|weight| weight / total)
+    }
+
+    // The BSDF times the cosine, for directions in the local frame.
+    fn eval(&self, wo: &Vec3f, wi: &Vec3f) -> Vec3f {
+        let mut result = Vec3f::new(0.0, 0.0, 0.0);
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return result;
+        }
+        // The clearcoat keeps the light it reflects from the layers below.
+        let under_clearcoat = 1.0 - self.clearcoat_fresnel(wo.z);
+
+        if wi.z < 0.0 {
+            // Only glass lets light through, tinted by the base color.
+            let glass = (1.0 - self.metallic) * self.transmission;
+            let Some(h) = self.refraction_half_vector(wo, wi) else {
+                return result;
+            };
+            let (cos_o, cos_i) = (wo.dot_ref(&h), wi.dot_ref(&h));
+            let fresnel = dielectric_fresnel(cos_o, self.eta);
+            let denominator = cos_o + self.eta * cos_i;
+            let bsdf = (1.0 - fresnel) * self.ggx.d(&h) * self.ggx.g(wo, wi) * cos_o * -cos_i
+                / (wo.z * denominator * denominator);
+            // Like `DielectricMaterial`, radiance isn't scaled by the square
+            // of the ratio of the indices on the way through.
+            return &self.base_color * (glass * bsdf * self.eta * self.eta * under_clearcoat);
+        }
+
+        let h = (wo + wi).normalize();
+        let (cos_o, cos_i, cos_h) = (wo.z, wi.z, wo.dot_ref(&h));
+        let dielectric = (1.0 - self.metallic) * (1.0 - self.transmission);
+
+        // Ashikhmin and Shirley's diffuse term, which only takes the light
+        // that the specular reflection of dielectrics leaves.
+        let rs = self.dielectric_f0();
+        let diffuse = 28.0 / (23.0 * PI)
+            * (1.0 - rs)
+            * (1.0 - schlick_weight(0.5 * cos_i))
+            * (1.0 - schlick_weight(0.5 * cos_o));
+        let sheen = self.sheen * schlick_weight(wi.dot_ref(&h));
+        result = result
+            + (&self.base_color * diffuse + Vec3f::new(sheen, sheen, sheen)) * (dielectric * cos_i);
+
+        // D * G * F / (4 cos(wo) cos(wi)), times cos(wi), for each lobe.
+        let specular = self.ggx.d(&h) * self.ggx.g(wo, wi) / (4.0 * cos_o);
+        result = result + self.specular_fresnel(cos_h) * specular;
+        let glass = (1.0 - self.metallic) * self.transmission;
+        let fresnel = dielectric_fresnel(cos_h, self.eta);
+        result = result + Vec3f::new(1.0, 1.0, 1.0) * (glass * fresnel * specular);
+        result = result * under_clearcoat;
+
+        let clearcoat = self.clearcoat_ggx.d(&h) * self.clearcoat_ggx.g(wo, wi) / (4.0 * cos_o);
+        let clearcoat = self.clearcoat_fresnel(cos_h) * clearcoat;
+        result + Vec3f::new(clearcoat, clearcoat, clearcoat)
+    }
+
+    // The microfacet normal that refracts `wo` into `wi`, facing `wo`, if
+    // there is one.
+    fn refraction_half_vector(&self, wo: &Vec3f, wi: &Vec3f) -> Option<Vec3f> {
+        let h = (wo + &(wi * self.eta)).normalize();
+        let h = if h.z < 0.0 { h * -1.0 } else { h };
+        if wo.dot_ref(&h) <= 0.0 || wi.dot_ref(&h) >= 0.0 {
+            return None;
+        }
+        Some(h)
+    }
+
+    fn pdf(&self, wo: &Vec3f, wi: &Vec3f) -> f64 {
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return 0.0;
+        }
+        let [p_diffuse, p_specular, p_glass, p_clearcoat] = self.lobe_probabilities(wo);
+        if wi.z < 0.0 {
+            let Some(h) = self.refraction_half_vector(wo, wi) else {
+                return 0.0;
+            };
+            let (cos_o, cos_i) = (wo.dot_ref(&h), wi.dot_ref(&h));
+            let fresnel = dielectric_fresnel(cos_o, self.eta);
+            // From the density of h to that of the refracted direction.
+            let denominator = cos_o + self.eta * cos_i;
+            let jacobian = self.eta * self.eta * -cos_i / (denominator * denominator);
+            return p_glass * (1.0 - fresnel) * self.ggx.visible_pdf(wo, &h) * jacobian;
+        }
+
+        let h = (wo + wi).normalize();
+        let reflection = |ggx: &Ggx| ggx.visible_pdf(wo, &h) / (4.0 * wo.dot_ref(&h));
+        let fresnel = dielectric_fresnel(wo.dot_ref(&h), self.eta);
+        p_diffuse * cosine_hemisphere_pdf(wi.z)
+            + (p_specular + p_glass * fresnel) * reflection(&self.ggx)
+            + p_clearcoat * reflection(&self.clearcoat_ggx)
+    }
+
+    // Picks a direction from one of the lobes. Microfacets can reflect light
+    // below the surface, or refract it back out above, where `eval` and `pdf`
+    // would take it for the other kind of scattering: those rays are lost.
+    // Every lobe draws the same dimensions from `sampler`, needed or not.
+    fn sample(&self, wo: &Vec3f, sampler: &mut dyn Sampler) -> Option<Vec3f> {
+        let [p_diffuse, p_specular, p_glass, _] = self.lobe_probabilities(wo);
+        let pick = sampler.next_1d();
+        let u = sampler.next_2d();
+        let reflect_or_refract = sampler.next_1d();
+        let reflected = |h: &Vec3f| Some(reflect_about(wo, h)).filter(|wi| wi.z > 0.0);
+        if pick < p_diffuse {
+            Some(cosine_hemisphere(u))
+        } else if pick < p_diffuse + p_specular {
+            reflected(&self.ggx.sample_visible(wo, u))
+        } else if pick < p_diffuse + p_specular + p_glass {
+            let h = self.ggx.sample_visible(wo, u);
+            let fresnel = dielectric_fresnel(wo.dot_ref(&h), self.eta);
+            if reflect_or_refract < fresnel {
+                reflected(&h)
+            } else {
+                refract_about(wo, &h, self.eta).filter(|wi| wi.z < 0.0)
+            }
+        } else {
+            reflected(&self.clearcoat_ggx.sample_visible(wo, u))
+        }
+    }
+}
+
+impl PrincipledMaterial {
+    fn parameters(&self, ray: &Ray, hit: &Hit) -> Parameters {
+        let scalar = |texture: &Arc<dyn Texture + Send + Sync>| {
+            let value = texture_at(texture.as_ref(), ray, hit);
+            (value.x + value.y + value.z) / 3.0
+        };
+        let unit = |texture: &Arc<dyn Texture + Send + Sync>| scalar(texture).clamp(0.0, 1.0);
+        let roughness = unit(&self.roughness);
+        let clearcoat_roughness = unit(&self.clearcoat_roughness);
+        let ior = scalar(&self.ior).max(1.0);
+        Parameters {
+            frame: Frame::new(&hit.normal),
+            base_color: texture_at(self.base_color.as_ref(), ray, hit),
+            metallic: unit(&self.metallic),
+            specular: unit(&self.specular),
+            sheen: unit(&self.sheen),
+            clearcoat: unit(&self.clearcoat),
+            transmission: unit(&self.transmission),
+            eta: if hit.front_face { ior } else { 1.0 / ior },
+            ggx: Ggx::from_roughness(roughness, roughness),
+            clearcoat_ggx: Ggx::from_roughness(clearcoat_roughness, clearcoat_roughness),
+        }
+    }
+}
+
+// Every lobe is a continuous distribution: even perfectly smooth surfaces are
+// given a tiny roughness, so that sampling lights works the same way for all
+// combinations of lobes.
+impl Material for PrincipledMaterial {
+    fn scatter(&self, ray: &Ray, hit: &Hit, sampler: &mut dyn Sampler) -> Option<(Vec3f, Ray)> {
+        let parameters = self.parameters(ray, hit);
+        let wo = parameters.frame.to_local(&(&ray.dir * -1.0).normalize());
+        let wi = parameters.sample(&wo, sampler)?;
+        let pdf = parameters.pdf(&wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((
+            parameters.eval(&wo, &wi) * (1.0 / pdf),
+            Ray {
+                origin: ray.interpolate(hit.t),
+                dir: parameters.frame.to_world(&wi),
+            },
+        ))
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, direction: &Vec3f) -> Vec3f {
+        let parameters = self.parameters(ray, hit);
+        let wo = parameters.frame.to_local(&(&ray.dir * -1.0).normalize());
+        let wi = parameters.frame.to_local(&direction.normalize());
+        parameters.eval(&wo, &wi)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &Hit, direction: &Vec3f) -> f64 {
+        let parameters = self.parameters(ray, hit);
+        let wo = parameters.frame.to_local(&(&ray.dir * -1.0).normalize());
+        let wi = parameters.frame.to_local(&direction.normalize());
+        parameters.pdf(&wo, &wi)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::furnace::{constant, Furnace};
+    use crate::texture::ConstantTexture;
+
+    fn principled(
+        metallic: f64,
+        sheen: f64,
+        clearcoat: f64,
+        transmission: f64,
+    ) -> PrincipledMaterial {
+        PrincipledMaterial {
+            base_color: Arc::new(ConstantTexture {
+                color: Vec3f::new(0.9, 0.6, 0.3),
+            }),
+            metallic: constant(metallic),
+            roughness: constant(0.5),
+            specular: constant(0.5),
+            sheen: constant(sheen),
+            clearcoat: constant(clearcoat),
+            clearcoat_roughness: constant(0.5),
+            transmission: constant(transmission),
+            ior: constant(1.5),
+        }
+    }
+
+    fn furnace(two_sided: bool) -> Furnace {
+        Furnace {
+            albedo: None,
+            has_bsdf: true,
+            two_sided,
+        }
+    }
+
+    #[test]
+    fn principled_dielectric_conserves_energy() {
+        furnace(false).check(&principled(0.0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn principled_metal_conserves_energy() {
+        furnace(false).check(&principled(1.0, 0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn principled_glass_conserves_energy() {
+        furnace(true).check(&principled(0.0, 0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn principled_sheen_and_clearcoat_conserve_energy() {
+        furnace(false).check(&principled(0.0, 1.0, 1.0, 0.0));
+    }
+}
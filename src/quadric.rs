@@ -0,0 +1,421 @@
+use crate::bvh::Aabb;
+use crate::object::{around_y_tangent, pair_crossings, Hit, Interval, Shape, MIN_HIT_DISTANCE};
+use crate::vector::{Ray, Vec3f};
+use std::f64::consts::PI;
+
+// Bisection steps when polishing a root, enough to reach full precision on
+// any interval of f64s.
+const ROOT_ITERATIONS: usize = 100;
+
+// Evaluates the polynomial whose coefficient for x^i is coefficients[i].
+fn evaluate(coefficients: &[f64], x: f64) -> f64 {
+    coefficients.iter().rev().fold(0.0, |sum, c| sum * x + c)
+}
+
+// The real roots of a polynomial in [lo, hi], in increasing order. The roots
+// of the derivative split the interval into pieces on which the polynomial is
+// monotonic, so each piece holds at most one root, which bisection then finds
+// reliably. Unlike closed-form solutions for cubics and quartics, this doesn't
+// lose roots to cancellation when they're close together.
+pub fn polynomial_roots(coefficients: &[f64], lo: f64, hi: f64) -> Vec<f64> {
+    // Leading zero coefficients would make the recursion divide by zero.
+    let degree = match coefficients.iter().rposition(|&c| c != 0.0) {
+        Some(degree) => degree,
+        None => return vec![],
+    };
+    let coefficients = &coefficients[..=degree];
+    if degree == 0 {
+        return vec![];
+    }
+    if degree == 1 {
+        let root = -coefficients[0] / coefficients[1];
+        return if (lo..=hi).contains(&root) {
+            vec![root]
+        } else {
+            vec![]
+        };
+    }
+
+    let derivative: Vec<f64> = coefficients
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, c)| i as f64 * c)
+        .collect();
+    let mut bounds = vec![lo];
+    bounds.extend(polynomial_roots(&derivative, lo, hi));
+    bounds.push(hi);
+
+    let mut roots = vec![];
+    for pair in bounds.windows(2) {
+        let (mut a, mut b) = (pair[0], pair[1]);
+        let (fa, fb) = (evaluate(coefficients, a), evaluate(coefficients, b));
+        if fa == 0.0 {
+            // Shared endpoints would otherwise give the same root twice.
+            if roots.last() != Some(&a) {
+                roots.push(a);
+            }
+            continue;
+        }
+        if fa.signum() == fb.signum() {
+            continue;
+        }
+        let rising = fb > fa;
+        for _ in 0..ROOT_ITERATIONS {
+            let mid = 0.5 * (a + b);
+            if mid <= a || mid >= b {
+                break;
+            }
+            if (evaluate(coefficients, mid) < 0.0) == rising {
+                a = mid;
+            } else {
+                b = mid;
+            }
+        }
+        roots.push(0.5 * (a + b));
+    }
+    if evaluate(coefficients, hi) == 0.0 && roots.last() != Some(&hi) {
+        roots.push(hi);
+    }
+    roots
+}
+
+// Where a ray crosses a surface, as a distance along the ray, the outward
+// normal (not normalized) and the texture coordinates.
+type Crossing = (f64, Vec3f, (f64, f64));
+
+// The closest crossing at least MIN_HIT_DISTANCE along the ray.
+fn closest_hit(ray: &Ray, mut crossings: Vec<Crossing>) -> Option<Hit> {
+    crossings.retain(|(t, _, _)| *t >= MIN_HIT_DISTANCE);
+    let (t, normal, uv) = crossings.into_iter().min_by(|a, b| a.0.total_cmp(&b.0))?;
+    Some(crossing_hit(ray, t, normal, uv))
+}
+
+fn crossing_intervals(ray: &Ray, crossings: Vec<Crossing>) -> Vec<Interval> {
+    pair_crossings(
+        crossings
+            .into_iter()
+            .map(|(t, normal, uv)| crossing_hit(ray, t, normal, uv))
+            .collect(),
+    )
+}
+
+// Every shape here has u going around the y axis, on its sides and caps alike.
+fn crossing_hit(ray: &Ray, t: f64, normal: Vec3f, uv: (f64, f64)) -> Hit {
+    let tangent = around_y_tangent(&ray.interpolate(t));
+    Hit::new(ray, t, normal.normalize(), uv).with_tangent(tangent)
+}
+
+// The angle of a point around the y axis, as a texture coordinate in [0, 1]
+// that starts from -x like `sphere_uv`.
+fn around_y(point: &Vec3f) -> f64 {
+    ((-point.z).atan2(point.x) + PI) / (2.0 * PI)
+}
+
+// A solid of revolution around the y axis between y = 0 and y = `height`,
+// whose squared radius at height y is a + b * y + c * y^2. That covers
+// cylinders, cones, paraboloids and hyperboloids of one sheet, which are
+// built by the constructors below; use a transform to put them in place.
+//
+// With `capped`, the ends are closed by disks. On the side, u goes around the
+// axis and v from the bottom to the top; on the caps, u goes around and v
+// from the center to the rim.
+pub struct Quadric {
+    pub a: f64,
+    pub b: f64,
+    pub c: f64,
+    pub height: f64,
+    pub capped: bool,
+}
+
+impl Quadric {
+    pub fn cylinder(radius: f64, height: f64, capped: bool) -> Self {
+        Quadric {
+            a: radius * radius,
+            b: 0.0,
+            c: 0.0,
+            height,
+            capped,
+        }
+    }
+
+    // A cone with its base at y = 0 and its tip at y = `height`.
+    pub fn cone(radius: f64, height: f64, capped: bool) -> Self {
+        // radius^2 * (1 - y / height)^2
+        let k = radius / height;
+        Quadric {
+            a: radius * radius,
+            b: -2.0 * k * radius,
+            c: k * k,
+            height,
+            capped,
+        }
+    }
+
+    // A paraboloid with its tip at the origin, opening upwards to `radius`
+    // at y = `height`.
+    pub fn paraboloid(radius: f64, height: f64, capped: bool) -> Self {
+        Quadric {
+            a: 0.0,
+            b: radius * radius / height,
+            c: 0.0,
+            height,
+            capped,
+        }
+    }
+
+    // A hyperboloid of one sheet, `waist_radius` wide in the middle and
+    // `radius` wide at both ends.
+    pub fn hyperboloid(waist_radius: f64, radius: f64, height: f64, capped: bool) -> Self {
+        // waist^2 + (radius^2 - waist^2) * (2 * y / height - 1)^2
+        let spread = radius * radius - waist_radius * waist_radius;
+        let s = 2.0 / height;
+        Quadric {
+            a: waist_radius * waist_radius + spread,
+            b: -2.0 * s * spread,
+            c: s * s * spread,
+            height,
+            capped,
+        }
+    }
+
+    fn sq_radius(&self, y: f64) -> f64 {
+        self.a + self.b * y + self.c * y * y
+    }
+
+    // Every crossing of the ray with the surface, in no particular order.
+    fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        let (o, d) = (&ray.origin, &ray.dir);
+        // Substituting the ray into x^2 + z^2 = a + b * y + c * y^2.
+        let qa = d.x * d.x + d.z * d.z - self.c * d.y * d.y;
+        let qb = 2.0 * (o.x * d.x + o.z * d.z) - self.b * d.y - 2.0 * self.c * o.y * d.y;
+        let qc = o.x * o.x + o.z * o.z - self.sq_radius(o.y);
+
+        let mut crossings = vec![];
+        for t in solve_quadratic(qa, qb, qc) {
+            let p = ray.interpolate(t);
+            if (0.0..=self.height).contains(&p.y) {
+                // The gradient of x^2 + z^2 - r(y)^2 points outwards.
+                let normal = Vec3f::new(p.x, -0.5 * (self.b + 2.0 * self.c * p.y), p.z);
+                crossings.push((t, normal, (around_y(&p), p.y / self.height)));
+            }
+        }
+
+        if self.capped && d.y != 0.0 {
+            for (y, normal_y) in [(0.0, -1.0), (self.height, 1.0)] {
+                let sq_radius = self.sq_radius(y);
+                let t = (y - o.y) / d.y;
+                let p = ray.interpolate(t);
+                let sq_distance = p.x * p.x + p.z * p.z;
+                if sq_radius > 0.0 && sq_distance <= sq_radius {
+                    let uv = (around_y(&p), (sq_distance / sq_radius).sqrt());
+                    crossings.push((t, Vec3f::new(0.0, normal_y, 0.0), uv));
+                }
+            }
+        }
+        crossings
+    }
+}
+
+// The real roots of a * t^2 + b * t + c, computed without the cancellation
+// of the textbook formula. Falls back to the linear equation when a is 0.
+fn solve_quadratic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    if a == 0.0 {
+        return if b == 0.0 { vec![] } else { vec![-c / b] };
+    }
+    let disc = b * b - 4.0 * a * c;
+    if disc < 0.0 {
+        return vec![];
+    }
+    let q = -0.5 * (b + b.signum() * disc.sqrt());
+    if q == 0.0 {
+        // b and c are both 0.
+        return vec![0.0];
+    }
+    vec![q / a, c / q]
+}
+
+impl Shape for Quadric {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        closest_hit(ray, self.crossings(ray))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        // The radius is largest at one of the ends, or at the vertex of the
+        // parabola r(y)^2 when it opens downwards.
+        let mut sq_radius = self.sq_radius(0.0).max(self.sq_radius(self.height));
+        if self.c < 0.0 {
+            let vertex = (-self.b / (2.0 * self.c)).clamp(0.0, self.height);
+            sq_radius = sq_radius.max(self.sq_radius(vertex));
+        }
+        let radius = sq_radius.max(0.0).sqrt();
+        Aabb::new(
+            Vec3f::new(-radius, 0.0, -radius),
+            Vec3f::new(radius, self.height, radius),
+        )
+    }
+
+    // Without its caps, the surface doesn't enclose anything.
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        if self.capped {
+            crossing_intervals(ray, self.crossings(ray))
+        } else {
+            vec![]
+        }
+    }
+}
+
+// A torus around the y axis, centered at the origin: the surface swept by a
+// circle of radius `minor_radius` whose center goes around a circle of radius
+// `major_radius`. u goes around the y axis and v around the tube, starting
+// from its outer edge.
+pub struct Torus {
+    pub major_radius: f64,
+    pub minor_radius: f64,
+}
+
+impl Torus {
+    fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        let (major, minor) = (self.major_radius, self.minor_radius);
+        // Solve along a unit direction starting where the ray enters the
+        // torus' bounding sphere. Keeping the coefficients small and the roots
+        // close to 0 keeps the quartic well conditioned.
+        let scale = ray.dir.norm();
+        let d = &ray.dir * (1.0 / scale);
+        let bound = major + minor;
+        let b = ray.origin.dot_ref(&d);
+        let disc = b * b - (ray.origin.sq_norm() - bound * bound);
+        if disc < 0.0 {
+            return vec![];
+        }
+        let (enter, exit) = (-b - disc.sqrt(), -b + disc.sqrt());
+        let o = &ray.origin + &(&d * enter);
+
+        // Substituting o + t * d into (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + z^2).
+        let e = o.dot_ref(&d);
+        let k = o.sq_norm() + major * major - minor * minor;
+        let four_r2 = 4.0 * major * major;
+        let coefficients = [
+            k * k - four_r2 * (o.x * o.x + o.z * o.z),
+            4.0 * e * k - 2.0 * four_r2 * (o.x * d.x + o.z * d.z),
+            4.0 * e * e + 2.0 * k - four_r2 * (d.x * d.x + d.z * d.z),
+            4.0 * e,
+            1.0,
+        ];
+
+        polynomial_roots(&coefficients, 0.0, exit - enter)
+            .into_iter()
+            .map(|s| {
+                let t = (s + enter) / scale;
+                let p = ray.interpolate(t);
+                // The normal points away from the closest point on the
+                // circle running through the middle of the tube.
+                let around = Vec3f::new(p.x, 0.0, p.z);
+                let ring = around.normalize() * major;
+                let normal = &p - &ring;
+                let v = p.y.atan2(around.norm() - major) / (2.0 * PI);
+                (t, normal, (around_y(&p), v.rem_euclid(1.0)))
+            })
+            .collect()
+    }
+}
+
+impl Shape for Torus {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        closest_hit(ray, self.crossings(ray))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let extent = self.major_radius + self.minor_radius;
+        Aabb::new(
+            Vec3f::new(-extent, -self.minor_radius, -extent),
+            Vec3f::new(extent, self.minor_radius, extent),
+        )
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        crossing_intervals(ray, self.crossings(ray))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_roots(coefficients: &[f64], expected: &[f64]) {
+        let roots = polynomial_roots(coefficients, -10.0, 10.0);
+        assert_eq!(roots.len(), expected.len(), "roots {:?}", roots);
+        for (root, expected) in roots.iter().zip(expected) {
+            assert!((root - expected).abs() < 1e-12, "roots {:?}", roots);
+        }
+    }
+
+    #[test]
+    fn quartic_with_four_roots() {
+        // (x - 1)(x - 2)(x - 3)(x - 4)
+        assert_roots(&[24.0, -50.0, 35.0, -10.0, 1.0], &[1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn quartic_with_two_roots() {
+        // (x + 2)(x - 1)(x^2 + 1)
+        assert_roots(&[-2.0, 1.0, -1.0, 1.0, 1.0], &[-2.0, 1.0]);
+    }
+
+    #[test]
+    fn quartic_with_no_roots() {
+        // (x^2 + 1)(x^2 + 4)
+        assert_roots(&[4.0, 0.0, 5.0, 0.0, 1.0], &[]);
+    }
+
+    fn torus() -> Torus {
+        Torus {
+            major_radius: 1.0,
+            minor_radius: 0.25,
+        }
+    }
+
+    fn ray_along_x(y: f64) -> Ray {
+        Ray {
+            origin: Vec3f::new(-3.0, y, 0.0),
+            dir: Vec3f::new(1.0, 0.0, 0.0),
+        }
+    }
+
+    #[test]
+    fn torus_hit_head_on() {
+        // Through both sides of the tube, across the hole.
+        let ray = ray_along_x(0.0);
+        let hit = torus().intersect(&ray).unwrap();
+        assert!((hit.t - 1.75).abs() < 1e-9, "t = {}", hit.t);
+        assert!((&hit.normal - &Vec3f::new(-1.0, 0.0, 0.0)).norm() < 1e-9);
+        let intervals: Vec<(f64, f64)> = torus()
+            .intervals(&ray)
+            .iter()
+            .map(|interval| (interval.enter.t, interval.exit.t))
+            .collect();
+        assert_eq!(intervals.len(), 2, "intervals {:?}", intervals);
+        for (interval, expected) in intervals.iter().zip([(1.75, 2.25), (3.75, 4.25)]) {
+            assert!(
+                (interval.0 - expected.0).abs() < 1e-9 && (interval.1 - expected.1).abs() < 1e-9,
+                "intervals {:?}",
+                intervals
+            );
+        }
+    }
+
+    #[test]
+    fn torus_hit_tangentially() {
+        // Rays skimming the top of the tube, where it touches y = 0.25 at
+        // x = -1 and x = 1. Just below, they cut through it near there; just
+        // above, they miss it.
+        let hit = torus().intersect(&ray_along_x(0.25 - 1e-6)).unwrap();
+        assert!((hit.t - 2.0).abs() < 1e-3, "t = {}", hit.t);
+        assert!(torus().intersect(&ray_along_x(0.25 + 1e-6)).is_none());
+        // Exactly tangent, either is fine, but a hit must be at the tangent
+        // point.
+        if let Some(hit) = torus().intersect(&ray_along_x(0.25)) {
+            assert!((hit.t - 2.0).abs() < 1e-3, "t = {}", hit.t);
+        }
+    }
+}
@@ -0,0 +1,466 @@
+use std::sync::Arc;
+
+// Generators of the random numbers that rendering a sample of a pixel takes:
+// where in the pixel the camera ray goes through, where on the lens it
+// starts, which light is sampled and where at every bounce, which way the
+// ray scatters, and so on. Each of these draws the next dimension (or two)
+// of the sample, always in the same order, so that with samplers that spread
+// the samples of a pixel evenly, the values of every dimension cover [0, 1)
+// much more evenly than independent random numbers do, and images converge
+// faster.
+//
+// Every sampler is seeded: the numbers it gives depend only on the seed, the
+// pixel, the index of the sample and the dimension.
+
+// The largest f64 below 1.
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+pub trait Sampler {
+    // Moves on to the `index`-th sample of `pixel`, given as (column, row),
+    // starting again from the first dimension.
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize);
+
+    // The next dimension of the current sample, in [0, 1).
+    fn next_1d(&mut self) -> f64;
+
+    // The next two dimensions of the current sample, spread out evenly over
+    // the unit square together, not just each on its own.
+    fn next_2d(&mut self) -> (f64, f64);
+
+    // A sampler with the same settings, for another thread to use.
+    fn clone_sampler(&self) -> Box<dyn Sampler>;
+}
+
+pub const SAMPLER_NAMES: &str = "sobol, halton, stratified and independent";
+
+// Picks a sampler by the name it's given on the command line. Stratified
+// sampling needs to know how many samples each pixel gets.
+pub fn from_name(
+    name: &str,
+    samples_per_pixel: usize,
+    seed: u64,
+) -> Option<Arc<dyn Sampler + Send + Sync>> {
+    match name {
+        "independent" => Some(Arc::new(IndependentSampler::new(seed))),
+        "stratified" => Some(Arc::new(StratifiedSampler::new(samples_per_pixel, seed))),
+        "halton" => Some(Arc::new(HaltonSampler::new(seed))),
+        "sobol" => Some(Arc::new(SobolSampler::new(seed))),
+        _ => None,
+    }
+}
+
+// Scrambles the bits of `value` so that each of them affects all the others,
+// after Stafford's variant 13 of the finalizer of MurmurHash3.
+fn mix_bits(mut value: u64) -> u64 {
+    value ^= value >> 31;
+    value = value.wrapping_mul(0x7fb5_d329_728e_a185);
+    value ^= value >> 27;
+    value = value.wrapping_mul(0x81da_def4_bc2d_d44d);
+    value ^= value >> 33;
+    value
+}
+
+// A hash of a few numbers, to seed the randomness of one sample or
+// dimension.
+pub fn hash(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e37_79b9_7f4a_7c15, |hash, &value| {
+        mix_bits(hash ^ mix_bits(value.wrapping_add(0x6a09_e667_f3bc_c909)))
+    })
+}
+
+// A small and fast pseudorandom number generator (SplitMix64), for the
+// numbers that don't need to be spread out evenly.
+#[derive(Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix_bits(self.state)
+    }
+
+    // A number in [0, 1), all equally likely.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+}
+
+// The position of `i` in a random permutation of 0..`n` picked by `seed`,
+// without building the permutation, after Kensler's "Correlated Multi-Jittered
+// Sampling": a hash that is one-to-one over the next power of two, repeated
+// until it lands below `n`.
+fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut mask = n.wrapping_sub(1);
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < n {
+            return (i.wrapping_add(seed)) % n;
+        }
+    }
+}
+
+// Independent uniform random numbers for every dimension: the simplest
+// sampler, and the slowest to converge.
+#[derive(Clone)]
+pub struct IndependentSampler {
+    pub seed: u64,
+    rng: Rng,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        IndependentSampler {
+            seed,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.rng = Rng::new(hash(&[
+            self.seed,
+            pixel.0 as u64,
+            pixel.1 as u64,
+            index as u64,
+        ]));
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        self.rng.next_f64()
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        (self.rng.next_f64(), self.rng.next_f64())
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+// Jittered sampling: every dimension is split into as many strata as there
+// are samples per pixel, and each sample of the pixel gets a random point in
+// a different stratum. Pairs of dimensions are split into a grid of cells as
+// close to square as the number of samples allows. Which sample gets which
+// stratum is shuffled differently for every dimension of every pixel, so
+// that dimensions don't follow one another.
+#[derive(Clone)]
+pub struct StratifiedSampler {
+    pub samples_per_pixel: usize,
+    pub seed: u64,
+    // The grid of cells for pairs of dimensions.
+    columns: usize,
+    rows: usize,
+    pixel: (usize, usize),
+    index: usize,
+    dimension: usize,
+    rng: Rng,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: usize, seed: u64) -> Self {
+        let samples_per_pixel = samples_per_pixel.max(1);
+        let columns = (1..=samples_per_pixel)
+            .take_while(|columns| columns * columns <= samples_per_pixel)
+            .filter(|&columns| samples_per_pixel.is_multiple_of(columns))
+            .last()
+            .unwrap_or(1);
+        StratifiedSampler {
+            samples_per_pixel,
+            seed,
+            columns,
+            rows: samples_per_pixel / columns,
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+            rng: Rng::new(seed),
+        }
+    }
+
+    // The stratum of the current sample in the current dimension.
+    fn stratum(&self) -> usize {
+        let seed = hash(&[
+            self.seed,
+            self.pixel.0 as u64,
+            self.pixel.1 as u64,
+            self.dimension as u64,
+        ]);
+        permutation_element(
+            (self.index % self.samples_per_pixel) as u32,
+            self.samples_per_pixel as u32,
+            seed as u32,
+        ) as usize
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+        self.rng = Rng::new(hash(&[
+            self.seed,
+            pixel.0 as u64,
+            pixel.1 as u64,
+            index as u64,
+        ]));
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let stratum = self.stratum();
+        self.dimension += 1;
+        ((stratum as f64 + self.rng.next_f64()) / self.samples_per_pixel as f64)
+            .min(ONE_MINUS_EPSILON)
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let stratum = self.stratum();
+        self.dimension += 2;
+        let (column, row) = (stratum % self.columns, stratum / self.columns);
+        (
+            ((column as f64 + self.rng.next_f64()) / self.columns as f64).min(ONE_MINUS_EPSILON),
+            ((row as f64 + self.rng.next_f64()) / self.rows as f64).min(ONE_MINUS_EPSILON),
+        )
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+const PRIMES: [u64; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// The digits of `index` in `base`, mirrored around the decimal point, with
+// every digit replaced according to a random permutation of the digits that
+// depends on `seed` and on all the digits before it (Owen scrambling). Even
+// the zeros past the last digit of `index` are scrambled, so that the result
+// is uniformly distributed.
+fn owen_scrambled_radical_inverse(base: u64, mut index: u64, seed: u64) -> f64 {
+    let limit = u64::MAX / base - base;
+    let inverse_base = 1.0 / base as f64;
+    let mut scale = 1.0;
+    let mut reversed_digits = 0u64;
+    while 1.0 - scale < 1.0 && reversed_digits < limit {
+        let digit = index % base;
+        index /= base;
+        let digit_seed = mix_bits(seed ^ reversed_digits) as u32;
+        let digit = permutation_element(digit as u32, base as u32, digit_seed) as u64;
+        reversed_digits = reversed_digits * base + digit;
+        scale *= inverse_base;
+    }
+    (reversed_digits as f64 * scale).min(ONE_MINUS_EPSILON)
+}
+
+// The Halton sequence: dimension `d` of sample `i` is `i` written in the
+// `d`-th prime base and mirrored around the decimal point, so that every
+// dimension fills the gaps left by the previous samples. Each pixel scrambles
+// the digits differently, which keeps the pixels from all getting the same
+// samples and the dimensions in higher bases from being correlated. Paths
+// long enough to run out of primes carry on with independent random numbers.
+#[derive(Clone)]
+pub struct HaltonSampler {
+    pub seed: u64,
+    pixel_seed: u64,
+    index: usize,
+    dimension: usize,
+    rng: Rng,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        HaltonSampler {
+            seed,
+            pixel_seed: seed,
+            index: 0,
+            dimension: 0,
+            rng: Rng::new(seed),
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.pixel_seed = hash(&[self.seed, pixel.0 as u64, pixel.1 as u64]);
+        self.index = index;
+        self.dimension = 0;
+        self.rng = Rng::new(hash(&[self.pixel_seed, index as u64]));
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        match PRIMES.get(dimension) {
+            Some(&base) => owen_scrambled_radical_inverse(
+                base,
+                self.index as u64,
+                hash(&[self.pixel_seed, dimension as u64]),
+            ),
+            None => self.rng.next_f64(),
+        }
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        (self.next_1d(), self.next_1d())
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+// Scrambles the bits of `value` so that each bit only depends on the bits
+// below it, with Laine and Karras's hash, and then on the higher ones by
+// reversing them around it: Burley's hash-based Owen scrambling, for numbers
+// in [0, 1) as fixed point.
+fn nested_uniform_scramble(value: u32, seed: u32) -> u32 {
+    let mut x = value.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+// The first two dimensions of the Sobol sequence, as fixed point: the first
+// is the van der Corput sequence, `index` mirrored around the binary point;
+// the second adds up, for every bit of `index`, a row of Pascal's triangle
+// modulo 2.
+fn sobol(index: u32) -> (u32, u32) {
+    let mut y = 0;
+    let mut direction = 1u32 << 31;
+    let mut i = index;
+    while i != 0 {
+        if i & 1 != 0 {
+            y ^= direction;
+        }
+        i >>= 1;
+        direction ^= direction >> 1;
+    }
+    (index.reverse_bits(), y)
+}
+
+fn fixed_point_to_f64(value: u32) -> f64 {
+    value as f64 * (1.0 / (1u64 << 32) as f64)
+}
+
+// Owen-scrambled Sobol points: every dimension, or pair of dimensions for
+// `next_2d`, gets its own scrambling of the first one or two dimensions of
+// the Sobol sequence, which are stratified in every power of two of samples,
+// and its own shuffling of the order of the samples (padding, as opposed to
+// using higher dimensions of the sequence, which are worse distributed).
+// Best with a power of two samples per pixel.
+#[derive(Clone)]
+pub struct SobolSampler {
+    pub seed: u64,
+    pixel_seed: u64,
+    index: usize,
+    dimension: usize,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        SobolSampler {
+            seed,
+            pixel_seed: seed,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    // The hash seeding the current dimension, after moving past it.
+    fn dimension_seed(&mut self, count: usize) -> u64 {
+        let seed = hash(&[self.pixel_seed, self.dimension as u64]);
+        self.dimension += count;
+        seed
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.pixel_seed = hash(&[self.seed, pixel.0 as u64, pixel.1 as u64]);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let seed = self.dimension_seed(1);
+        let index = nested_uniform_scramble(self.index as u32, seed as u32);
+        let (x, _) = sobol(index);
+        fixed_point_to_f64(nested_uniform_scramble(x, (seed >> 32) as u32))
+    }
+
+    fn next_2d(&mut self) -> (f64, f64) {
+        let seed = self.dimension_seed(2);
+        let index = nested_uniform_scramble(self.index as u32, seed as u32);
+        let (x, y) = sobol(index);
+        let scramble = mix_bits(seed);
+        (
+            fixed_point_to_f64(nested_uniform_scramble(x, scramble as u32)),
+            fixed_point_to_f64(nested_uniform_scramble(y, (scramble >> 32) as u32)),
+        )
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::furnace::check_sampler;
+
+    #[test]
+    fn independent_sampler() {
+        check_sampler("independent");
+    }
+
+    #[test]
+    fn stratified_sampler() {
+        check_sampler("stratified");
+    }
+
+    #[test]
+    fn halton_sampler() {
+        check_sampler("halton");
+    }
+
+    #[test]
+    fn sobol_sampler() {
+        check_sampler("sobol");
+    }
+}
@@ -0,0 +1,98 @@
+use crate::vector::Vec3f;
+use std::f64::consts::PI;
+
+// Random points and directions with known densities, for Monte Carlo
+// estimates, made from uniformly distributed numbers `u` in [0, 1) given by a
+// `Sampler`. Most functions have a `*_pdf` returning the density they pick
+// their results with. The mappings keep points that are close together in the
+// unit square close together, so that evenly spread samples stay evenly
+// spread. Directions on the hemisphere are around +z, to be turned into a
+// surface's frame.
+
+// A direction on the hemisphere, all equally likely.
+#[cfg(test)]
+pub fn uniform_hemisphere(u: (f64, f64)) -> Vec3f {
+    let z = u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3f::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+#[cfg(test)]
+pub fn uniform_hemisphere_pdf() -> f64 {
+    1.0 / (2.0 * PI)
+}
+
+// A direction on the hemisphere, with a density proportional to the cosine of
+// its angle with +z, like the light a Lambertian surface reflects: a point on
+// the unit disk lifted up onto the hemisphere.
+pub fn cosine_hemisphere(u: (f64, f64)) -> Vec3f {
+    let (x, y) = concentric_disk(u);
+    Vec3f::new(x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+}
+
+pub fn cosine_hemisphere_pdf(cos_theta: f64) -> f64 {
+    cos_theta.max(0.0) / PI
+}
+
+// A direction on the whole sphere, all equally likely.
+pub fn uniform_sphere(u: (f64, f64)) -> Vec3f {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3f::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+#[cfg(test)]
+pub fn uniform_sphere_pdf() -> f64 {
+    1.0 / (4.0 * PI)
+}
+
+// A point on the unit disk, all equally likely, in polar coordinates: the
+// square root keeps the outer rings, which are larger, from being
+// undersampled.
+pub fn uniform_disk(u: (f64, f64)) -> (f64, f64) {
+    let r = u.0.sqrt();
+    let phi = 2.0 * PI * u.1;
+    (r * phi.cos(), r * phi.sin())
+}
+
+// A point on the unit disk, all equally likely, by Shirley and Chiu's
+// mapping of concentric squares onto concentric circles. It distorts areas
+// less than polar coordinates do, so stratified random numbers stay well
+// spread on the disk.
+pub fn concentric_disk(u: (f64, f64)) -> (f64, f64) {
+    let a = 2.0 * u.0 - 1.0;
+    let b = 2.0 * u.1 - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, phi) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    (r * phi.cos(), r * phi.sin())
+}
+
+// Both ways of sampling the unit disk have the same density.
+#[cfg(test)]
+pub fn disk_pdf() -> f64 {
+    1.0 / PI
+}
+
+// A point on a triangle, all equally likely, as its barycentric coordinates
+// with respect to the second and third vertices. Points picked in the
+// parallelogram the triangle is half of are folded back into it.
+pub fn uniform_triangle(u: (f64, f64)) -> (f64, f64) {
+    let (b1, b2) = u;
+    if b1 + b2 > 1.0 {
+        (1.0 - b1, 1.0 - b2)
+    } else {
+        (b1, b2)
+    }
+}
+
+pub fn triangle_pdf(area: f64) -> f64 {
+    1.0 / area
+}
@@ -0,0 +1,1197 @@
+use crate::camera::{Camera, Lens};
+use crate::color::Color;
+use crate::csg::{Csg, CsgOperation};
+use crate::image::read_image;
+use crate::medium::{fog, BlackbodyEmission, ConstantMedium, GridMedium, VolumeMaterial};
+use crate::mesh::Triangle;
+use crate::microfacet::{ConductorMaterial, ALUMINUM, COPPER, GOLD, SILVER};
+use crate::obj::load_obj;
+use crate::object::*;
+use crate::primitive::{Cuboid, Disk, Plane, Quad};
+use crate::principled::PrincipledMaterial;
+use crate::quadric::{Quadric, Torus};
+use crate::sampler::{hash, Rng};
+use crate::sdf::*;
+use crate::texture::*;
+use crate::vector::{Transform, Vec3f, ORIGIN};
+use crate::voxel::{load_voxels, VoxelGrid};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use toml::Spanned;
+
+// Scene files are TOML documents, described in docs/scene-format.md.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    camera: View,
+    #[serde(default)]
+    render: RenderFile,
+    background: Option<BackgroundFile>,
+    fog: Option<FogFile>,
+    #[serde(default)]
+    materials: HashMap<String, Spanned<MaterialFile>>,
+    #[serde(default)]
+    objects: Vec<Spanned<ObjectFile>>,
+}
+
+// Where the camera is and where it's looking.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct View {
+    pub origin: [f64; 3],
+    pub lookat: [f64; 3],
+    #[serde(default = "default_up")]
+    pub up: [f64; 3],
+    pub vfov: f64,
+    #[serde(default)]
+    pub aperture: f64,
+    pub focus_distance: Option<f64>,
+    #[serde(default)]
+    pub autofocus: bool,
+    #[serde(default)]
+    pub blades: usize,
+    #[serde(default)]
+    pub blade_rotation: f64,
+}
+
+fn default_up() -> [f64; 3] {
+    [0.0, -1.0, 0.0]
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RenderFile {
+    width: Option<usize>,
+    height: Option<usize>,
+    samples_per_pixel: Option<usize>,
+    num_threads: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum BackgroundFile {
+    Gradient { horizon: [f64; 3], zenith: [f64; 3] },
+    Solid { color: [f64; 3] },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TextureFile {
+    Color([f64; 3]),
+    Texture(PatternFile),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum PatternFile {
+    Checker {
+        even: Box<TextureFile>,
+        odd: Box<TextureFile>,
+        #[serde(default = "default_scale")]
+        scale: f64,
+    },
+    Noise {
+        #[serde(default = "default_scale")]
+        scale: f64,
+    },
+    Turbulence {
+        #[serde(default = "default_scale")]
+        scale: f64,
+    },
+    Marble {
+        #[serde(default = "default_scale")]
+        scale: f64,
+    },
+    Image {
+        path: String,
+        #[serde(default)]
+        wrap: WrapFile,
+    },
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum WrapFile {
+    #[default]
+    Repeat,
+    Mirror,
+    Clamp,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum MaterialFile {
+    Diffuse {
+        color: TextureFile,
+    },
+    Metal {
+        color: TextureFile,
+        #[serde(default)]
+        fuzz: f64,
+    },
+    Dielectric {
+        ior: f64,
+        #[serde(default)]
+        absorption: [f64; 3],
+    },
+    DiffuseLight {
+        color: TextureFile,
+        #[serde(default = "default_intensity")]
+        intensity: f64,
+    },
+    Volume {
+        color: TextureFile,
+        #[serde(default)]
+        anisotropy: f64,
+    },
+    Conductor {
+        metal: ConductorFile,
+        roughness: Option<RoughnessFile>,
+        tint: Option<TextureFile>,
+    },
+    Principled {
+        base_color: Option<TextureFile>,
+        metallic: Option<ScalarFile>,
+        roughness: Option<ScalarFile>,
+        specular: Option<ScalarFile>,
+        sheen: Option<ScalarFile>,
+        clearcoat: Option<ScalarFile>,
+        clearcoat_roughness: Option<ScalarFile>,
+        transmission: Option<ScalarFile>,
+        ior: Option<ScalarFile>,
+    },
+}
+
+// A number, or a texture whose channels are averaged into one.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScalarFile {
+    Value(f64),
+    Texture(TextureFile),
+}
+
+impl ScalarFile {
+    fn to_texture(
+        scalar: &Option<ScalarFile>,
+        default: f64,
+        directory: &Path,
+        rng: &mut Rng,
+    ) -> Result<Arc<dyn Texture + Send + Sync>, io::Error> {
+        match scalar {
+            Some(ScalarFile::Texture(texture)) => texture.to_texture(directory, rng),
+            Some(ScalarFile::Value(value)) => Ok(constant(*value)),
+            None => Ok(constant(default)),
+        }
+    }
+}
+
+fn constant(value: f64) -> Arc<dyn Texture + Send + Sync> {
+    Arc::new(ConstantTexture {
+        color: Vec3f::new(value, value, value),
+    })
+}
+
+// Either one of the metals we know the index of refraction of, or an index
+// given as its real and imaginary parts.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConductorFile {
+    Preset(MetalFile),
+    Custom { eta: [f64; 3], k: [f64; 3] },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MetalFile {
+    Gold,
+    Copper,
+    Aluminum,
+    Silver,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RoughnessFile {
+    Isotropic(f64),
+    Anisotropic([f64; 2]),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FogFile {
+    density: f64,
+    color: [f64; 3],
+    #[serde(default)]
+    anisotropy: f64,
+}
+
+fn default_intensity() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ObjectFile {
+    shape: ShapeFile,
+    material: Option<Spanned<String>>,
+    #[serde(default)]
+    transform: Vec<TransformFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum TransformFile {
+    Translate([f64; 3]),
+    Rotate {
+        axis: [f64; 3],
+        angle: f64,
+    },
+    Scale(ScaleFile),
+    LookAt {
+        eye: [f64; 3],
+        target: [f64; 3],
+        #[serde(default = "default_look_at_up")]
+        up: [f64; 3],
+    },
+}
+
+fn default_look_at_up() -> [f64; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ScaleFile {
+    Uniform(f64),
+    Axes([f64; 3]),
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ShapeFile {
+    Sphere {
+        center: [f64; 3],
+        radius: f64,
+    },
+    Triangle {
+        vertices: [[f64; 3]; 3],
+    },
+    Mesh {
+        path: String,
+    },
+    Plane {
+        point: [f64; 3],
+        normal: [f64; 3],
+    },
+    Quad {
+        corner: [f64; 3],
+        u: [f64; 3],
+        v: [f64; 3],
+    },
+    Box {
+        min: [f64; 3],
+        max: [f64; 3],
+    },
+    Disk {
+        center: [f64; 3],
+        normal: [f64; 3],
+        radius: f64,
+    },
+    Cylinder {
+        radius: f64,
+        height: f64,
+        #[serde(default = "default_capped")]
+        capped: bool,
+    },
+    Cone {
+        radius: f64,
+        height: f64,
+        #[serde(default = "default_capped")]
+        capped: bool,
+    },
+    Paraboloid {
+        radius: f64,
+        height: f64,
+        #[serde(default = "default_capped")]
+        capped: bool,
+    },
+    Hyperboloid {
+        waist_radius: f64,
+        radius: f64,
+        height: f64,
+        #[serde(default = "default_capped")]
+        capped: bool,
+    },
+    Torus {
+        major_radius: f64,
+        minor_radius: f64,
+    },
+    Union {
+        left: Box<NestedShapeFile>,
+        right: Box<NestedShapeFile>,
+    },
+    Intersection {
+        left: Box<NestedShapeFile>,
+        right: Box<NestedShapeFile>,
+    },
+    Difference {
+        left: Box<NestedShapeFile>,
+        right: Box<NestedShapeFile>,
+    },
+    Medium {
+        boundary: Box<NestedShapeFile>,
+        density: f64,
+    },
+    Grid {
+        path: String,
+        #[serde(default = "default_intensity")]
+        density: f64,
+        emission: Option<f64>,
+    },
+    Sdf {
+        sdf: SdfFile,
+        max_steps: Option<usize>,
+        epsilon: Option<f64>,
+        max_distance: Option<f64>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum SdfFile {
+    Sphere {
+        radius: f64,
+    },
+    Box {
+        size: [f64; 3],
+        #[serde(default)]
+        rounding: f64,
+    },
+    Torus {
+        major_radius: f64,
+        minor_radius: f64,
+    },
+    Cylinder {
+        radius: f64,
+        height: f64,
+    },
+    Translate {
+        offset: [f64; 3],
+        sdf: Box<SdfFile>,
+    },
+    SmoothUnion {
+        left: Box<SdfFile>,
+        right: Box<SdfFile>,
+        #[serde(default)]
+        smoothness: f64,
+    },
+    SmoothSubtract {
+        left: Box<SdfFile>,
+        right: Box<SdfFile>,
+        #[serde(default)]
+        smoothness: f64,
+    },
+    Twist {
+        sdf: Box<SdfFile>,
+        rate: f64,
+    },
+    Repeat {
+        sdf: Box<SdfFile>,
+        period: [f64; 3],
+    },
+    Mandelbulb {
+        #[serde(default = "default_mandelbulb_power")]
+        power: f64,
+        #[serde(default = "default_fractal_iterations")]
+        iterations: usize,
+    },
+    Menger {
+        #[serde(default = "default_fractal_iterations")]
+        iterations: usize,
+    },
+}
+
+fn default_mandelbulb_power() -> f64 {
+    8.0
+}
+
+fn default_fractal_iterations() -> usize {
+    8
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NestedShapeFile {
+    shape: ShapeFile,
+    #[serde(default)]
+    transform: Vec<TransformFile>,
+}
+
+fn default_capped() -> bool {
+    true
+}
+
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: usize,
+    pub num_threads: Option<usize>,
+}
+
+// Everything needed to render an image: the objects to render and the camera
+// and settings to render them with.
+pub struct Scene {
+    pub view: View,
+    pub render: RenderSettings,
+    pub background: Background,
+    pub objects: Vec<Object>,
+}
+
+impl Scene {
+    pub fn camera(&self) -> Camera {
+        Camera::new(
+            self.render.width,
+            self.render.height,
+            vec3(self.view.origin),
+            vec3(self.view.lookat),
+            vec3(self.view.up),
+            self.view.vfov,
+            self.render.samples_per_pixel,
+            Lens {
+                aperture: self.view.aperture,
+                focus_distance: self
+                    .view
+                    .focus_distance
+                    .unwrap_or_else(|| (&vec3(self.view.origin) - &vec3(self.view.lookat)).norm()),
+                blades: self.view.blades,
+                blade_rotation: self.view.blade_rotation,
+            },
+        )
+    }
+}
+
+fn vec3(v: [f64; 3]) -> Vec3f {
+    Vec3f::new(v[0], v[1], v[2])
+}
+
+// Chains the steps of an object's transform, applying the first one first.
+fn to_transform(steps: &[TransformFile]) -> Transform {
+    steps.iter().fold(Transform::identity(), |transform, step| {
+        let step = match step {
+            TransformFile::Translate(offset) => Transform::translate(&vec3(*offset)),
+            TransformFile::Rotate { axis, angle } => Transform::rotate(&vec3(*axis), *angle),
+            TransformFile::Scale(ScaleFile::Uniform(factor)) => {
+                Transform::scale(&Vec3f::new(*factor, *factor, *factor))
+            }
+            TransformFile::Scale(ScaleFile::Axes(factors)) => Transform::scale(&vec3(*factors)),
+            TransformFile::LookAt { eye, target, up } => {
+                Transform::look_at(&vec3(*eye), &vec3(*target), &vec3(*up))
+            }
+        };
+        transform.then(&step)
+    })
+}
+
+// Maps a byte offset into `contents` to a 1-based line number.
+fn line_of(contents: &str, offset: usize) -> usize {
+    contents[..offset.min(contents.len())].matches('\n').count() + 1
+}
+
+fn parse_error(path: &Path, line: usize, message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{}: {}", path.display(), line, message),
+    )
+}
+
+impl TextureFile {
+    // Image paths are relative to `directory`. Noise patterns are seeded from
+    // `rng`.
+    fn to_texture(
+        &self,
+        directory: &Path,
+        rng: &mut Rng,
+    ) -> Result<Arc<dyn Texture + Send + Sync>, io::Error> {
+        let pattern = match self {
+            TextureFile::Color(color) => {
+                return Ok(Arc::new(ConstantTexture {
+                    color: vec3(*color),
+                }))
+            }
+            TextureFile::Texture(pattern) => pattern,
+        };
+        Ok(match pattern {
+            PatternFile::Checker { even, odd, scale } => Arc::new(CheckerTexture {
+                even: even.to_texture(directory, rng)?,
+                odd: odd.to_texture(directory, rng)?,
+                scale: *scale,
+            }),
+            PatternFile::Noise { scale } => {
+                Arc::new(NoiseTexture::new(*scale, NoiseKind::Noise, rng.next_u64()))
+            }
+            PatternFile::Turbulence { scale } => Arc::new(NoiseTexture::new(
+                *scale,
+                NoiseKind::Turbulence,
+                rng.next_u64(),
+            )),
+            PatternFile::Marble { scale } => {
+                Arc::new(NoiseTexture::new(*scale, NoiseKind::Marble, rng.next_u64()))
+            }
+            PatternFile::Image { path, wrap } => Arc::new(ImageTexture {
+                image: read_image(&directory.join(path))?,
+                wrap: match wrap {
+                    WrapFile::Repeat => WrapMode::Repeat,
+                    WrapFile::Mirror => WrapMode::Mirror,
+                    WrapFile::Clamp => WrapMode::Clamp,
+                },
+            }),
+        })
+    }
+}
+
+impl MaterialFile {
+    fn to_material(
+        &self,
+        directory: &Path,
+        rng: &mut Rng,
+    ) -> Result<Arc<dyn Material + Send + Sync>, io::Error> {
+        Ok(match self {
+            MaterialFile::Diffuse { color } => Arc::new(DiffuseMaterial {
+                color: color.to_texture(directory, rng)?,
+            }),
+            MaterialFile::Metal { color, fuzz } => Arc::new(MetalMaterial {
+                attenuation: color.to_texture(directory, rng)?,
+                fuzz: *fuzz,
+            }),
+            MaterialFile::Dielectric { ior, absorption } => Arc::new(DielectricMaterial {
+                ior: *ior,
+                absorption: vec3(*absorption),
+            }),
+            MaterialFile::DiffuseLight { color, intensity } => Arc::new(DiffuseLight {
+                color: color.to_texture(directory, rng)?,
+                intensity: *intensity,
+            }),
+            MaterialFile::Volume { color, anisotropy } => Arc::new(VolumeMaterial {
+                albedo: color.to_texture(directory, rng)?,
+                anisotropy: *anisotropy,
+            }),
+            MaterialFile::Conductor {
+                metal,
+                roughness,
+                tint,
+            } => {
+                let (eta, k) = match metal {
+                    ConductorFile::Preset(MetalFile::Gold) => GOLD,
+                    ConductorFile::Preset(MetalFile::Copper) => COPPER,
+                    ConductorFile::Preset(MetalFile::Aluminum) => ALUMINUM,
+                    ConductorFile::Preset(MetalFile::Silver) => SILVER,
+                    ConductorFile::Custom { eta, k } => (*eta, *k),
+                };
+                let (roughness_x, roughness_y) = match roughness {
+                    Some(RoughnessFile::Isotropic(roughness)) => (*roughness, *roughness),
+                    Some(RoughnessFile::Anisotropic([x, y])) => (*x, *y),
+                    None => (0.0, 0.0),
+                };
+                Arc::new(ConductorMaterial {
+                    eta: vec3(eta),
+                    k: vec3(k),
+                    roughness_x,
+                    roughness_y,
+                    tint: match tint {
+                        Some(tint) => tint.to_texture(directory, rng)?,
+                        None => constant(1.0),
+                    },
+                })
+            }
+            MaterialFile::Principled {
+                base_color,
+                metallic,
+                roughness,
+                specular,
+                sheen,
+                clearcoat,
+                clearcoat_roughness,
+                transmission,
+                ior,
+            } => {
+                let base_color = match base_color {
+                    Some(color) => color.to_texture(directory, rng)?,
+                    None => constant(0.8),
+                };
+                let mut scalar =
+                    |scalar, default| ScalarFile::to_texture(scalar, default, directory, rng);
+                Arc::new(PrincipledMaterial {
+                    base_color,
+                    metallic: scalar(metallic, 0.0)?,
+                    roughness: scalar(roughness, 0.5)?,
+                    specular: scalar(specular, 0.5)?,
+                    sheen: scalar(sheen, 0.0)?,
+                    clearcoat: scalar(clearcoat, 0.0)?,
+                    clearcoat_roughness: scalar(clearcoat_roughness, 0.1)?,
+                    transmission: scalar(transmission, 0.0)?,
+                    ior: scalar(ior, 1.5)?,
+                })
+            }
+        })
+    }
+}
+
+impl ShapeFile {
+    // Builds every shape but meshes, which are made of several objects.
+    fn to_shape(&self) -> Result<Arc<dyn Shape + Send + Sync>, String> {
+        Ok(match self {
+            ShapeFile::Sphere { center, radius } => Arc::new(Sphere {
+                center: vec3(*center),
+                radius: *radius,
+            }),
+            ShapeFile::Triangle { vertices } => Arc::new(Triangle {
+                v0: vec3(vertices[0]),
+                v1: vec3(vertices[1]),
+                v2: vec3(vertices[2]),
+            }),
+            ShapeFile::Plane { point, normal } => Arc::new(Plane {
+                point: vec3(*point),
+                normal: vec3(*normal),
+            }),
+            ShapeFile::Quad { corner, u, v } => Arc::new(Quad {
+                corner: vec3(*corner),
+                u: vec3(*u),
+                v: vec3(*v),
+            }),
+            ShapeFile::Box { min, max } => Arc::new(Cuboid::new(vec3(*min), vec3(*max))),
+            ShapeFile::Disk {
+                center,
+                normal,
+                radius,
+            } => Arc::new(Disk {
+                center: vec3(*center),
+                normal: vec3(*normal),
+                radius: *radius,
+            }),
+            ShapeFile::Cylinder {
+                radius,
+                height,
+                capped,
+            } => Arc::new(Quadric::cylinder(*radius, *height, *capped)),
+            ShapeFile::Cone {
+                radius,
+                height,
+                capped,
+            } => Arc::new(Quadric::cone(*radius, *height, *capped)),
+            ShapeFile::Paraboloid {
+                radius,
+                height,
+                capped,
+            } => Arc::new(Quadric::paraboloid(*radius, *height, *capped)),
+            ShapeFile::Hyperboloid {
+                waist_radius,
+                radius,
+                height,
+                capped,
+            } => Arc::new(Quadric::hyperboloid(
+                *waist_radius,
+                *radius,
+                *height,
+                *capped,
+            )),
+            ShapeFile::Torus {
+                major_radius,
+                minor_radius,
+            } => Arc::new(Torus {
+                major_radius: *major_radius,
+                minor_radius: *minor_radius,
+            }),
+            ShapeFile::Union { left, right } => csg(CsgOperation::Union, left, right)?,
+            ShapeFile::Intersection { left, right } => {
+                csg(CsgOperation::Intersection, left, right)?
+            }
+            ShapeFile::Difference { left, right } => csg(CsgOperation::Difference, left, right)?,
+            ShapeFile::Medium { boundary, density } => Arc::new(ConstantMedium {
+                boundary: boundary.to_shape()?,
+                density: *density,
+            }),
+            ShapeFile::Sdf {
+                sdf,
+                max_steps,
+                epsilon,
+                max_distance,
+            } => {
+                let mut shape = SdfShape::new(sdf.to_sdf());
+                shape.max_steps = max_steps.unwrap_or(shape.max_steps);
+                shape.epsilon = epsilon.unwrap_or(shape.epsilon);
+                shape.max_distance = max_distance.unwrap_or(shape.max_distance);
+                Arc::new(shape)
+            }
+            ShapeFile::Mesh { .. } => {
+                return Err("meshes can only be used as objects on their own".to_string())
+            }
+            ShapeFile::Grid { .. } => {
+                return Err("voxel grids can only be used as objects on their own".to_string())
+            }
+        })
+    }
+}
+
+impl SdfFile {
+    fn to_sdf(&self) -> Arc<dyn Sdf + Send + Sync> {
+        match self {
+            SdfFile::Sphere { radius } => Arc::new(SdfSphere { radius: *radius }),
+            SdfFile::Box { size, rounding } => Arc::new(SdfBox {
+                half_extents: vec3(*size) * 0.5,
+                rounding: *rounding,
+            }),
+            SdfFile::Torus {
+                major_radius,
+                minor_radius,
+            } => Arc::new(SdfTorus {
+                major_radius: *major_radius,
+                minor_radius: *minor_radius,
+            }),
+            SdfFile::Cylinder { radius, height } => Arc::new(SdfCylinder {
+                radius: *radius,
+                half_height: height / 2.0,
+            }),
+            SdfFile::Translate { offset, sdf } => Arc::new(Translated {
+                offset: vec3(*offset),
+                sdf: sdf.to_sdf(),
+            }),
+            SdfFile::SmoothUnion {
+                left,
+                right,
+                smoothness,
+            } => Arc::new(SmoothUnion {
+                left: left.to_sdf(),
+                right: right.to_sdf(),
+                smoothness: *smoothness,
+            }),
+            SdfFile::SmoothSubtract {
+                left,
+                right,
+                smoothness,
+            } => Arc::new(SmoothSubtract {
+                left: left.to_sdf(),
+                right: right.to_sdf(),
+                smoothness: *smoothness,
+            }),
+            SdfFile::Twist { sdf, rate } => Arc::new(Twist::new(sdf.to_sdf(), rate.to_radians())),
+            SdfFile::Repeat { sdf, period } => Arc::new(Repeat {
+                sdf: sdf.to_sdf(),
+                period: vec3(*period),
+            }),
+            SdfFile::Mandelbulb { power, iterations } => Arc::new(Mandelbulb {
+                power: *power,
+                iterations: *iterations,
+            }),
+            SdfFile::Menger { iterations } => Arc::new(Menger {
+                iterations: *iterations,
+            }),
+        }
+    }
+}
+
+impl NestedShapeFile {
+    fn to_shape(&self) -> Result<Arc<dyn Shape + Send + Sync>, String> {
+        let shape = self.shape.to_shape()?;
+        Ok(if self.transform.is_empty() {
+            shape
+        } else {
+            Arc::new(Transformed::new(shape, to_transform(&self.transform)))
+        })
+    }
+}
+
+fn csg(
+    operation: CsgOperation,
+    left: &NestedShapeFile,
+    right: &NestedShapeFile,
+) -> Result<Arc<dyn Shape + Send + Sync>, String> {
+    Ok(Arc::new(Csg {
+        operation,
+        left: left.to_shape()?,
+        right: right.to_shape()?,
+    }))
+}
+
+// Random choices made while loading, such as the noise of textures, are
+// picked by `seed`.
+pub fn load_scene(path: &Path, seed: u64) -> Result<Scene, io::Error> {
+    let contents = fs::read_to_string(path)?;
+    let file: SceneFile = toml::from_str(&contents).map_err(|e| {
+        let line = e.span().map_or(0, |span| line_of(&contents, span.start));
+        parse_error(path, line, e.message().to_string())
+    })?;
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut materials: HashMap<&str, Arc<dyn Material + Send + Sync>> = HashMap::new();
+    for (name, material) in file.materials.iter() {
+        let line = line_of(&contents, material.span().start);
+        // Seeded by name, as materials are built in no particular order.
+        let mut rng = Rng::new(hash(&[seed, name_hash(name)]));
+        let material = material
+            .get_ref()
+            .to_material(directory, &mut rng)
+            .map_err(|e| parse_error(path, line, format!("failed to load texture: {}", e)))?;
+        materials.insert(name.as_str(), material);
+    }
+
+    let mut objects = vec![];
+    // Meshes used by several objects are loaded once and shared by all of
+    // them.
+    let mut meshes: HashMap<&str, Vec<Object>> = HashMap::new();
+    let mut grids: HashMap<&str, Arc<VoxelGrid>> = HashMap::new();
+    for object in file.objects.iter() {
+        let object_line = line_of(&contents, object.span().start);
+        let object = object.get_ref();
+        let material = match &object.material {
+            Some(name) => match materials.get(name.get_ref().as_str()) {
+                Some(material) => Some(material.clone()),
+                None => {
+                    return Err(parse_error(
+                        path,
+                        line_of(&contents, name.span().start),
+                        format!("unknown material `{}`", name.get_ref()),
+                    ))
+                }
+            },
+            None => None,
+        };
+        let place = |shape: Arc<dyn Shape + Send + Sync>| -> Arc<dyn Shape + Send + Sync> {
+            if object.transform.is_empty() {
+                shape
+            } else {
+                Arc::new(Transformed::new(shape, to_transform(&object.transform)))
+            }
+        };
+
+        let shape = match &object.shape {
+            ShapeFile::Mesh { path: mesh_path } => {
+                if !meshes.contains_key(mesh_path.as_str()) {
+                    let loaded = load_obj(&directory.join(mesh_path)).map_err(|e| {
+                        parse_error(path, object_line, format!("failed to load mesh: {}", e))
+                    })?;
+                    meshes.insert(mesh_path, loaded);
+                }
+                objects.extend(meshes[mesh_path.as_str()].iter().map(|mesh| Object {
+                    material: material.clone().unwrap_or_else(|| mesh.material.clone()),
+                    shape: place(mesh.shape.clone()),
+                }));
+                continue;
+            }
+            ShapeFile::Grid {
+                path: grid_path,
+                density,
+                emission,
+            } => {
+                if !grids.contains_key(grid_path.as_str()) {
+                    let loaded = load_voxels(&directory.join(grid_path)).map_err(|e| {
+                        parse_error(path, object_line, format!("failed to load voxels: {}", e))
+                    })?;
+                    grids.insert(grid_path, Arc::new(loaded));
+                }
+                let grid = grids[grid_path.as_str()].clone();
+                let Some(material) = material else {
+                    return Err(parse_error(
+                        path,
+                        object_line,
+                        "object has no material".to_string(),
+                    ));
+                };
+                let material: Arc<dyn Material + Send + Sync> = match emission {
+                    Some(_) if grid.temperature.is_none() => {
+                        return Err(parse_error(
+                            path,
+                            object_line,
+                            format!("`{}` has no temperatures to emit light from", grid_path),
+                        ))
+                    }
+                    Some(intensity) => Arc::new(BlackbodyEmission {
+                        material,
+                        grid: grid.clone(),
+                        to_local: to_transform(&object.transform).inverse(),
+                        intensity: *intensity,
+                    }),
+                    None => material,
+                };
+                objects.push(Object {
+                    material,
+                    shape: place(Arc::new(GridMedium {
+                        grid,
+                        density: *density,
+                    })),
+                });
+                continue;
+            }
+            shape => shape
+                .to_shape()
+                .map_err(|message| parse_error(path, object_line, message))?,
+        };
+
+        match material {
+            Some(material) => objects.push(Object {
+                material,
+                shape: place(shape),
+            }),
+            None => {
+                return Err(parse_error(
+                    path,
+                    object_line,
+                    "object has no material".to_string(),
+                ))
+            }
+        }
+    }
+
+    if let Some(FogFile {
+        density,
+        color,
+        anisotropy,
+    }) = file.fog
+    {
+        let material = Arc::new(VolumeMaterial {
+            albedo: Arc::new(ConstantTexture { color: vec3(color) }),
+            anisotropy,
+        });
+        objects.extend(fog(&objects, density, material));
+    }
+
+    let background = match file.background {
+        Some(BackgroundFile::Gradient { horizon, zenith }) => Background::Gradient {
+            horizon: vec3(horizon),
+            zenith: vec3(zenith),
+        },
+        Some(BackgroundFile::Solid { color }) => Background::Solid(vec3(color)),
+        None => Background::default(),
+    };
+
+    let width = file.render.width.unwrap_or(1920);
+    Ok(Scene {
+        view: file.camera,
+        background,
+        render: RenderSettings {
+            width,
+            height: file
+                .render
+                .height
+                .unwrap_or((width as f64 * 9.0 / 16.0) as usize),
+            samples_per_pixel: file.render.samples_per_pixel.unwrap_or(100),
+            num_threads: file.render.num_threads,
+        },
+        objects,
+    })
+}
+
+fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// The cover scene of Ray Tracing in One Weekend: a grid of small random
+// spheres around three big ones, laid out by `seed`.
+pub fn random_spheres(seed: u64) -> Scene {
+    let mut rng = Rng::new(hash(&[seed]));
+    let aspect_ratio = 16.0 / 9.0;
+    let img_width = 1920;
+    let img_height = (img_width as f64 / aspect_ratio) as usize;
+
+    let mut objects: Vec<Object> = vec![];
+    objects.push(Object {
+        shape: Arc::new(Plane {
+            point: ORIGIN,
+            normal: Vec3f::new(0.0, 1.0, 0.0),
+        }),
+        material: Arc::new(DiffuseMaterial {
+            color: Arc::new(ConstantTexture {
+                color: Vec3f::from_color(Color::new(125, 125, 125)),
+            }),
+        }),
+    });
+
+    let small_sphere_radius = 0.2;
+
+    for i in -11..11 {
+        for j in -11..11 {
+            let material_seed = rng.next_f64();
+            let center = Vec3f::new(
+                i as f64 + 0.9 * rng.next_f64(),
+                small_sphere_radius,
+                j as f64 + 0.9 * rng.next_f64(),
+            );
+
+            if (&center - &Vec3f::new(4.0, 0.2, 0.0)).norm() > 0.9 {
+                let sphere = Arc::new(Sphere {
+                    center,
+                    radius: small_sphere_radius,
+                });
+                if material_seed < 0.8 {
+                    let random_color = Color::random(&mut rng);
+                    objects.push(Object {
+                        shape: sphere,
+                        material: Arc::new(DiffuseMaterial {
+                            color: Arc::new(ConstantTexture {
+                                color: Vec3f::from_color(random_color),
+                            }),
+                        }),
+                    })
+                } else if material_seed < 0.9 {
+                    let fuzz = rng.next_f64();
+                    objects.push(Object {
+                        shape: sphere,
+                        material: Arc::new(MetalMaterial {
+                            attenuation: Arc::new(ConstantTexture {
+                                color: Vec3f::from_color(Color::random(&mut rng)),
+                            }),
+                            fuzz,
+                        }),
+                    })
+                } else {
+                    objects.push(Object {
+                        shape: sphere,
+                        material: Arc::new(DielectricMaterial {
+                            ior: 1.5,
+                            absorption: Vec3f::new(0.0, 0.0, 0.0),
+                        }),
+                    })
+                }
+            }
+        }
+    }
+
+    objects.push(Object {
+        shape: Arc::new(Sphere {
+            center: Vec3f::new(-4.0, 1.0, 0.0),
+            radius: 1.0,
+        }),
+        material: Arc::new(DiffuseMaterial {
+            color: Arc::new(ConstantTexture {
+                color: Vec3f::from_color(Color::new(100, 50, 25)),
+            }),
+        }),
+    });
+
+    objects.push(Object {
+        shape: Arc::new(Sphere {
+            center: Vec3f::new(0.0, 1.0, 0.0),
+            radius: 1.0,
+        }),
+        material: Arc::new(DielectricMaterial {
+            ior: 1.5,
+            absorption: Vec3f::new(0.0, 0.0, 0.0),
+        }),
+    });
+
+    objects.push(Object {
+        shape: Arc::new(Sphere {
+            center: Vec3f::new(4.0, 1.0, 0.0),
+            radius: 1.0,
+        }),
+        material: Arc::new(MetalMaterial {
+            attenuation: Arc::new(ConstantTexture {
+                color: Vec3f::from_color(Color::new(120, 120, 120)),
+            }),
+            fuzz: 0.0,
+        }),
+    });
+
+    Scene {
+        view: View {
+            origin: [13.0, 2.0, 3.0],
+            lookat: [ORIGIN.x, ORIGIN.y, ORIGIN.z],
+            up: default_up(),
+            vfov: 40.0,
+            aperture: 0.1,
+            focus_distance: Some(10.0),
+            autofocus: false,
+            blades: 0,
+            blade_rotation: 0.0,
+        },
+        render: RenderSettings {
+            width: (img_height as f64 * aspect_ratio) as usize,
+            height: img_height,
+            samples_per_pixel: 100,
+            num_threads: None,
+        },
+        background: Background::default(),
+        objects,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    const CAMERA: &str = "[camera]
+origin = [0.0, 0.0, 5.0]
+lookat = [0.0, 0.0, 0.0]
+vfov = 40.0
+";
+
+    fn load_error(contents: &str) -> (String, String) {
+        let dir = TempDir::new();
+        let path = dir.write("scene.toml", contents);
+        let error = load_scene(&path, 0).err().unwrap().to_string();
+        (error, path.display().to_string())
+    }
+
+    #[test]
+    fn unknown_material_is_reported_at_its_line() {
+        let contents = format!(
+            "{}
+[materials.red]
+type = \"diffuse\"
+color = [0.8, 0.1, 0.1]
+
+[[objects]]
+shape = {{ type = \"sphere\", center = [0.0, 0.0, 0.0], radius = 1.0 }}
+material = \"blue\"
+",
+            CAMERA
+        );
+        let (error, path) = load_error(&contents);
+        assert_eq!(error, format!("{}:12: unknown material `blue`", path));
+    }
+
+    #[test]
+    fn malformed_vector_is_reported_at_its_line() {
+        let contents = format!(
+            "{}
+[[objects]]
+shape = {{ type = \"sphere\", center = [0.0, 0.0], radius = 1.0 }}
+",
+            CAMERA
+        );
+        let (error, path) = load_error(&contents);
+        assert_eq!(
+            error,
+            format!(
+                "{}:7: invalid length 2, expected an array of length 3",
+                path
+            )
+        );
+    }
+
+    #[test]
+    fn loads_the_example_scenes() {
+        let mut count = 0;
+        for entry in fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/scenes")).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "toml") {
+                let scene = load_scene(&path, 0)
+                    .unwrap_or_else(|e| panic!("failed to load {}: {}", path.display(), e));
+                assert!(!scene.objects.is_empty(), "{}", path.display());
+                count += 1;
+            }
+        }
+        assert!(count >= 4);
+    }
+}
@@ -0,0 +1,619 @@
+use crate::bvh::Aabb;
+use crate::object::{around_y_tangent, sphere_uv, Hit, Shape, MIN_HIT_DISTANCE};
+use crate::vector::{Ray, Vec3f};
+use std::sync::Arc;
+
+// A signed distance function: for any point, the distance to the closest
+// point of a surface, negative inside it. Functions only need to be bounds,
+// never overestimating the distance, for sphere tracing to be safe.
+pub trait Sdf {
+    fn distance(&self, point: &Vec3f) -> f64;
+    // A box enclosing the surface. Infinite for endless fields, such as
+    // repetitions.
+    fn bounding_box(&self) -> Aabb;
+}
+
+fn abs(v: &Vec3f) -> Vec3f {
+    Vec3f::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+fn cube(half_size: f64) -> Aabb {
+    Aabb::new(
+        Vec3f::new(-half_size, -half_size, -half_size),
+        Vec3f::new(half_size, half_size, half_size),
+    )
+}
+
+// Grows a box by `margin` on every side.
+fn pad(bounds: Aabb, margin: f64) -> Aabb {
+    let margin = Vec3f::new(margin, margin, margin);
+    Aabb::new(&bounds.min - &margin, &bounds.max + &margin)
+}
+
+pub struct SdfSphere {
+    pub radius: f64,
+}
+
+impl Sdf for SdfSphere {
+    fn distance(&self, point: &Vec3f) -> f64 {
+        point.norm() - self.radius
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        cube(self.radius)
+    }
+}
+
+// A box centered on the origin, with its edges rounded off by `rounding`.
+pub struct SdfBox {
+    pub half_extents: Vec3f,
+    pub rounding: f64,
+}
+
+impl Sdf for SdfBox {
+    fn distance(&self, point: &Vec3f) -> f64 {
+        let rounding = Vec3f::new(self.rounding, self.rounding, self.rounding);
+        let q = &(&abs(point) - &self.half_extents) + &rounding;
+        let outside = q.max(&Vec3f::new(0.0, 0.0, 0.0)).norm();
+        let inside = q.x.max(q.y).max(q.z).min(0.0);
+        outside + inside - self.rounding
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::new(&self.half_extents * -1.0, self.half_extents.clone())
+    }
+}
+
+// A torus around the y axis.
+pub struct SdfTorus {
+    pub major_radius: f64,
+    pub minor_radius: f64,
+}
+
+impl Sdf for SdfTorus {
+    fn distance(&self, point: &Vec3f) -> f64 {
+        let around = (point.x * point.x + point.z * point.z).sqrt() - self.major_radius;
+        (around * around + point.y * point.y).sqrt() - self.minor_radius
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let extent = self.major_radius + self.minor_radius;
+        Aabb::new(
+            Vec3f::new(-extent, -self.minor_radius, -extent),
+            Vec3f::new(extent, self.minor_radius, extent),
+        )
+    }
+}
+
+// A capped cylinder around the y axis, centered on the origin.
+pub struct SdfCylinder {
+    pub radius: f64,
+    pub half_height: f64,
+}
+
+impl Sdf for SdfCylinder {
+    fn distance(&self, point: &Vec3f) -> f64 {
+        let dx = (point.x * point.x + point.z * point.z).sqrt() - self.radius;
+        let dy = point.y.abs() - self.half_height;
+        let outside = (dx.max(0.0).powi(2) + dy.max(0.0).powi(2)).sqrt();
+        outside + dx.max(dy).min(0.0)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::new(
+            Vec3f::new(-self.radius, -self.half_height, -self.radius),
+            Vec3f::new(self.radius, self.half_height, self.radius),
+        )
+    }
+}
+
+pub struct Translated {
+    pub offset: Vec3f,
+    pub sdf: Arc<dyn Sdf + Send + Sync>,
+}
+
+impl Sdf for Translated {
+    fn distance(&self, point: &Vec3f) -> f64 {
+        self.sdf.distance(&(point - &self.offset))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let bounds = self.sdf.bounding_box();
+        Aabb::new(&bounds.min + &self.offset, &bounds.max + &self.offset)
+    }
+}
+
+// Both shapes, blended into each other where they're closer than
+// `smoothness`. A smoothness of 0 is a plain union.
+pub struct SmoothUnion {
+    pub left: Arc<dyn Sdf + Send + Sync>,
+    pub right: Arc<dyn Sdf + Send + Sync>,
+    pub smoothness: f64,
+}
+
+impl Sdf for SmoothUnion {
+    fn distance(&self, point: &Vec3f) -> f64 {
+        let (a, b) = (self.left.distance(point), self.right.distance(point));
+        let k = self.smoothness;
+        if k <= 0.0 {
+            return a.min(b);
+        }
+        // Inigo Quilez's polynomial smooth minimum.
+        let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+        b + (a - b) * h - k * h * (1.0 - h)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        pad(
+            self.left.bounding_box().union(&self.right.bounding_box()),
+            self.smoothness,
+        )
+    }
+}
+
+// The left shape with the right one carved out of it, with the edges of the
+// cut rounded by `smoothness`.
+pub struct SmoothSubtract {
+    pub left: Arc<dyn Sdf + Send + Sync>,
+    pub right: Arc<dyn Sdf + Send + Sync>,
+    pub smoothness: f64,
+}
+
+impl Sdf for SmoothSubtract {
+    fn distance(&self, point: &Vec3f) -> f64 {
+        let (a, b) = (self.left.distance(point), self.right.distance(point));
+        let k = self.smoothness;
+        if k <= 0.0 {
+            return a.max(-b);
+        }
+        let h = (0.5 - 0.5 * (a + b) / k).clamp(0.0, 1.0);
+        a + (-b - a) * h + k * h * (1.0 - h)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        pad(self.left.bounding_box(), self.smoothness)
+    }
+}
+
+// Twists a shape around the y axis by `rate` radians per unit of height.
+pub struct Twist {
+    pub sdf: Arc<dyn Sdf + Send + Sync>,
+    pub rate: f64,
+    // How far from the y axis the twisted shape reaches.
+    radius: f64,
+    // Twisting stretches space, so the distance to the twisted shape can be
+    // shorter than the distance to the original. Dividing by the most it gets
+    // stretched keeps it a bound.
+    stretch: f64,
+}
+
+impl Twist {
+    pub fn new(sdf: Arc<dyn Sdf + Send + Sync>, rate: f64) -> Self {
+        let bounds = sdf.bounding_box();
+        let x = bounds.min.x.abs().max(bounds.max.x.abs());
+        let z = bounds.min.z.abs().max(bounds.max.z.abs());
+        let radius = (x * x + z * z).sqrt();
+        // Heights get sheared by up to `rate * radius` around the axis; a
+        // shear by k stretches distances by up to (k + sqrt(k^2 + 4)) / 2.
+        let shear = (rate * radius).abs();
+        Twist {
+            sdf,
+            rate,
+            radius,
+            stretch: (shear + (shear * shear + 4.0).sqrt()) / 2.0,
+        }
+    }
+}
+
+impl Sdf for Twist {
+    fn distance(&self, point: &Vec3f) -> f64 {
+        let (sin, cos) = (self.rate * point.y).sin_cos();
+        let twisted = Vec3f::new(
+            cos * point.x - sin * point.z,
+            point.y,
+            sin * point.x + cos * point.z,
+        );
+        self.sdf.distance(&twisted) / self.stretch
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let bounds = self.sdf.bounding_box();
+        Aabb::new(
+            Vec3f::new(-self.radius, bounds.min.y, -self.radius),
+            Vec3f::new(self.radius, bounds.max.y, self.radius),
+        )
+    }
+}
+
+// Copies of a shape repeated endlessly every `period` along each axis, or not
+// at all along the axes where the period is 0. The shape must fit in a single
+// period for the distance to be right.
+pub struct Repeat {
+    pub sdf: Arc<dyn Sdf + Send + Sync>,
+    pub period: Vec3f,
+}
+
+impl Sdf for Repeat {
+    fn distance(&self, point: &Vec3f) -> f64 {
+        let wrap = |x: f64, period: f64| {
+            if period > 0.0 {
+                x - period * (x / period).round()
+            } else {
+                x
+            }
+        };
+        self.sdf.distance(&Vec3f::new(
+            wrap(point.x, self.period.x),
+            wrap(point.y, self.period.y),
+            wrap(point.z, self.period.z),
+        ))
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let bounds = self.sdf.bounding_box();
+        let axis = |min: f64, max: f64, period: f64| {
+            if period > 0.0 {
+                (f64::NEG_INFINITY, f64::INFINITY)
+            } else {
+                (min, max)
+            }
+        };
+        let x = axis(bounds.min.x, bounds.max.x, self.period.x);
+        let y = axis(bounds.min.y, bounds.max.y, self.period.y);
+        let z = axis(bounds.min.z, bounds.max.z, self.period.z);
+        Aabb::new(Vec3f::new(x.0, y.0, z.0), Vec3f::new(x.1, y.1, z.1))
+    }
+}
+
+// The Mandelbulb fractal, with the usual power of 8 giving the best known
+// shape. It's about 1.1 units in radius, pointing up along y.
+pub struct Mandelbulb {
+    pub power: f64,
+    pub iterations: usize,
+}
+
+impl Sdf for Mandelbulb {
+    // The distance estimate from the escape speed of the iteration
+    // z -> z^power + point, with powers of 3D points taken in spherical
+    // coordinates.
+    fn distance(&self, point: &Vec3f) -> f64 {
+        let mut z = point.clone();
+        let mut dr = 1.0;
+        let mut r = z.norm();
+        for _ in 0..self.iterations {
+            if r > 2.0 {
+                break;
+            }
+            let theta = (z.y / r).clamp(-1.0, 1.0).acos() * self.power;
+            let phi = z.z.atan2(z.x) * self.power;
+            dr = r.powf(self.power - 1.0) * self.power * dr + 1.0;
+            let zr = r.powf(self.power);
+            z = &Vec3f::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            ) * zr
+                + point.clone();
+            r = z.norm();
+        }
+        if r == 0.0 {
+            return 0.0;
+        }
+        0.5 * r.ln() * r / dr
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        cube(1.2)
+    }
+}
+
+// The Menger sponge: a cube 2 units across with its middle thirds removed,
+// again and again in every smaller cube.
+pub struct Menger {
+    pub iterations: usize,
+}
+
+impl Sdf for Menger {
+    // After Inigo Quilez: crosses of every size are carved out of the cube by
+    // folding space into one cell per level.
+    fn distance(&self, point: &Vec3f) -> f64 {
+        let mut d = SdfBox {
+            half_extents: Vec3f::new(1.0, 1.0, 1.0),
+            rounding: 0.0,
+        }
+        .distance(point);
+        let mut scale = 1.0;
+        for _ in 0..self.iterations {
+            let fold = |x: f64| (x * scale).rem_euclid(2.0) - 1.0;
+            let a = Vec3f::new(fold(point.x), fold(point.y), fold(point.z));
+            scale *= 3.0;
+            let r = Vec3f::new(
+                (1.0 - 3.0 * a.x.abs()).abs(),
+                (1.0 - 3.0 * a.y.abs()).abs(),
+                (1.0 - 3.0 * a.z.abs()).abs(),
+            );
+            let da = r.x.max(r.y);
+            let db = r.y.max(r.z);
+            let dc = r.z.max(r.x);
+            let cross = (da.min(db).min(dc) - 1.0) / scale;
+            d = d.max(cross);
+        }
+        d
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        cube(1.0)
+    }
+}
+
+// A surface given by a signed distance function, intersected by sphere
+// tracing: stepping along the ray by the distance to the surface, which can't
+// skip over it, until that distance drops below `epsilon`. Rays give up after
+// `max_steps` steps or `max_distance`, which only matters for infinite fields.
+// Normals are the gradient of the distance. Texture coordinates are spherical,
+// from the normal.
+pub struct SdfShape {
+    pub sdf: Arc<dyn Sdf + Send + Sync>,
+    pub max_steps: usize,
+    pub epsilon: f64,
+    pub max_distance: f64,
+}
+
+impl SdfShape {
+    pub fn new(sdf: Arc<dyn Sdf + Send + Sync>) -> Self {
+        SdfShape {
+            sdf,
+            max_steps: 256,
+            epsilon: 1e-4,
+            max_distance: 1e3,
+        }
+    }
+
+    // The gradient of the distance by central differences, taken at the
+    // corners of a tetrahedron to only need four evaluations.
+    fn normal(&self, point: &Vec3f) -> Vec3f {
+        let h = self.epsilon;
+        [
+            Vec3f::new(1.0, -1.0, -1.0),
+            Vec3f::new(-1.0, -1.0, 1.0),
+            Vec3f::new(-1.0, 1.0, -1.0),
+            Vec3f::new(1.0, 1.0, 1.0),
+        ]
+        .iter()
+        .fold(Vec3f::new(0.0, 0.0, 0.0), |sum, k| {
+            sum + k * self.sdf.distance(&(point + &(k * h)))
+        })
+        .normalize()
+    }
+
+    // The range of distances along a unit direction in which the ray is
+    // inside the bounding box.
+    fn clip(&self, origin: &Vec3f, dir: &Vec3f) -> Option<(f64, f64)> {
+        let bounds = pad(self.sdf.bounding_box(), self.epsilon);
+        let (mut t0, mut t1) = (0.0, self.max_distance);
+        for axis in 0..3 {
+            let inv = 1.0 / dir[axis];
+            let mut near = (bounds.min[axis] - origin[axis]) * inv;
+            let mut far = (bounds.max[axis] - origin[axis]) * inv;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // NaNs from infinite bounds along an axis the ray is parallel to
+            // fail both comparisons and leave the range alone.
+            if near > t0 {
+                t0 = near;
+            }
+            if far < t1 {
+                t1 = far;
+            }
+        }
+        if t0 <= t1 {
+            Some((t0, t1))
+        } else {
+            None
+        }
+    }
+}
+
+impl Shape for SdfShape {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let length = ray.dir.norm();
+        let dir = &ray.dir * (1.0 / length);
+        let (start, end) = self.clip(&ray.origin, &dir)?;
+        let mut t = start.max(MIN_HIT_DISTANCE * length);
+
+        // A ray leaving a surface, e.g. after bouncing off it, starts within
+        // epsilon of it. It's only heading into the shape if it's going
+        // against the gradient; refracted rays then look for the other side.
+        let start_distance = self.sdf.distance(&ray.origin);
+        let mut sign = start_distance.signum();
+        let mut escaped = start_distance.abs() >= 2.0 * self.epsilon;
+        if !escaped {
+            let heading_in = self.normal(&ray.origin).dot_ref(&dir) < 0.0;
+            sign = if heading_in { -1.0 } else { 1.0 };
+        }
+
+        for _ in 0..self.max_steps {
+            if t > end {
+                return None;
+            }
+            let point = &ray.origin + &(&dir * t);
+            let distance = sign * self.sdf.distance(&point);
+            if escaped && distance < self.epsilon {
+                let normal = self.normal(&point);
+                let uv = sphere_uv(&normal);
+                let tangent = around_y_tangent(&normal);
+                return Some(Hit::new(ray, t / length, normal, uv).with_tangent(tangent));
+            }
+            if distance >= self.epsilon {
+                escaped = true;
+            }
+            t += distance.max(self.epsilon);
+        }
+        None
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        pad(self.sdf.bounding_box(), self.epsilon)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Sphere;
+    use crate::sampler::Rng;
+
+    fn random_direction(rng: &mut Rng) -> Vec3f {
+        loop {
+            let v = Vec3f::new(
+                2.0 * rng.next_f64() - 1.0,
+                2.0 * rng.next_f64() - 1.0,
+                2.0 * rng.next_f64() - 1.0,
+            );
+            if v.norm() > 0.1 && v.norm() <= 1.0 {
+                return v.normalize();
+            }
+        }
+    }
+
+    // Checks that sphere tracing a sphere hits where the analytic sphere
+    // does, within the precision of the marching.
+    fn assert_matches_sphere(ray: &Ray) {
+        let sphere = Sphere {
+            center: Vec3f::new(0.0, 0.0, 0.0),
+            radius: 1.0,
+        };
+        let shape = SdfShape::new(Arc::new(SdfSphere { radius: 1.0 }));
+        let expected = sphere.intersect(ray);
+        let hit = shape.intersect(ray);
+        assert_eq!(hit.is_some(), expected.is_some(), "{:?}", ray);
+        if let (Some(hit), Some(expected)) = (hit, expected) {
+            let length = ray.dir.norm();
+            assert!((hit.t - expected.t).abs() * length < 1e-3, "{:?}", ray);
+            assert!((&hit.normal - &expected.normal).norm() < 1e-3, "{:?}", ray);
+            assert_eq!(hit.front_face, expected.front_face);
+        }
+    }
+
+    #[test]
+    fn sphere_matches_the_analytic_sphere() {
+        let mut rng = Rng::new(1);
+        for _ in 0..200 {
+            // From outside, aimed close to the sphere, and so sometimes
+            // missing it. Directions aren't normalized.
+            let origin = random_direction(&mut rng) * 4.0;
+            let target = random_direction(&mut rng) * 1.2;
+            // Rays grazing the sphere take more than max_steps to get there.
+            let dir = (&target - &origin).normalize();
+            let closest = (&origin - &(&dir * origin.dot_ref(&dir))).norm();
+            if (closest - 1.0).abs() < 0.01 {
+                continue;
+            }
+            assert_matches_sphere(&Ray {
+                dir: (&target - &origin) * 0.5,
+                origin,
+            });
+            // From inside, always hitting the back.
+            let origin = random_direction(&mut rng) * (0.9 * rng.next_f64());
+            let dir = random_direction(&mut rng) * 3.0;
+            assert_matches_sphere(&Ray { origin, dir });
+        }
+    }
+
+    #[test]
+    fn repeat_finds_every_copy() {
+        let repeat = Repeat {
+            sdf: Arc::new(SdfSphere { radius: 0.5 }),
+            period: Vec3f::new(2.0, 0.0, 2.0),
+        };
+        let bounds = repeat.bounding_box();
+        assert_eq!(
+            (bounds.min.x, bounds.max.x),
+            (f64::NEG_INFINITY, f64::INFINITY)
+        );
+        assert_eq!((bounds.min.y, bounds.max.y), (-0.5, 0.5));
+        assert_eq!(
+            (bounds.min.z, bounds.max.z),
+            (f64::NEG_INFINITY, f64::INFINITY)
+        );
+        assert!((repeat.distance(&Vec3f::new(41.0, 0.0, -6.0)) - 0.5).abs() < 1e-12);
+
+        let shape = SdfShape::new(Arc::new(repeat));
+        let hit_at = |origin: Vec3f, dir: Vec3f| shape.intersect(&Ray { origin, dir });
+        // Straight down onto a copy far from the origin, along an infinite
+        // axis of the bounds.
+        let hit = hit_at(Vec3f::new(-40.0, 5.0, 20.0), Vec3f::new(0.0, -1.0, 0.0)).unwrap();
+        assert!((hit.t - 4.5).abs() < 1e-3);
+        assert!((&hit.normal - &Vec3f::new(0.0, 1.0, 0.0)).norm() < 1e-3);
+        // Along a row of copies, hitting the next one.
+        let hit = hit_at(Vec3f::new(101.0, 0.0, 4.0), Vec3f::new(2.0, 0.0, 0.0)).unwrap();
+        assert!((hit.t - 0.25).abs() < 1e-3);
+        // Above all of them, the clipped range is empty.
+        assert!(shape
+            .clip(&Vec3f::new(0.0, 3.0, 0.0), &Vec3f::new(1.0, 0.0, 0.0))
+            .is_none());
+        assert!(hit_at(Vec3f::new(0.0, 3.0, 0.0), Vec3f::new(1.0, 0.0, 0.0)).is_none());
+        // Between two rows, the ray gives up at max_distance.
+        let (start, end) = shape
+            .clip(&Vec3f::new(0.0, 0.0, 1.0), &Vec3f::new(1.0, 0.0, 0.0))
+            .unwrap();
+        assert_eq!((start, end), (0.0, shape.max_distance));
+        assert!(hit_at(Vec3f::new(0.0, 0.0, 1.0), Vec3f::new(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn twist_bounds_and_surface() {
+        let twist = Twist::new(
+            Arc::new(SdfBox {
+                half_extents: Vec3f::new(1.0, 2.0, 0.5),
+                rounding: 0.0,
+            }),
+            1.0,
+        );
+        let bounds = twist.bounding_box();
+        let radius = 1.25f64.sqrt();
+        assert!((bounds.max.x - radius).abs() < 1e-12 && (bounds.min.z + radius).abs() < 1e-12);
+        assert_eq!((bounds.min.y, bounds.max.y), (-2.0, 2.0));
+
+        // Points of the original surface, rotated back, are on the twisted one.
+        let (sin, cos) = (-1.5f64).sin_cos();
+        let corner = Vec3f::new(cos * 1.0 - sin * 0.5, 1.5, sin * 1.0 + cos * 0.5);
+        assert!(twist.distance(&corner).abs() < 1e-12);
+    }
+
+    #[test]
+    fn twisted_distance_changes_no_faster_than_the_point() {
+        let twist = Twist::new(
+            Arc::new(SdfBox {
+                half_extents: Vec3f::new(1.0, 2.0, 0.5),
+                rounding: 0.0,
+            }),
+            1.0,
+        );
+        // Otherwise sphere tracing could step over the surface. Space is
+        // stretched the most around the edges furthest from the axis, so
+        // points are picked around them, and also further out.
+        let mut rng = Rng::new(2);
+        let h = 1e-7;
+        for i in 0..20000 {
+            let scale = if i % 2 == 0 { 0.2 } else { 3.0 };
+            let offset = Vec3f::new(
+                scale * (rng.next_f64() - 0.5),
+                scale * rng.next_f64(),
+                scale * rng.next_f64(),
+            );
+            let q = &Vec3f::new(1.0, 2.0, 0.5) + &offset;
+            // The point that twists onto q.
+            let (sin, cos) = (-q.y).sin_cos();
+            let p = Vec3f::new(cos * q.x - sin * q.z, q.y, sin * q.x + cos * q.z);
+            let d = twist.distance(&p);
+            let gradient = Vec3f::new(
+                twist.distance(&(&p + &Vec3f::new(h, 0.0, 0.0))) - d,
+                twist.distance(&(&p + &Vec3f::new(0.0, h, 0.0))) - d,
+                twist.distance(&(&p + &Vec3f::new(0.0, 0.0, h))) - d,
+            ) * (1.0 / h);
+            assert!(gradient.norm() <= 1.0 + 1e-4, "{:?}", p);
+        }
+    }
+}
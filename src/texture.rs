@@ -0,0 +1,234 @@
+use crate::framebuffer::Framebuffer;
+use crate::sampler::Rng;
+use crate::vector::Vec3f;
+use std::sync::Arc;
+
+// A color that varies over a surface, looked up by the texture coordinates of
+// a hit (u and v, both usually in [0, 1]) and by the hit point itself.
+pub trait Texture {
+    fn value(&self, u: f64, v: f64, point: &Vec3f) -> Vec3f;
+}
+
+pub struct ConstantTexture {
+    pub color: Vec3f,
+}
+
+impl Texture for ConstantTexture {
+    fn value(&self, _u: f64, _v: f64, _point: &Vec3f) -> Vec3f {
+        self.color.clone()
+    }
+}
+
+// A checkerboard made of cubes of side `scale` that alternate between two
+// textures. It's defined in space rather than on the surface, so it doesn't
+// stretch around the poles of spheres like a UV checker would.
+pub struct CheckerTexture {
+    pub even: Arc<dyn Texture + Send + Sync>,
+    pub odd: Arc<dyn Texture + Send + Sync>,
+    pub scale: f64,
+}
+
+impl Texture for CheckerTexture {
+    fn value(&self, u: f64, v: f64, point: &Vec3f) -> Vec3f {
+        let cell = (point.x / self.scale).floor()
+            + (point.y / self.scale).floor()
+            + (point.z / self.scale).floor();
+        if cell.rem_euclid(2.0) == 0.0 {
+            self.even.value(u, v, point)
+        } else {
+            self.odd.value(u, v, point)
+        }
+    }
+}
+
+const PERLIN_POINT_COUNT: usize = 256;
+
+// Ken Perlin's gradient noise: random unit gradients on the integer lattice,
+// smoothly interpolated in between. Hashing each coordinate through its own
+// permutation gives every lattice point a gradient without storing a grid.
+struct Perlin {
+    gradients: Vec<Vec3f>,
+    permutations: [Vec<usize>; 3],
+}
+
+impl Perlin {
+    fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut random_unit = || loop {
+            let v = Vec3f::new(
+                2.0 * rng.next_f64() - 1.0,
+                2.0 * rng.next_f64() - 1.0,
+                2.0 * rng.next_f64() - 1.0,
+            );
+            let sq_norm = v.sq_norm();
+            if sq_norm > 1e-6 && sq_norm <= 1.0 {
+                return v.normalize();
+            }
+        };
+        let gradients = (0..PERLIN_POINT_COUNT).map(|_| random_unit()).collect();
+        let mut permutation = || {
+            let mut p: Vec<usize> = (0..PERLIN_POINT_COUNT).collect();
+            // Fisher-Yates shuffle.
+            for i in (1..p.len()).rev() {
+                p.swap(i, (rng.next_u64() % (i as u64 + 1)) as usize);
+            }
+            p
+        };
+        Perlin {
+            gradients,
+            permutations: [permutation(), permutation(), permutation()],
+        }
+    }
+
+    // Noise in [-1, 1], 0 at every lattice point.
+    fn noise(&self, point: &Vec3f) -> f64 {
+        let cell = [point.x.floor(), point.y.floor(), point.z.floor()];
+        let fraction = [point.x - cell[0], point.y - cell[1], point.z - cell[2]];
+        // Hermite smoothing hides the lattice.
+        let smooth = fraction.map(|f| f * f * (3.0 - 2.0 * f));
+
+        let mut sum = 0.0;
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let mut hash = 0;
+            let mut weight = 1.0;
+            for axis in 0..3 {
+                let lattice = (cell[axis] as i64 + offset[axis] as i64)
+                    .rem_euclid(PERLIN_POINT_COUNT as i64) as usize;
+                hash ^= self.permutations[axis][lattice];
+                weight *= if offset[axis] == 1 {
+                    smooth[axis]
+                } else {
+                    1.0 - smooth[axis]
+                };
+            }
+            let to_point = Vec3f::new(
+                fraction[0] - offset[0] as f64,
+                fraction[1] - offset[1] as f64,
+                fraction[2] - offset[2] as f64,
+            );
+            sum += weight * self.gradients[hash].dot_ref(&to_point);
+        }
+        sum
+    }
+
+    // Sum of `depth` octaves of noise, each at twice the frequency and half
+    // the amplitude of the previous one.
+    fn turbulence(&self, point: &Vec3f, depth: usize) -> f64 {
+        let mut sum = 0.0;
+        let mut point = point.clone();
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            sum += weight * self.noise(&point);
+            weight *= 0.5;
+            point = point * 2.0;
+        }
+        sum.abs()
+    }
+}
+
+const TURBULENCE_DEPTH: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoiseKind {
+    // Smooth noise.
+    Noise,
+    // Several octaves of noise, for a cloudy look.
+    Turbulence,
+    // Stripes along z, distorted by turbulence.
+    Marble,
+}
+
+// A grayscale texture made of Perlin noise. Larger scales give finer detail.
+// The noise is picked by `seed`: the same seed always gives the same texture.
+pub struct NoiseTexture {
+    perlin: Perlin,
+    pub scale: f64,
+    pub kind: NoiseKind,
+}
+
+impl NoiseTexture {
+    pub fn new(scale: f64, kind: NoiseKind, seed: u64) -> Self {
+        NoiseTexture {
+            perlin: Perlin::new(seed),
+            scale,
+            kind,
+        }
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _u: f64, _v: f64, point: &Vec3f) -> Vec3f {
+        let p = point * self.scale;
+        let gray = match self.kind {
+            NoiseKind::Noise => 0.5 * (1.0 + self.perlin.noise(&p)),
+            NoiseKind::Turbulence => self.perlin.turbulence(&p, TURBULENCE_DEPTH),
+            NoiseKind::Marble => {
+                0.5 * (1.0 + (p.z + 10.0 * self.perlin.turbulence(point, TURBULENCE_DEPTH)).sin())
+            }
+        };
+        Vec3f::new(gray, gray, gray)
+    }
+}
+
+// What image textures do with coordinates outside [0, 1].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WrapMode {
+    // Tile the image.
+    Repeat,
+    // Tile the image, flipping every other copy so that the edges match.
+    Mirror,
+    // Extend the border pixels.
+    Clamp,
+}
+
+impl WrapMode {
+    // Maps a pixel index to one inside an image of `size` pixels.
+    fn apply(self, index: i64, size: usize) -> usize {
+        let size = size as i64;
+        let index = match self {
+            WrapMode::Repeat => index.rem_euclid(size),
+            WrapMode::Mirror => {
+                let index = index.rem_euclid(2 * size);
+                if index >= size {
+                    2 * size - 1 - index
+                } else {
+                    index
+                }
+            }
+            WrapMode::Clamp => index.clamp(0, size - 1),
+        };
+        index as usize
+    }
+}
+
+// An image mapped onto the surface, with (0, 0) at its bottom left corner and
+// (1, 1) at its top right. Pixels are filtered bilinearly.
+pub struct ImageTexture {
+    pub image: Framebuffer,
+    pub wrap: WrapMode,
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _point: &Vec3f) -> Vec3f {
+        let (width, height) = (self.image.width, self.image.height);
+        if width == 0 || height == 0 {
+            return Vec3f::new(0.0, 0.0, 0.0);
+        }
+        // Pixel centers sit at half-integer coordinates.
+        let x = u * width as f64 - 0.5;
+        let y = (1.0 - v) * height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |dx: i64, dy: i64| {
+            self.image.pixel(
+                self.wrap.apply(y0 as i64 + dy, height),
+                self.wrap.apply(x0 as i64 + dx, width),
+            )
+        };
+        texel(0, 0) * ((1.0 - fx) * (1.0 - fy))
+            + texel(1, 0) * (fx * (1.0 - fy))
+            + texel(0, 1) * ((1.0 - fx) * fy)
+            + texel(1, 1) * (fx * fy)
+    }
+}
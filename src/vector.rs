@@ -164,6 +164,38 @@ impl Vec3f {
             z: self.x * other.y - self.y * other.x,
         }
     }
+
+    // Component-wise minimum of two vectors.
+    pub fn min(&self, other: &Vec3f) -> Self {
+        Self {
+            x: self.x.min(other.x),
+            y: self.y.min(other.y),
+            z: self.z.min(other.z),
+        }
+    }
+
+    // Component-wise maximum of two vectors.
+    pub fn max(&self, other: &Vec3f) -> Self {
+        Self {
+            x: self.x.max(other.x),
+            y: self.y.max(other.y),
+            z: self.z.max(other.z),
+        }
+    }
+}
+
+// Allows indexing a vector by axis: 0 is x, 1 is y and 2 is z.
+impl ops::Index<usize> for Vec3f {
+    type Output = f64;
+
+    fn index(&self, axis: usize) -> &f64 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Axis {} out of range for a 3D vector", axis),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -186,3 +218,244 @@ impl Ray {
         }
     }
 }
+
+type Matrix4 = [[f64; 4]; 4];
+
+fn identity_matrix() -> Matrix4 {
+    let mut m = [[0.0; 4]; 4];
+    for (i, row) in m.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+    m
+}
+
+fn mul_matrices(a: &Matrix4, b: &Matrix4) -> Matrix4 {
+    let mut m = [[0.0; 4]; 4];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    m
+}
+
+// An affine transformation of space, stored as a 4x4 matrix acting on column
+// vectors together with its inverse, so that neither ever has to be computed
+// from the other.
+#[derive(Clone, Debug)]
+pub struct Transform {
+    pub matrix: Matrix4,
+    pub inverse: Matrix4,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Transform {
+            matrix: identity_matrix(),
+            inverse: identity_matrix(),
+        }
+    }
+
+    pub fn translate(offset: &Vec3f) -> Self {
+        let mut matrix = identity_matrix();
+        let mut inverse = identity_matrix();
+        for axis in 0..3 {
+            matrix[axis][3] = offset[axis];
+            inverse[axis][3] = -offset[axis];
+        }
+        Transform { matrix, inverse }
+    }
+
+    // Scales each axis by the matching component of `factors`, none of which
+    // may be 0.
+    pub fn scale(factors: &Vec3f) -> Self {
+        let mut matrix = identity_matrix();
+        let mut inverse = identity_matrix();
+        for axis in 0..3 {
+            matrix[axis][axis] = factors[axis];
+            inverse[axis][axis] = 1.0 / factors[axis];
+        }
+        Transform { matrix, inverse }
+    }
+
+    // Rotates by `degrees` around `axis`, counterclockwise when looking down
+    // the axis towards the origin.
+    pub fn rotate(axis: &Vec3f, degrees: f64) -> Self {
+        let a = axis.normalize();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        // Rodrigues' rotation formula.
+        let mut matrix = identity_matrix();
+        for i in 0..3 {
+            for j in 0..3 {
+                let cross = match (i, j) {
+                    (0, 1) => -a.z,
+                    (1, 0) => a.z,
+                    (0, 2) => a.y,
+                    (2, 0) => -a.y,
+                    (1, 2) => -a.x,
+                    (2, 1) => a.x,
+                    _ => 0.0,
+                };
+                let delta = if i == j { 1.0 } else { 0.0 };
+                matrix[i][j] = a[i] * a[j] * (1.0 - cos) + delta * cos + cross * sin;
+            }
+        }
+        // Rotations are orthogonal: their inverse is their transpose.
+        let mut inverse = identity_matrix();
+        for (i, row) in inverse.iter_mut().enumerate().take(3) {
+            for (j, value) in row.iter_mut().enumerate().take(3) {
+                *value = matrix[j][i];
+            }
+        }
+        Transform { matrix, inverse }
+    }
+
+    // Places an object at `eye`, turned so that its +z axis points towards
+    // `target` and its +y axis is as close to `up` as possible.
+    pub fn look_at(eye: &Vec3f, target: &Vec3f, up: &Vec3f) -> Self {
+        let w = (target - eye).normalize();
+        let u = up.cross(&w).normalize();
+        let v = w.cross(&u);
+        let mut matrix = identity_matrix();
+        let mut inverse = identity_matrix();
+        for (column, axis) in [&u, &v, &w].iter().enumerate() {
+            for row in 0..3 {
+                matrix[row][column] = axis[row];
+                inverse[column][row] = axis[row];
+            }
+            inverse[column][3] = -axis.dot_ref(eye);
+        }
+        for row in 0..3 {
+            matrix[row][3] = eye[row];
+        }
+        Transform { matrix, inverse }
+    }
+
+    pub fn inverse(&self) -> Self {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    // The transformation that applies `self` first and then `other`.
+    pub fn then(&self, other: &Transform) -> Self {
+        Transform {
+            matrix: mul_matrices(&other.matrix, &self.matrix),
+            inverse: mul_matrices(&self.inverse, &other.inverse),
+        }
+    }
+
+    pub fn apply_point(&self, point: &Vec3f) -> Vec3f {
+        let m = &self.matrix;
+        let row = |i: usize| m[i][0] * point.x + m[i][1] * point.y + m[i][2] * point.z + m[i][3];
+        // Affine transforms leave w at 1, so there's no need to divide by it.
+        Vec3f::new(row(0), row(1), row(2))
+    }
+
+    // Directions ignore the translation.
+    pub fn apply_vector(&self, vector: &Vec3f) -> Vec3f {
+        let m = &self.matrix;
+        let row = |i: usize| m[i][0] * vector.x + m[i][1] * vector.y + m[i][2] * vector.z;
+        Vec3f::new(row(0), row(1), row(2))
+    }
+
+    // Normals go through the inverse transpose to stay perpendicular to the
+    // surface when it's scaled unevenly. The result isn't normalized.
+    pub fn apply_normal(&self, normal: &Vec3f) -> Vec3f {
+        let m = &self.inverse;
+        let column = |j: usize| m[0][j] * normal.x + m[1][j] * normal.y + m[2][j] * normal.z;
+        Vec3f::new(column(0), column(1), column(2))
+    }
+
+    // The direction is transformed as is, without normalizing it, so that
+    // distances along the ray are the same in both spaces.
+    pub fn apply_ray(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: self.apply_point(&ray.origin),
+            dir: self.apply_vector(&ray.dir),
+        }
+    }
+
+    // How much the transform scales volumes, and so the determinant of its
+    // linear part.
+    pub fn determinant(&self) -> f64 {
+        let m = &self.matrix;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_identity(m: &Matrix4) {
+        let identity = identity_matrix();
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((m[i][j] - identity[i][j]).abs() < 1e-12, "{:?}", m);
+            }
+        }
+    }
+
+    fn transforms() -> Vec<Transform> {
+        let eye = Vec3f::new(1.0, 2.0, 3.0);
+        vec![
+            Transform::translate(&Vec3f::new(1.0, -2.0, 3.0)),
+            Transform::scale(&Vec3f::new(2.0, 0.5, -3.0)),
+            Transform::rotate(&Vec3f::new(1.0, 1.0, 0.0), 30.0),
+            Transform::look_at(&eye, &ORIGIN, &Vec3f::new(0.0, 1.0, 0.0)),
+            Transform::scale(&Vec3f::new(3.0, 1.0, 0.5))
+                .then(&Transform::rotate(&Vec3f::new(0.0, 0.0, 1.0), 60.0))
+                .then(&Transform::translate(&eye)),
+        ]
+    }
+
+    #[test]
+    fn transforms_store_their_inverse() {
+        for t in transforms().iter() {
+            assert_identity(&mul_matrices(&t.matrix, &t.inverse));
+            let round_trip = t.then(&t.inverse());
+            assert_identity(&round_trip.matrix);
+            assert_identity(&round_trip.inverse);
+            assert_identity(&t.inverse().then(t).matrix);
+
+            let p = Vec3f::new(0.3, -1.2, 4.0);
+            assert!((&t.inverse().apply_point(&t.apply_point(&p)) - &p).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn then_applies_in_order() {
+        let scale = Transform::scale(&Vec3f::new(2.0, 2.0, 2.0));
+        let translate = Transform::translate(&Vec3f::new(1.0, 0.0, 0.0));
+        let p = Vec3f::new(1.0, 1.0, 1.0);
+        let scaled_first = scale.then(&translate).apply_point(&p);
+        assert!((&scaled_first - &Vec3f::new(3.0, 2.0, 2.0)).norm() < 1e-12);
+        let translated_first = translate.then(&scale).apply_point(&p);
+        assert!((&translated_first - &Vec3f::new(4.0, 2.0, 2.0)).norm() < 1e-12);
+        // Vectors aren't moved by translations.
+        let v = translate.then(&scale).apply_vector(&p);
+        assert!((&v - &Vec3f::new(2.0, 2.0, 2.0)).norm() < 1e-12);
+    }
+
+    #[test]
+    fn look_at_points_z_at_the_target() {
+        let eye = Vec3f::new(1.0, 2.0, 3.0);
+        let target = Vec3f::new(-1.0, 0.0, 2.0);
+        let t = Transform::look_at(&eye, &target, &Vec3f::new(0.0, 1.0, 0.0));
+        assert!((&t.apply_point(&ORIGIN) - &eye).norm() < 1e-12);
+        let z = t.apply_vector(&Vec3f::new(0.0, 0.0, 1.0));
+        assert!((&z - &(&target - &eye).normalize()).norm() < 1e-12);
+        assert!(t.apply_vector(&Vec3f::new(0.0, 1.0, 0.0)).y > 0.0);
+        assert!((t.determinant() - 1.0).abs() < 1e-12);
+    }
+}
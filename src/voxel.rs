@@ -0,0 +1,307 @@
+use crate::vector::Vec3f;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// A dense grid of voxels filling the unit cube, holding the density of a
+// medium and optionally its temperature, as read from a voxel file. Values
+// sit at the centers of the voxels and are interpolated trilinearly in
+// between; they're stored as f32 to halve the size of large grids.
+//
+// Voxel files are text: a header giving the size of the grid along x, y and
+// z and the number of values per voxel, followed by the values of every
+// voxel, x varying fastest, then y, then z. With one value per voxel it's the
+// density; with two, the density then the temperature in kelvins. `#` starts
+// a comment running to the end of the line. For example, a 2x1x2 grid of
+// smoke getting hotter towards +z:
+//
+//     voxels 2 1 2 2
+//     0.0 300.0   1.0 300.0     # z = 0
+//     0.5 1500.0  0.2 1500.0    # z = 1
+pub struct VoxelGrid {
+    pub size: [usize; 3],
+    pub density: Vec<f32>,
+    pub temperature: Option<Vec<f32>>,
+    max_density: f64,
+}
+
+impl VoxelGrid {
+    pub fn new(size: [usize; 3], density: Vec<f32>, temperature: Option<Vec<f32>>) -> Self {
+        let max_density = density.iter().fold(0.0f32, |max, &d| max.max(d)) as f64;
+        VoxelGrid {
+            size,
+            density,
+            temperature,
+            max_density,
+        }
+    }
+
+    // The highest density in the grid, which no interpolated value exceeds.
+    pub fn max_density(&self) -> f64 {
+        self.max_density
+    }
+
+    // The density at a point of the unit cube.
+    pub fn density_at(&self, point: &Vec3f) -> f64 {
+        self.interpolate(&self.density, point)
+    }
+
+    // The temperature at a point of the unit cube, 0 if the grid has none.
+    pub fn temperature_at(&self, point: &Vec3f) -> f64 {
+        match &self.temperature {
+            Some(temperature) => self.interpolate(temperature, point),
+            None => 0.0,
+        }
+    }
+
+    fn interpolate(&self, values: &[f32], point: &Vec3f) -> f64 {
+        // For each axis, the two voxels on either side of the point and the
+        // weight of the second one. Past the outermost voxel centers, the
+        // values of the outermost voxels carry on to the edge.
+        let axis = |p: f64, n: usize| {
+            let x = (p * n as f64 - 0.5).clamp(0.0, (n - 1) as f64);
+            let i = (x.floor() as usize).min(n - 1);
+            (i, (i + 1).min(n - 1), x - i as f64)
+        };
+        let [nx, ny, nz] = self.size;
+        let (x0, x1, fx) = axis(point.x, nx);
+        let (y0, y1, fy) = axis(point.y, ny);
+        let (z0, z1, fz) = axis(point.z, nz);
+        let at = |x: usize, y: usize, z: usize| values[x + nx * (y + ny * z)] as f64;
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let plane = |z: usize| {
+            lerp(
+                lerp(at(x0, y0, z), at(x1, y0, z), fx),
+                lerp(at(x0, y1, z), at(x1, y1, z), fx),
+                fy,
+            )
+        };
+        lerp(plane(z0), plane(z1), fz)
+    }
+}
+
+fn parse_error(path: &Path, line: usize, message: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{}: {}", path.display(), line, message),
+    )
+}
+
+// Reads a voxel file in the format described above.
+pub fn load_voxels(path: &Path) -> Result<VoxelGrid, io::Error> {
+    let contents = fs::read_to_string(path)?;
+    let tokens: Vec<(usize, &str)> = contents
+        .lines()
+        .enumerate()
+        .flat_map(|(number, line)| {
+            let line = line.split('#').next().unwrap_or("");
+            line.split_whitespace()
+                .map(move |token| (number + 1, token))
+        })
+        .collect();
+    // Past the keyword and the four numbers of the header.
+    let values_left = tokens.len().saturating_sub(5);
+    let mut tokens = tokens.into_iter();
+
+    let last_line = contents.lines().count();
+    let mut next = |what: &str| {
+        tokens
+            .next()
+            .ok_or_else(|| parse_error(path, last_line, format!("missing {}", what)))
+    };
+    let (line, keyword) = next("header")?;
+    if keyword != "voxels" {
+        return Err(parse_error(
+            path,
+            line,
+            format!("expected `voxels`, found `{}`", keyword),
+        ));
+    }
+    let mut header = [0; 4];
+    for (value, what) in header
+        .iter_mut()
+        .zip(["width", "height", "depth", "channels"])
+    {
+        let (line, token) = next(what)?;
+        *value = token
+            .parse::<usize>()
+            .map_err(|_| parse_error(path, line, format!("invalid {} `{}`", what, token)))?;
+    }
+    let [nx, ny, nz, channels] = header;
+    if nx == 0 || ny == 0 || nz == 0 {
+        return Err(parse_error(path, line, "empty grid".to_string()));
+    }
+    if channels != 1 && channels != 2 {
+        return Err(parse_error(
+            path,
+            line,
+            format!("expected 1 or 2 channels, found {}", channels),
+        ));
+    }
+
+    // Checked before allocating anything, so that a bogus header can't ask
+    // for more memory than there is.
+    let count = nx
+        .checked_mul(ny)
+        .and_then(|count| count.checked_mul(nz))
+        .filter(|count| {
+            count
+                .checked_mul(channels)
+                .is_some_and(|n| n <= values_left)
+        })
+        .ok_or_else(|| {
+            parse_error(
+                path,
+                line,
+                format!(
+                    "a {}x{}x{} grid needs more values than the {} in the file",
+                    nx, ny, nz, values_left
+                ),
+            )
+        })?;
+    let mut density = Vec::with_capacity(count);
+    let mut temperature = Vec::with_capacity(if channels == 2 { count } else { 0 });
+    for _ in 0..count {
+        let (line, token) = next("voxel")?;
+        match token.parse::<f32>() {
+            Ok(value) if value >= 0.0 => density.push(value),
+            _ => {
+                return Err(parse_error(
+                    path,
+                    line,
+                    format!("invalid density `{}`", token),
+                ))
+            }
+        }
+        if channels == 2 {
+            let (line, token) = next("temperature")?;
+            match token.parse::<f32>() {
+                Ok(value) if value >= 0.0 => temperature.push(value),
+                _ => {
+                    return Err(parse_error(
+                        path,
+                        line,
+                        format!("invalid temperature `{}`", token),
+                    ))
+                }
+            }
+        }
+    }
+    if let Some((line, _)) = tokens.next() {
+        return Err(parse_error(path, line, "too many values".to_string()));
+    }
+
+    let temperature = if channels == 2 {
+        Some(temperature)
+    } else {
+        None
+    };
+    Ok(VoxelGrid::new([nx, ny, nz], density, temperature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    // Loads a voxel file with the given contents, and returns the grid or
+    // the error message, stripped of the path.
+    fn load(contents: &str) -> Result<VoxelGrid, String> {
+        let dir = TempDir::new();
+        let path = dir.write("grid.vox", contents);
+        load_voxels(&path).map_err(|e| {
+            let message = e.to_string();
+            let prefix = format!("{}:", path.display());
+            message.strip_prefix(&prefix).unwrap().to_string()
+        })
+    }
+
+    fn error(contents: &str) -> String {
+        match load(contents) {
+            Ok(_) => panic!("loaded an invalid grid"),
+            Err(message) => message,
+        }
+    }
+
+    #[test]
+    fn parses_the_documented_example() {
+        let grid = load(
+            "voxels 2 1 2 2\n\
+             0.0 300.0   1.0 300.0     # z = 0\n\
+             0.5 1500.0  0.2 1500.0    # z = 1\n",
+        )
+        .unwrap();
+        assert_eq!(grid.size, [2, 1, 2]);
+        assert_eq!(grid.density, [0.0, 1.0, 0.5, 0.2]);
+        assert_eq!(grid.temperature, Some(vec![300.0, 300.0, 1500.0, 1500.0]));
+        assert_eq!(grid.max_density(), 1.0);
+    }
+
+    #[test]
+    fn rejects_a_wrong_keyword() {
+        assert_eq!(
+            error("grid 1 1 1 1\n0.5\n"),
+            "1: expected `voxels`, found `grid`"
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_channels() {
+        assert_eq!(
+            error("# no values\nvoxels 1 1 1 0\n"),
+            "2: expected 1 or 2 channels, found 0"
+        );
+        assert_eq!(
+            error("voxels 1 1 1 3\n0.5 300.0 1.0\n"),
+            "1: expected 1 or 2 channels, found 3"
+        );
+    }
+
+    #[test]
+    fn rejects_negative_densities() {
+        assert_eq!(
+            error("voxels 1 1 2 1\n0.5\n-1.0\n"),
+            "3: invalid density `-1.0`"
+        );
+    }
+
+    #[test]
+    fn rejects_too_few_values() {
+        assert_eq!(
+            error("voxels 2 2 2 1\n1 2 3\n"),
+            "1: a 2x2x2 grid needs more values than the 3 in the file"
+        );
+    }
+
+    #[test]
+    fn rejects_too_many_values() {
+        assert_eq!(error("voxels 1 1 1 1\n0.5\n\n0.7\n"), "4: too many values");
+    }
+
+    #[test]
+    fn rejects_huge_grids_without_allocating_them() {
+        assert_eq!(
+            error("voxels 100000 100000 100000 1\n1.0\n"),
+            "1: a 100000x100000x100000 grid needs more values than the 1 in the file"
+        );
+        let max = usize::MAX;
+        assert!(error(&format!("voxels {} {} 2 2\n", max, max)).starts_with("1: a "));
+    }
+
+    #[test]
+    fn density_at_voxel_centers_and_edges() {
+        // Two voxels along x, centered at x = 0.25 and x = 0.75.
+        let grid = VoxelGrid::new([2, 1, 1], vec![1.0, 3.0], None);
+        let at = |x| grid.density_at(&Vec3f::new(x, 0.5, 0.5));
+        assert_eq!(at(0.25), 1.0);
+        assert_eq!(at(0.75), 3.0);
+        assert_eq!(at(0.5), 2.0);
+        // The outermost values carry on to the edges of the cube.
+        assert_eq!(at(0.0), 1.0);
+        assert_eq!(at(0.1), 1.0);
+        assert_eq!(at(1.0), 3.0);
+        // A single voxel along y and z is the same everywhere along them.
+        assert_eq!(grid.density_at(&Vec3f::new(0.25, 0.0, 1.0)), 1.0);
+        assert_eq!(grid.temperature_at(&Vec3f::new(0.5, 0.5, 0.5)), 0.0);
+    }
+}
@@ -0,0 +1,579 @@
+// Just enough of zlib (RFC 1950) and CRC-32 to read and write PNG and EXR
+// files. Data either goes into "stored" deflate blocks, which every inflater
+// has to accept, or is compressed with LZ77 and the fixed Huffman codes of
+// RFC 1951, which is far simpler than building dynamic code tables. Reading
+// supports every kind of block.
+
+use std::io;
+
+// Deflate limits stored blocks to 65535 bytes each.
+const MAX_STORED_BLOCK: usize = 65535;
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// How many earlier occurrences of a 3-byte prefix to try before giving up.
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+// Base lengths and extra bits of length codes 257 to 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+// Base distances and extra bits of distance codes 0 to 29.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// Writes a deflate bit stream: values are packed starting from the least
+// significant bit of each byte.
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    num_bits: u32,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.buffer |= value << self.num_bits;
+        self.num_bits += count;
+        while self.num_bits >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.num_bits -= 8;
+        }
+    }
+
+    // Huffman codes are defined most significant bit first, the opposite of
+    // every other value in the stream.
+    fn write_code(&mut self, code: u32, length: u32) {
+        self.write_bits(code.reverse_bits() >> (32 - length), length);
+    }
+
+    fn flush(mut self) -> Vec<u8> {
+        if self.num_bits > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+// Writes a literal byte or length symbol (0 to 287) with the fixed code.
+fn write_fixed_symbol(bits: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => bits.write_code(0x30 + symbol, 8),
+        144..=255 => bits.write_code(0x190 + symbol - 144, 9),
+        256..=279 => bits.write_code(symbol - 256, 7),
+        _ => bits.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(bits: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= length)
+        .unwrap();
+    write_fixed_symbol(bits, 257 + code as u32);
+    bits.write_bits(
+        (length - LENGTH_BASE[code] as usize) as u32,
+        LENGTH_EXTRA[code] as u32,
+    );
+
+    let code = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| base as usize <= distance)
+        .unwrap();
+    bits.write_code(code as u32, 5);
+    bits.write_bits(
+        (distance - DISTANCE_BASE[code] as usize) as u32,
+        DISTANCE_EXTRA[code] as u32,
+    );
+}
+
+// The order in which the code lengths of the code length alphabet are stored.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Reads a deflate bit stream, the counterpart of BitWriter.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    num_bits: u32,
+}
+
+impl BitReader<'_> {
+    fn read_bits(&mut self, count: u32) -> Result<u32, io::Error> {
+        while self.num_bits < count {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or_else(|| invalid_data("truncated deflate stream"))?;
+            self.buffer |= (byte as u32) << self.num_bits;
+            self.position += 1;
+            self.num_bits += 8;
+        }
+        let value = self.buffer & ((1u64 << count) - 1) as u32;
+        self.buffer >>= count;
+        self.num_bits -= count;
+        Ok(value)
+    }
+
+    // Drops the bits left in the current byte.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.num_bits = 0;
+    }
+}
+
+// A canonical Huffman code, decoded one bit at a time the way zlib's `puff`
+// does: codes of the same length are consecutive numbers, so only the number
+// of codes of each length and the symbols in code order are needed.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    // Builds the code from the code length of every symbol, 0 meaning unused.
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols: Vec<u16> = (0..lengths.len() as u16)
+            .filter(|&symbol| lengths[symbol as usize] != 0)
+            .collect();
+        symbols.sort_by_key(|&symbol| lengths[symbol as usize]);
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> Result<u16, io::Error> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..16 {
+            code |= bits.read_bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid_data("invalid Huffman code"))
+    }
+}
+
+// The fixed literal/length and distance codes of RFC 1951.
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (symbol, length) in lengths.iter_mut().enumerate() {
+        *length = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+// Reads the code length tables at the start of a dynamic block.
+fn dynamic_codes(bits: &mut BitReader) -> Result<(Huffman, Huffman), io::Error> {
+    let num_literals = bits.read_bits(5)? as usize + 257;
+    let num_distances = bits.read_bits(5)? as usize + 1;
+    let num_code_lengths = bits.read_bits(4)? as usize + 4;
+
+    let mut code_length_lengths = [0u8; 19];
+    for &symbol in CODE_LENGTH_ORDER.iter().take(num_code_lengths) {
+        code_length_lengths[symbol] = bits.read_bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_length_lengths);
+
+    let mut lengths = Vec::with_capacity(num_literals + num_distances);
+    while lengths.len() < num_literals + num_distances {
+        let (value, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or_else(|| invalid_data("repeated code length with no previous one"))?;
+                (previous, 3 + bits.read_bits(2)?)
+            }
+            17 => (0, 3 + bits.read_bits(3)?),
+            _ => (0, 11 + bits.read_bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() > num_literals + num_distances {
+        return Err(invalid_data("too many code lengths"));
+    }
+    Ok((
+        Huffman::new(&lengths[..num_literals]),
+        Huffman::new(&lengths[num_literals..]),
+    ))
+}
+
+// Decompresses a zlib stream and checks its Adler-32 checksum.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    if data.len() < 6
+        || data[0] & 0x0f != 8
+        || !((data[0] as u16) << 8 | data[1] as u16).is_multiple_of(31)
+    {
+        return Err(invalid_data("not a zlib stream"));
+    }
+    if data[1] & 0x20 != 0 {
+        return Err(invalid_data("zlib preset dictionaries are not supported"));
+    }
+
+    let mut bits = BitReader {
+        data: &data[2..],
+        position: 0,
+        buffer: 0,
+        num_bits: 0,
+    };
+    let mut out = vec![];
+    loop {
+        let is_final = bits.read_bits(1)? == 1;
+        let (literals, distances) = match bits.read_bits(2)? {
+            0 => {
+                bits.align();
+                let start = bits.position;
+                let header = bits
+                    .data
+                    .get(start..start + 4)
+                    .ok_or_else(|| invalid_data("truncated deflate stream"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(invalid_data("corrupt stored block length"));
+                }
+                let block = bits
+                    .data
+                    .get(start + 4..start + 4 + len as usize)
+                    .ok_or_else(|| invalid_data("truncated deflate stream"))?;
+                out.extend_from_slice(block);
+                bits.position = start + 4 + len as usize;
+                if is_final {
+                    break;
+                }
+                continue;
+            }
+            1 => fixed_codes(),
+            2 => dynamic_codes(&mut bits)?,
+            _ => return Err(invalid_data("invalid deflate block type")),
+        };
+
+        loop {
+            let symbol = literals.decode(&mut bits)? as usize;
+            if symbol < 256 {
+                out.push(symbol as u8);
+                continue;
+            }
+            if symbol == 256 {
+                break;
+            }
+            let code = symbol - 257;
+            if code >= LENGTH_BASE.len() {
+                return Err(invalid_data("invalid length code"));
+            }
+            let length =
+                LENGTH_BASE[code] as usize + bits.read_bits(LENGTH_EXTRA[code] as u32)? as usize;
+            let code = distances.decode(&mut bits)? as usize;
+            if code >= DISTANCE_BASE.len() {
+                return Err(invalid_data("invalid distance code"));
+            }
+            let distance = DISTANCE_BASE[code] as usize
+                + bits.read_bits(DISTANCE_EXTRA[code] as u32)? as usize;
+            if distance > out.len() {
+                return Err(invalid_data("distance too far back"));
+            }
+            // The match may overlap the bytes it produces, so copy one at a time.
+            let start = out.len() - distance;
+            for i in 0..length {
+                out.push(out[start + i]);
+            }
+        }
+        if is_final {
+            break;
+        }
+    }
+
+    // The checksum follows the last block, starting on a byte boundary.
+    let checksum = bits
+        .data
+        .get(bits.position..bits.position + 4)
+        .ok_or_else(|| invalid_data("missing zlib checksum"))?;
+    if u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) != adler32(&out) {
+        return Err(invalid_data("zlib checksum mismatch"));
+    }
+    Ok(out)
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let key = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+    (key.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+// Records that the 3 bytes at `i` can be matched from now on.
+fn insert(data: &[u8], i: usize, head: &mut [usize], prev: &mut [usize]) {
+    if i + MIN_MATCH <= data.len() {
+        let h = hash(data, i);
+        prev[i] = head[h];
+        head[h] = i;
+    }
+}
+
+// Compresses `data` into a zlib stream holding a single fixed Huffman block,
+// finding repeats with a greedy, hash-chained LZ77 search.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut bits = BitWriter {
+        out: vec![0x78, 0x01],
+        buffer: 0,
+        num_bits: 0,
+    };
+    // BFINAL = 1, BTYPE = 01 (fixed Huffman codes).
+    bits.write_bits(1, 1);
+    bits.write_bits(1, 2);
+
+    // `head` holds the last position where each hash was seen, `prev` links
+    // every position to the previous one with the same hash.
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+
+    let mut i = 0;
+    while i < data.len() {
+        let mut best_length = 0;
+        let mut best_distance = 0;
+        if i + MIN_MATCH <= data.len() {
+            let mut candidate = head[hash(data, i)];
+            let mut chain = 0;
+            let max_length = MAX_MATCH.min(data.len() - i);
+            while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = (0..max_length)
+                    .take_while(|&k| data[candidate + k] == data[i + k])
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = i - candidate;
+                    if length == max_length {
+                        break;
+                    }
+                }
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            write_match(&mut bits, best_length, best_distance);
+            for k in i..i + best_length {
+                insert(data, k, &mut head, &mut prev);
+            }
+            i += best_length;
+        } else {
+            write_fixed_symbol(&mut bits, data[i] as u32);
+            insert(data, i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    // End of block.
+    write_fixed_symbol(&mut bits, 256);
+
+    let mut out = bits.flush();
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+// Wraps `data` into a zlib stream made of uncompressed deflate blocks.
+pub fn compress_stored(data: &[u8]) -> Vec<u8> {
+    let num_blocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    let mut out = Vec::with_capacity(data.len() + 5 * num_blocks + 6);
+    // CMF: deflate with a 32K window. FLG: no dictionary, fastest level, and
+    // check bits so that CMF * 256 + FLG is a multiple of 31.
+    out.extend_from_slice(&[0x78, 0x01]);
+
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // An empty input still needs one (final) block.
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(is_final as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 is the largest n for which 255n(n+1)/2 + (n+1)(MOD_ADLER-1) fits
+    // in a u32, so the modulo only needs to be taken once per chunk.
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+// CRC-32 with the reflected 0xEDB88320 polynomial, as used by PNG.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// Continues a CRC-32 computed over preceding data.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Rng;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress(data);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert_eq!(decompress(&compress_stored(data)).unwrap(), data);
+        compressed
+    }
+
+    fn random_bytes(count: usize) -> Vec<u8> {
+        let mut rng = Rng::new(1);
+        (0..count).map(|_| rng.next_u64() as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_empty_and_short_inputs() {
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"ab");
+        round_trip(b"Hello, hello, hello!");
+    }
+
+    #[test]
+    fn compresses_repetitive_input() {
+        let data: Vec<u8> = b"abcabd".iter().cycle().take(100_000).copied().collect();
+        assert!(round_trip(&data).len() < 1000);
+        assert!(round_trip(&vec![0; 100_000]).len() < 1000);
+    }
+
+    #[test]
+    fn round_trips_incompressible_input() {
+        // Larger than a stored block, and than the LZ77 window.
+        round_trip(&random_bytes(70_000));
+    }
+
+    // Made by zlib's own deflate, at level 9, as a single dynamic Huffman
+    // block.
+    const DYNAMIC_STREAM: &str = "\
+        78da25ccd109c3300c84e1556e80d01d3a8aa248b1a923816413bc7de3f6f13eb8ff8da0\
+        891ec412506fcdef5c947045ab67e9d8893f37c5911b34fc422f0299826add7f23594c36\
+        ec3e8cab9dcbaee7aec811fa8413c37a6dcb2742880be8df7e7d0181c82df6";
+    const DYNAMIC_TEXT: &[u8] = b"A ray tracer follows rays of light backwards, from the eye \
+        into the scene, bouncing them off surfaces until they reach a light.";
+
+    fn dynamic_stream() -> Vec<u8> {
+        (0..DYNAMIC_STREAM.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&DYNAMIC_STREAM[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decompresses_dynamic_huffman_blocks() {
+        let stream = dynamic_stream();
+        // BTYPE, in the bits after BFINAL, is 2 for dynamic codes.
+        assert_eq!(stream[2] >> 1 & 3, 2);
+        assert_eq!(decompress(&stream).unwrap(), DYNAMIC_TEXT);
+    }
+
+    #[test]
+    fn checksums_match_known_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xcbf4_3926);
+        // Large enough for Adler-32's sums to need reducing along the way.
+        let data = vec![0xff; 100_000];
+        let (mut a, mut b) = (1u64, 0u64);
+        for &byte in data.iter() {
+            a = (a + byte as u64) % 65521;
+            b = (b + a) % 65521;
+        }
+        assert_eq!(adler32(&data) as u64, b << 16 | a);
+    }
+
+    #[test]
+    fn rejects_truncated_streams() {
+        for stream in [
+            compress(DYNAMIC_TEXT),
+            dynamic_stream(),
+            compress_stored(DYNAMIC_TEXT),
+        ] {
+            for length in 0..stream.len() {
+                assert!(decompress(&stream[..length]).is_err(), "length {}", length);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_corrupt_streams() {
+        let stream = dynamic_stream();
+        let corrupt = |i: usize, mask: u8| {
+            let mut stream = stream.clone();
+            stream[i] ^= mask;
+            decompress(&stream)
+        };
+        // The header check, the compression method, the block type and the
+        // checksum.
+        assert!(corrupt(1, 0x01).is_err());
+        assert!(corrupt(0, 0x01).is_err());
+        assert!(corrupt(2, 0x06).is_err());
+        assert!(corrupt(stream.len() - 1, 0x01).is_err());
+        // A fixed Huffman block starting with a match of length 3 at distance
+        // 1, before there is any output to copy from.
+        let error = decompress(&[0x78, 0x01, 0x03, 0x02, 0, 0, 0, 1]).unwrap_err();
+        assert_eq!(error.to_string(), "distance too far back");
+        // Anything else may or may not be caught, but mustn't panic.
+        for i in 0..stream.len() {
+            for mask in [0x01, 0x10, 0xff] {
+                let _ = corrupt(i, mask);
+            }
+        }
+    }
+}